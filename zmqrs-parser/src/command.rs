@@ -1,22 +1,24 @@
+use bytes::BufMut;
 use nom::{
     bytes::complete::{take, take_while_m_n},
     number::complete::{be_u16, be_u32, be_u8},
-    IResult,
 };
 
-use alloc::collections::BTreeMap as Map;
+use alloc::vec::Vec;
 use slog::{Error, Record, Serializer};
 
-use crate::{ByteSlice, FrameHeader};
+use crate::curve::{command_curve, CurveCommand};
+use crate::error::{fail, fail_hard, with_kind, EncodeError, IResult, ZmtpErrorKind};
+use crate::{ByteSlice, FrameHeader, Limits, SecurityMechanism};
 
-impl<'a> slog::Value for ByteSlice<&'a [u8]> {
+impl slog::Value for ByteSlice<&[u8]> {
     fn serialize(
         &self,
         _record: &Record,
         key: slog::Key,
         serializer: &mut dyn Serializer,
     ) -> Result<(), Error> {
-        let to_printable_ascii = |v: u8| if v >= 32 && v < 127 { v } else { b'.' };
+        let to_printable_ascii = |v: u8| if (32..127).contains(&v) { v } else { b'.' };
 
         let mut buf = [0u8; 80]; // small internal buffer to sanitize bytes to something printable
         let mut cnt = 0;
@@ -32,7 +34,8 @@ impl<'a> slog::Value for ByteSlice<&'a [u8]> {
 }
 
 // http://zmtp.org/page:read-the-docs#toc12
-#[derive(Debug, Clone)]
+// https://rfc.zeromq.org/spec/37/
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command<S, T> {
     // for null-security
     READY(MetaData<S, T>),
@@ -41,6 +44,17 @@ pub enum Command<S, T> {
     CANCEL(ByteSlice<T>),
     PING(Ping<T>),
     PONG(Pong<T>),
+    // for plain-security, see https://rfc.zeromq.org/spec/24/
+    HELLO(Hello<T>),
+    WELCOME,
+    INITIATE(MetaData<S, T>),
+    /// All commands of curve-security, see https://rfc.zeromq.org/spec/26/
+    CURVE(CurveCommand<T>),
+    /// Any other command, e.g. of a security mechanism or a newer protocol version.
+    Unknown {
+        name: S,
+        body: T,
+    },
 }
 
 impl From<(&bytes::Bytes, Command<&str, &[u8]>)> for Command<bytes::Bytes, bytes::Bytes> {
@@ -54,17 +68,130 @@ impl From<(&bytes::Bytes, Command<&str, &[u8]>)> for Command<bytes::Bytes, bytes
             Command::CANCEL(slice) => Command::CANCEL((buffer, slice).into()),
             Command::PING(ping) => Command::PING((buffer, ping).into()),
             Command::PONG(pong) => Command::PONG((buffer, pong).into()),
+            Command::HELLO(hello) => Command::HELLO((buffer, hello).into()),
+            Command::WELCOME => Command::WELCOME,
+            Command::INITIATE(meta_data) => Command::INITIATE((buffer, meta_data).into()),
+            Command::CURVE(curve) => Command::CURVE((buffer, curve).into()),
+            Command::Unknown { name, body } => Command::Unknown {
+                name: buffer.slice_ref(name.as_bytes()),
+                body: buffer.slice_ref(body),
+            },
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl<S: AsRef<[u8]>, T: AsRef<[u8]>> Command<S, T> {
+    pub fn name(&self) -> &[u8] {
+        match self {
+            Command::READY(_) => b"READY",
+            Command::ERROR(_) => b"ERROR",
+            Command::SUBSCRIBE(_) => b"SUBSCRIBE",
+            Command::CANCEL(_) => b"CANCEL",
+            Command::PING(_) => b"PING",
+            Command::PONG(_) => b"PONG",
+            Command::HELLO(_) => b"HELLO",
+            Command::WELCOME => b"WELCOME",
+            Command::INITIATE(_) => b"INITIATE",
+            Command::CURVE(curve) => curve.name(),
+            Command::Unknown { name, .. } => name.as_ref(),
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        match self {
+            Command::READY(meta_data) => meta_data.encoded_len(),
+            Command::ERROR(reason) => 1 + reason.0.as_ref().len(),
+            Command::SUBSCRIBE(subscription) => subscription.0.as_ref().len(),
+            Command::CANCEL(subscription) => subscription.0.as_ref().len(),
+            Command::PING(ping) => 2 + ping.context.as_ref().len(),
+            Command::PONG(pong) => pong.context.as_ref().len(),
+            Command::HELLO(hello) => hello.encoded_len(),
+            Command::WELCOME => 0,
+            Command::INITIATE(meta_data) => meta_data.encoded_len(),
+            Command::CURVE(curve) => curve.data_len(),
+            Command::Unknown { body, .. } => body.as_ref().len(),
+        }
+    }
+
+    /// Number of octets of the frame body.
+    pub fn encoded_len(&self) -> usize {
+        1 + self.name().len() + self.data_len()
+    }
+
+    /// Check that the command can be encoded, e.g. that the name fits its short-size.
+    pub(crate) fn check(&self) -> Result<(), EncodeError> {
+        if !is_command_name(self.name()) {
+            return Err(EncodeError::InvalidCommandName);
+        }
+        match self {
+            Command::READY(meta_data) | Command::INITIATE(meta_data) => meta_data.check(),
+            Command::ERROR(reason) => check_short_string(reason.0.as_ref()),
+            Command::HELLO(hello) => {
+                check_short_string(hello.username.as_ref())?;
+                check_short_string(hello.password.as_ref())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the frame body; the header is written by `Frame::encode`.
+    ///
+    /// Nothing is written if the command can't be encoded.
+    ///
+    /// command-body = command-name command-data
+    pub fn encode<B: BufMut>(&self, dst: &mut B) -> Result<(), EncodeError> {
+        self.check()?;
+        self.write(dst);
+        Ok(())
+    }
+
+    /// Write the frame body of a command that passed `check`.
+    pub(crate) fn write<B: BufMut>(&self, dst: &mut B) {
+        let name = self.name();
+        dst.put_u8(name.len() as u8);
+        dst.put_slice(name);
+
+        match self {
+            Command::READY(meta_data) => meta_data.write(dst),
+            // error-reason = short-size 0*255VCHAR
+            Command::ERROR(reason) => encode_short_string(reason.0.as_ref(), dst),
+            Command::SUBSCRIBE(subscription) => dst.put_slice(subscription.0.as_ref()),
+            Command::CANCEL(subscription) => dst.put_slice(subscription.0.as_ref()),
+            Command::PING(ping) => {
+                dst.put_u16(ping.ttl);
+                dst.put_slice(ping.context.as_ref());
+            }
+            Command::PONG(pong) => dst.put_slice(pong.context.as_ref()),
+            Command::HELLO(hello) => hello.encode(dst),
+            Command::WELCOME => {}
+            Command::INITIATE(meta_data) => meta_data.write(dst),
+            Command::CURVE(curve) => curve.encode(dst),
+            Command::Unknown { body, .. } => dst.put_slice(body.as_ref()),
+        }
+    }
+}
+
+fn check_short_string(value: &[u8]) -> Result<(), EncodeError> {
+    if value.len() <= 255 {
+        Ok(())
+    } else {
+        Err(EncodeError::StringTooLong)
+    }
+}
+
+/// Write a short-size prefixed string that passed `check_short_string`.
+fn encode_short_string<B: BufMut>(value: &[u8], dst: &mut B) {
+    dst.put_u8(value.len() as u8);
+    dst.put_slice(value);
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Ping<T> {
     pub ttl: u16,
     pub context: T,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Pong<T> {
     pub context: T,
 }
@@ -88,7 +215,38 @@ impl From<(&bytes::Bytes, Pong<&[u8]>)> for Pong<bytes::Bytes> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Credentials sent by a PLAIN client.
+///
+/// Both username and password are at most 255 octets long; encoding longer values fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello<T> {
+    pub username: T,
+    pub password: T,
+}
+
+impl<T: AsRef<[u8]>> Hello<T> {
+    fn encoded_len(&self) -> usize {
+        2 + self.username.as_ref().len() + self.password.as_ref().len()
+    }
+
+    /// hello = command-size %d5 "HELLO" username password
+    fn encode<B: BufMut>(&self, dst: &mut B) {
+        encode_short_string(self.username.as_ref(), dst);
+        encode_short_string(self.password.as_ref(), dst);
+    }
+}
+
+impl From<(&bytes::Bytes, Hello<&[u8]>)> for Hello<bytes::Bytes> {
+    fn from(input: (&bytes::Bytes, Hello<&[u8]>)) -> Self {
+        let (buffer, subset) = input;
+        Hello {
+            username: buffer.slice_ref(subset.username),
+            password: buffer.slice_ref(subset.password),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MetaData<S, T> {
     /// Metadata names SHALL be case-insensitive.
     /// These metadata properties are defined:
@@ -98,13 +256,141 @@ pub struct MetaData<S, T> {
     /// * "Identity", which specifies the sender's socket identity. See the section "The Identity Property" below. The sender MAY specify an Identity.
     ///
     /// * "Resource", which specifies the a resource to connect to. See the section "The Resource Property" below. The sender MAY specify a Resource.
-    properties: Map<S, ByteSlice<T>>, // TODO: das passt für plain, aber auch für andere?
+    ///
+    /// Properties are kept in wire order.
+    properties: Vec<(S, ByteSlice<T>)>,
+}
+
+// Names of the properties defined by ZMTP.
+pub const SOCKET_TYPE: &str = "Socket-Type";
+pub const IDENTITY: &str = "Identity";
+pub const RESOURCE: &str = "Resource";
+
+impl<S, T> Default for MetaData<S, T> {
+    fn default() -> Self {
+        MetaData {
+            properties: Vec::new(),
+        }
+    }
+}
+
+impl<S, T> MetaData<S, T> {
+    /// Empty metadata; add properties with the `with_*` methods.
+    pub fn new() -> Self {
+        MetaData::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.properties.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+
+    /// All properties in wire order.
+    pub fn iter(&self) -> impl Iterator<Item = (&S, &T)> {
+        self.properties.iter().map(|(name, value)| (name, &value.0))
+    }
+}
+
+impl<S: AsRef<[u8]>, T> MetaData<S, T> {
+    /// Value of the property `name`; names are compared case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&T> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n.as_ref().eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, value)| &value.0)
+    }
+
+    /// The sender's socket type.
+    pub fn socket_type(&self) -> Option<&T> {
+        self.get(SOCKET_TYPE)
+    }
+
+    /// The sender's socket identity.
+    pub fn identity(&self) -> Option<&T> {
+        self.get(IDENTITY)
+    }
+
+    /// The resource to connect to.
+    pub fn resource(&self) -> Option<&T> {
+        self.get(RESOURCE)
+    }
+
+    /// Set the property `name`, replacing a property of the same name.
+    ///
+    /// Names must consist of 1 to 255 characters out of ALPHA, DIGIT, "-", "_", "." and "+";
+    /// otherwise `encode` fails.
+    pub fn with_property(mut self, name: S, value: T) -> Self {
+        self.properties
+            .retain(|(n, _)| !n.as_ref().eq_ignore_ascii_case(name.as_ref()));
+        self.properties.push((name, ByteSlice(value)));
+        self
+    }
+}
+
+impl<S: AsRef<[u8]> + From<&'static str>, T> MetaData<S, T> {
+    pub fn with_socket_type(self, value: T) -> Self {
+        self.with_property(SOCKET_TYPE.into(), value)
+    }
+
+    pub fn with_identity(self, value: T) -> Self {
+        self.with_property(IDENTITY.into(), value)
+    }
+
+    pub fn with_resource(self, value: T) -> Self {
+        self.with_property(RESOURCE.into(), value)
+    }
+}
+
+impl<S: AsRef<[u8]>, T: AsRef<[u8]>> MetaData<S, T> {
+    /// Number of octets `encode` will write.
+    pub fn encoded_len(&self) -> usize {
+        self.properties
+            .iter()
+            .map(|(name, value)| 1 + name.as_ref().len() + 4 + value.0.as_ref().len())
+            .sum()
+    }
+
+    /// Check that all names are valid and all values fit their size field.
+    fn check(&self) -> Result<(), EncodeError> {
+        for (name, value) in &self.properties {
+            if !is_property_name(name.as_ref()) {
+                return Err(EncodeError::InvalidPropertyName);
+            }
+            if value.0.as_ref().len() > u32::MAX as usize {
+                return Err(EncodeError::PropertyTooLarge);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all properties; nothing is written if one of them can't be encoded.
+    ///
+    /// property = name value
+    pub fn encode<B: BufMut>(&self, dst: &mut B) -> Result<(), EncodeError> {
+        self.check()?;
+        self.write(dst);
+        Ok(())
+    }
+
+    fn write<B: BufMut>(&self, dst: &mut B) {
+        for (name, value) in &self.properties {
+            let name = name.as_ref();
+            let value = value.0.as_ref();
+            dst.put_u8(name.len() as u8);
+            dst.put_slice(name);
+            dst.put_u32(value.len() as u32);
+            dst.put_slice(value);
+        }
+    }
 }
 
 impl From<(&bytes::Bytes, MetaData<&str, &[u8]>)> for MetaData<bytes::Bytes, bytes::Bytes> {
     fn from(input: (&bytes::Bytes, MetaData<&str, &[u8]>)) -> Self {
         let (buffer, meta_data) = input;
-        let mut properties = meta_data
+        let properties = meta_data
             .properties
             .into_iter()
             .map(|(k, v)| (buffer.slice_ref(k.as_bytes()), (buffer, v).into()))
@@ -117,24 +403,41 @@ impl From<(&bytes::Bytes, MetaData<&str, &[u8]>)> for MetaData<bytes::Bytes, byt
 fn meta_data<'a>(
     input: &'a [u8],
     data_len: usize,
+    limits: &Limits,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], MetaData<&'a str, &'a [u8]>> {
-    let mut properties = Map::new();
+    let mut properties = Vec::new();
 
-    let mut current_pos = input;
+    // properties must not exceed the frame
+    let (remaining, data) = take(data_len)(input)?;
+    let mut current_pos = data;
 
-    while input.len() - current_pos.len() < data_len {
-        let (new_pos, (name, value)) = property(current_pos, logger)?;
+    while !current_pos.is_empty() {
+        if let Err(kind) = limits.check_property_count(properties.len() + 1) {
+            return fail_hard(current_pos, kind);
+        }
+
+        let (new_pos, (name, value)) = property(current_pos, limits, logger)
+            .map_err(|e| with_kind(e, ZmtpErrorKind::MalformedProperty))?;
 
         trace!(logger, "property";
-                "consumed" => input.len() - new_pos.len(),
-                "remaining" => data_len - (input.len() - new_pos.len()),
+                "consumed" => data.len() - new_pos.len(),
+                "remaining" => new_pos.len(),
                 "data_len" => data_len );
 
         current_pos = new_pos;
-        properties.insert(name, value);
+        properties.push((name, value));
     }
-    Ok((input, MetaData { properties }))
+    Ok((remaining, MetaData { properties }))
+}
+
+/// Parse a buffer holding nothing but properties, e.g. the metadata of a decrypted box.
+pub fn properties<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], MetaData<&'a str, &'a [u8]>> {
+    meta_data(input, input.len(), limits, logger)
 }
 
 /// Parse a single property
@@ -143,21 +446,40 @@ fn meta_data<'a>(
 /// name = short-size 1*255name-char
 /// name-char = ALPHA | DIGIT | "-" | "_" | "." | "+"
 /// value = 4OCTET *OCTET       ; Size in network byte order
-fn property<'a>(
-    input: &'a [u8],
-    logger: &mut slog::Logger,
-) -> IResult<&'a [u8], (&'a str, ByteSlice<&'a [u8]>)> {
-    let is_name_char = |v: u8| match v {
+fn is_name_char(v: u8) -> bool {
+    match v {
         b'-' => true,
         b'_' => true,
         b'.' => true,
         b'+' => true,
         _ => nom::character::is_alphanumeric(v),
-    };
+    }
+}
+
+/// command-name = short-size 1*255command-name-char
+fn is_command_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.len() <= 255 && name.iter().all(|v| v.is_ascii_alphabetic())
+}
+
+fn is_property_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.len() <= 255 && name.iter().all(|v| is_name_char(*v))
+}
+
+fn property<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], (&'a str, ByteSlice<&'a [u8]>)> {
     let (input, name_len) = be_u8(input)?;
     let (input, name_raw) = take_while_m_n(1, name_len as usize, is_name_char)(input)?;
-    let (input, value_len) = be_u32(input)?;
-    let (input, value) = take(value_len as usize)(input)?;
+    if name_raw.len() != name_len as usize {
+        return fail(input, ZmtpErrorKind::MalformedProperty);
+    }
+    let (remaining, value_len) = be_u32(input)?;
+    if let Err(kind) = limits.check_property_value_len(value_len as usize) {
+        return fail_hard(input, kind);
+    }
+    let (input, value) = take(value_len as usize)(remaining)?;
 
     // If this conversion ever causes performance problems, it could be replaced with an unsafe
     // variant. The constraint "is_name_char" is stronger than utf8 validity.
@@ -173,58 +495,164 @@ pub fn command<'a>(
     hdr: &FrameHeader,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    command_limited(
+        input,
+        hdr,
+        &SecurityMechanism::NULL,
+        &Limits::unlimited(),
+        logger,
+    )
+}
+
+/// Parse a command, enforcing the metadata limits.
+///
+/// The commands of the security handshake are only recognized for the given `mechanism`; for
+/// any other mechanism they are passed on as `Command::Unknown`.
+pub fn command_limited<'a>(
+    input: &'a [u8],
+    hdr: &FrameHeader,
+    mechanism: &SecurityMechanism,
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    // command-body = command-name command-data
+    // command-name = short-size 1*255command-name-char
+    // command-name-char = ALPHA
     let (input, cmd_name_len) = be_u8(input)?;
+    let (remaining, cmd_name) = take(cmd_name_len as usize)(input)?;
 
-    // sanity check: longest command name is SUBSCRIBE
-    if cmd_name_len > 10 {
-        Err(nom::Err::Error(nom::error::make_error(
-            input,
-            nom::error::ErrorKind::LengthValue,
-        )))
-    } else {
-        let (cmd_name, remaining) = input.split_at(cmd_name_len as usize);
-
-        assert!(hdr.frame_length >= cmd_name.len() + 1);
-        let data_len = hdr.frame_length - 1 - cmd_name.len();
-
-        match cmd_name {
-            b"READY" => command_ready_meta_data(remaining, data_len, logger),
-            b"ERROR" => command_error_reason(remaining, logger),
-            b"SUBSCRIBE" => command_subscribe_subscription(remaining, data_len, logger),
-            b"CANCEL" => command_cancel_subscription(remaining, data_len, logger),
-            b"PING" => command_ping(remaining, data_len, logger),
-            b"PONG" => command_pong(remaining, data_len, logger),
-            _ => Err(nom::Err::Error(nom::error::make_error(
-                input,
-                nom::error::ErrorKind::OneOf,
-            ))),
+    if !is_command_name(cmd_name) {
+        return fail(input, ZmtpErrorKind::InvalidCommandName);
+    }
+
+    let data_len = match hdr.frame_length.checked_sub(1 + cmd_name.len()) {
+        Some(data_len) => data_len,
+        // the frame is too short to hold the command name
+        None => return fail(input, ZmtpErrorKind::TruncatedFrame),
+    };
+
+    match (mechanism, cmd_name) {
+        (SecurityMechanism::PLAIN, b"HELLO") => command_hello(remaining, data_len, logger),
+        (SecurityMechanism::PLAIN, b"WELCOME") => command_welcome(remaining, data_len, logger),
+        (SecurityMechanism::PLAIN, b"INITIATE") => {
+            command_initiate_meta_data(remaining, data_len, limits, logger)
+        }
+        (SecurityMechanism::CURVE, b"HELLO")
+        | (SecurityMechanism::CURVE, b"WELCOME")
+        | (SecurityMechanism::CURVE, b"INITIATE")
+        | (SecurityMechanism::CURVE, b"READY")
+        | (SecurityMechanism::CURVE, b"MESSAGE") => {
+            command_curve(cmd_name, remaining, data_len, logger)
         }
+        (_, b"READY") => command_ready_meta_data(remaining, data_len, limits, logger),
+        (_, b"ERROR") => command_error_reason(remaining, data_len, logger),
+        (_, b"SUBSCRIBE") => command_subscribe_subscription(remaining, data_len, logger),
+        (_, b"CANCEL") => command_cancel_subscription(remaining, data_len, logger),
+        (_, b"PING") => command_ping(remaining, data_len, logger),
+        (_, b"PONG") => command_pong(remaining, data_len, logger),
+        (_, _) => command_unknown(cmd_name, remaining, data_len, logger),
     }
 }
 
+/// A command this implementation does not know; the data is passed on unparsed.
+fn command_unknown<'a>(
+    name_raw: &'a [u8],
+    input: &'a [u8],
+    data_len: usize,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    let (input, body) = take(data_len)(input)?;
+
+    // command names are ALPHA only, thus valid utf8
+    let name = core::str::from_utf8(name_raw).unwrap_or("<this cannot happen>");
+    debug!(logger, "unknown command:"; o!("name" => name), o!("data_len" => data_len));
+
+    Ok((input, Command::Unknown { name, body }))
+}
+
 fn command_ready_meta_data<'a>(
     input: &'a [u8],
     data_len: usize,
+    limits: &Limits,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
-    let (input, md) = meta_data(input, data_len, logger)?;
+    let (input, md) = meta_data(input, data_len, limits, logger)?;
 
     Ok((input, Command::READY(md)))
 }
 
+/// Hello command of the PLAIN mechanism
+///
+/// hello = command-size %d5 "HELLO" username password
+/// username = short-size 0*255username-char
+/// password = short-size 0*255password-char
+fn command_hello<'a>(
+    input: &'a [u8],
+    data_len: usize,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    let (remaining, data) = take(data_len)(input)?;
+    let (data, username_len) = be_u8(data)?;
+    let (data, username) = take(username_len as usize)(data)?;
+    let (data, password_len) = be_u8(data)?;
+    let (data, password) = take(password_len as usize)(data)?;
+    if !data.is_empty() {
+        return fail(data, ZmtpErrorKind::MalformedCommand);
+    }
+    // never log the password
+    trace!(logger, "command hello:"; o!("username" => ByteSlice(username)));
+
+    Ok((remaining, Command::HELLO(Hello { username, password })))
+}
+
+/// Welcome command of the PLAIN mechanism
+///
+/// welcome = command-size %d7 "WELCOME"
+fn command_welcome<'a>(
+    input: &'a [u8],
+    data_len: usize,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    if data_len != 0 {
+        return fail(input, ZmtpErrorKind::MalformedCommand);
+    }
+    trace!(logger, "command welcome");
+
+    Ok((input, Command::WELCOME))
+}
+
+/// Initiate command of the PLAIN mechanism
+///
+/// initiate = command-size %d8 "INITIATE" *property
+fn command_initiate_meta_data<'a>(
+    input: &'a [u8],
+    data_len: usize,
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    let (input, md) = meta_data(input, data_len, limits, logger)?;
+
+    Ok((input, Command::INITIATE(md)))
+}
+
 /// Error command
 ///
 /// error-reason = short-size 0*255VCHAR
 fn command_error_reason<'a>(
     input: &'a [u8],
+    data_len: usize,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
-    let (input, len) = be_u8(input)?;
-    let (input, error_txt) = take(len as usize)(input)?;
+    let (remaining, data) = take(data_len)(input)?;
+    let (data, len) = be_u8(data)?;
+    let (data, error_txt) = take(len as usize)(data)?;
+    if !data.is_empty() {
+        return fail(data, ZmtpErrorKind::MalformedCommand);
+    }
     trace!(logger, "command_error:";
         o!("length" => len),
         o!("content" => ByteSlice(error_txt)));
-    Ok((input, Command::ERROR(ByteSlice(error_txt))))
+    Ok((remaining, Command::ERROR(ByteSlice(error_txt))))
 }
 
 /// Subcribe
@@ -272,7 +700,9 @@ fn command_ping<'a>(
     data_len: usize,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
-    assert!(data_len > 2);
+    if data_len < 2 {
+        return fail(input, ZmtpErrorKind::TruncatedFrame);
+    }
     let (input, ttl) = be_u16(input)?;
     let (input, context) = take(data_len - 2)(input)?;
     trace!(logger, "command ping:"; o!("ttl" => ttl), o!("context" => ByteSlice(context)));
@@ -290,3 +720,385 @@ fn command_pong<'a>(
 
     Ok((input, Command::PONG(Pong { context })))
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use slog::*;
+
+    use crate::tests::make_logger;
+    use crate::*;
+
+    fn all_commands() -> Vec<Frame<&'static str, &'static [u8]>> {
+        let mut logger = make_logger();
+        let (_, ready) = frame(
+            b"\x04\x19\x05READY\x0bSocket-Type\x00\x00\x00\x03REP",
+            &mut logger,
+        )
+        .unwrap();
+
+        vec![
+            ready,
+            Frame::Command(Command::ERROR(ByteSlice(&b"bad socket type"[..]))),
+            Frame::Command(Command::SUBSCRIBE(ByteSlice(&b"weather"[..]))),
+            Frame::Command(Command::CANCEL(ByteSlice(&b"weather"[..]))),
+            Frame::Command(Command::PING(Ping {
+                ttl: 300,
+                context: &b"ctx"[..],
+            })),
+            Frame::Command(Command::PONG(Pong {
+                context: &b"ctx"[..],
+            })),
+        ]
+    }
+
+    #[test]
+    fn truncated_command_frames() {
+        let mut logger = make_logger().new(o!("test" => "truncated_command_frames"));
+
+        for cmd in all_commands() {
+            let mut encoded = Vec::new();
+            cmd.encode(&mut encoded).unwrap();
+            let body = &encoded[2..];
+            let name_len = match &cmd {
+                Frame::Command(cmd) => cmd.name().len(),
+                Frame::Message(_) => unreachable!(),
+            };
+
+            for len in 0..body.len() {
+                // the header announces the short length: a short, but complete frame
+                let mut short_frame = Vec::new();
+                FrameHeader::new(true, false, len).encode(&mut short_frame);
+                short_frame.extend_from_slice(&body[..len]);
+                let expected = match (&cmd, len.checked_sub(1 + name_len)) {
+                    // the name is cut off
+                    (_, None) => Some(ZmtpErrorKind::TruncatedFrame),
+                    (Frame::Command(Command::READY(_)), Some(0)) => None,
+                    (Frame::Command(Command::READY(_)), Some(_)) => {
+                        Some(ZmtpErrorKind::MalformedProperty)
+                    }
+                    (Frame::Command(Command::ERROR(_)), Some(_)) => {
+                        Some(ZmtpErrorKind::TruncatedFrame)
+                    }
+                    (Frame::Command(Command::PING(_)), Some(data_len)) if data_len < 2 => {
+                        Some(ZmtpErrorKind::TruncatedFrame)
+                    }
+                    // topics and contexts may be shorter
+                    _ => None,
+                };
+                match frame(&short_frame, &mut logger) {
+                    Ok((rest, _)) => assert!(
+                        expected.is_none() && rest.is_empty(),
+                        "{:?} cut to {} octets",
+                        cmd,
+                        len
+                    ),
+                    Err(nom::Err::Error(e)) => {
+                        assert_eq!(Some(e.kind), expected, "{:?} cut to {} octets", cmd, len)
+                    }
+                    other => panic!("unexpected result {:?}", other),
+                }
+
+                // the header announces the full length, but the data ends early
+                let truncated = &encoded[..2 + len];
+                assert!(
+                    frame(truncated, &mut logger).is_err(),
+                    "{:?} truncated to {} octets",
+                    cmd,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_accessors() {
+        let mut logger = make_logger().new(o!("test" => "metadata_accessors"));
+
+        let client_ready =
+            b"\x04\x26\x05READY\x0bSocket-Type\x00\x00\x00\x03REQ\x08Identity\x00\x00\x00\x00";
+        let (_, parsed) = frame(client_ready, &mut logger).unwrap();
+        let md = match parsed {
+            Frame::Command(Command::READY(md)) => md,
+            other => panic!("expected READY, got {:?}", other),
+        };
+
+        assert_eq!(md.len(), 2);
+        assert_eq!(md.socket_type(), Some(&&b"REQ"[..]));
+        assert_eq!(md.get("socket-type"), Some(&&b"REQ"[..]));
+        assert_eq!(md.get("SOCKET-TYPE"), Some(&&b"REQ"[..]));
+        assert_eq!(md.identity(), Some(&&b""[..]));
+        assert_eq!(md.resource(), None);
+
+        let names: Vec<_> = md.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["Socket-Type", "Identity"]);
+    }
+
+    #[test]
+    fn metadata_builder_encodes_like_libzmq() {
+        let mut logger = make_logger().new(o!("test" => "metadata_builder_encodes_like_libzmq"));
+
+        let md: MetaData<&str, &[u8]> = MetaData::new()
+            .with_socket_type(&b"REQ"[..])
+            .with_identity(&b""[..])
+            .with_property("x-custom", &b"1"[..])
+            .with_property("X-Custom", &b"2"[..]);
+        assert_eq!(md.get("x-custom"), Some(&&b"2"[..]));
+        assert_eq!(md.len(), 3);
+
+        let ready = Frame::Command(Command::READY(
+            MetaData::new()
+                .with_socket_type(&b"REQ"[..])
+                .with_identity(&b""[..]),
+        ));
+        let mut encoded = Vec::new();
+        ready.encode(&mut encoded).unwrap();
+        assert_eq!(
+            &encoded[..],
+            &b"\x04\x26\x05READY\x0bSocket-Type\x00\x00\x00\x03REQ\x08Identity\x00\x00\x00\x00"[..]
+        );
+
+        let (_, parsed) = frame(&encoded, &mut logger).unwrap();
+        assert_eq!(parsed, ready);
+    }
+
+    #[test]
+    fn invalid_property_names_are_not_encoded() {
+        let long_name = "X".repeat(256);
+        for name in ["", "no space", long_name.as_str()].iter() {
+            let md: MetaData<&str, &[u8]> = MetaData::new()
+                .with_socket_type(&b"REQ"[..])
+                .with_property(name, &b"1"[..]);
+            let mut encoded = Vec::new();
+            assert_eq!(
+                md.encode(&mut encoded),
+                Err(EncodeError::InvalidPropertyName)
+            );
+            assert_eq!(
+                Frame::Command(Command::READY(md)).encode(&mut encoded),
+                Err(EncodeError::InvalidPropertyName)
+            );
+            assert!(encoded.is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_passed_on() {
+        let mut logger = make_logger().new(o!("test" => "unknown_commands_are_passed_on"));
+
+        let hello = b"\x04\x0c\x05HELLO\x05hello";
+        let (rest, parsed) = frame(hello, &mut logger).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            parsed,
+            Frame::Command(Command::Unknown {
+                name: "HELLO",
+                body: &b"\x05hello"[..]
+            })
+        );
+
+        let mut encoded = Vec::new();
+        parsed.encode(&mut encoded).unwrap();
+        assert_eq!(&encoded[..], &hello[..]);
+    }
+
+    fn plain_frame<'a>(
+        input: &'a [u8],
+        logger: &mut slog::Logger,
+    ) -> IResult<&'a [u8], Frame<&'a str, &'a [u8]>> {
+        frame_limited(
+            input,
+            &SecurityMechanism::PLAIN,
+            &Limits::unlimited(),
+            logger,
+        )
+    }
+
+    #[test]
+    fn plain_handshake_commands() {
+        let mut logger = make_logger().new(o!("test" => "plain_handshake_commands"));
+
+        let hello = b"\x04\x0f\x05HELLO\x05admin\x02pw";
+        let welcome = b"\x04\x08\x07WELCOME";
+        let initiate = b"\x04\x1c\x08INITIATE\x0bSocket-Type\x00\x00\x00\x03REQ";
+
+        let expected: Vec<Frame<&str, &[u8]>> = vec![
+            Frame::Command(Command::HELLO(Hello {
+                username: &b"admin"[..],
+                password: &b"pw"[..],
+            })),
+            Frame::Command(Command::WELCOME),
+            Frame::Command(Command::INITIATE(
+                MetaData::new().with_socket_type(&b"REQ"[..]),
+            )),
+        ];
+
+        for (input, cmd) in [&hello[..], &welcome[..], &initiate[..]]
+            .iter()
+            .zip(expected.iter())
+        {
+            let (rest, parsed) = plain_frame(input, &mut logger).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(&parsed, cmd);
+
+            let mut encoded = Vec::new();
+            cmd.encode(&mut encoded).unwrap();
+            assert_eq!(&encoded[..], *input);
+
+            for len in 0..input.len() {
+                assert!(plain_frame(&input[..len], &mut logger).is_err());
+            }
+        }
+
+        // without the PLAIN mechanism these are passed on
+        let (_, parsed) = frame(welcome, &mut logger).unwrap();
+        assert_eq!(
+            parsed,
+            Frame::Command(Command::Unknown {
+                name: "WELCOME",
+                body: &b""[..]
+            })
+        );
+    }
+
+    #[test]
+    fn long_credentials_are_not_encoded() {
+        let long = [b'x'; 256];
+        for (username, password) in [(&long[..], &b"pw"[..]), (&b"admin"[..], &long[..])].iter() {
+            let hello: Frame<&str, &[u8]> =
+                Frame::Command(Command::HELLO(Hello { username, password }));
+            let mut encoded = Vec::new();
+            assert_eq!(hello.encode(&mut encoded), Err(EncodeError::StringTooLong));
+            assert!(encoded.is_empty());
+        }
+
+        // 255 octets still fit
+        let hello: Frame<&str, &[u8]> = Frame::Command(Command::HELLO(Hello {
+            username: &long[..255],
+            password: &b""[..],
+        }));
+        let mut encoded = Vec::new();
+        hello.encode(&mut encoded).unwrap();
+        assert_eq!(encoded.len(), hello.encoded_len());
+    }
+
+    #[test]
+    fn malformed_plain_commands() {
+        let mut logger = make_logger().new(o!("test" => "malformed_plain_commands"));
+
+        let inputs = [
+            // trailing data after the password
+            &b"\x04\x10\x05HELLO\x05admin\x02pwx"[..],
+            // WELCOME carries no data
+            &b"\x04\x09\x07WELCOMEx"[..],
+        ];
+        for input in inputs.iter() {
+            match plain_frame(input, &mut logger) {
+                Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::MalformedCommand),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        // the password runs past the end of the frame
+        let long_password = b"\x04\x0c\x05HELLO\x00\x05pw\x00\x00\x00";
+        match plain_frame(long_password, &mut logger) {
+            Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::TruncatedFrame),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn long_command_names() {
+        let mut logger = make_logger().new(o!("test" => "long_command_names"));
+
+        let name = "X".repeat(255);
+        let cmd: Frame<&str, &[u8]> = Frame::Command(Command::Unknown {
+            name: &name,
+            body: &b"data"[..],
+        });
+        let mut encoded = Vec::new();
+        cmd.encode(&mut encoded).unwrap();
+        assert_eq!(encoded.len(), 9 + 1 + 255 + 4);
+
+        let (rest, parsed) = frame(&encoded, &mut logger).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed, cmd);
+
+        // doesn't fit the short-size of the name
+        let name = "X".repeat(256);
+        let cmd: Frame<&str, &[u8]> = Frame::Command(Command::Unknown {
+            name: &name,
+            body: &b"data"[..],
+        });
+        let mut encoded = Vec::new();
+        assert_eq!(
+            cmd.encode(&mut encoded),
+            Err(EncodeError::InvalidCommandName)
+        );
+        assert!(encoded.is_empty());
+    }
+
+    #[test]
+    fn invalid_command_names() {
+        let mut logger = make_logger().new(o!("test" => "invalid_command_names"));
+
+        for input in [&b"\x04\x01\x00"[..], &b"\x04\x06\x05HEL-O"[..]].iter() {
+            match frame(input, &mut logger) {
+                Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::InvalidCommandName),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn short_command_frames_are_rejected() {
+        let mut logger = make_logger().new(o!("test" => "short_command_frames_are_rejected"));
+
+        // header announces 3 octets, name needs 5
+        let short_name = b"\x04\x03\x05REA";
+        match frame(short_name, &mut logger) {
+            Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::TruncatedFrame),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // PING without ttl
+        let short_ping = b"\x04\x06\x04PING\x01";
+        match frame(short_ping, &mut logger) {
+            Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::TruncatedFrame),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // PING with an empty context is fine
+        let empty_ping = b"\x04\x07\x04PING\x00\x01";
+        let (rest, parsed) = frame(empty_ping, &mut logger).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            parsed,
+            Frame::Command(Command::PING(Ping {
+                ttl: 1,
+                context: &b""[..]
+            }))
+        );
+
+        // the error reason runs into the next frame
+        let long_reason = b"\x04\x08\x05ERROR\x05a\x00\x03abc";
+        match frame(long_reason, &mut logger) {
+            Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::TruncatedFrame),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // trailing data after the error reason
+        let trailing_reason = b"\x04\x09\x05ERROR\x01ab";
+        match frame(trailing_reason, &mut logger) {
+            Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::MalformedCommand),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // property running past the end of the frame
+        let long_property = b"\x04\x0c\x05READY\x01a\x00\x00\x00\x05abcde";
+        match frame(long_property, &mut logger) {
+            Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::MalformedProperty),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
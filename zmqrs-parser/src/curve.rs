@@ -0,0 +1,403 @@
+//! Commands of the CURVE security mechanism, see https://rfc.zeromq.org/spec/26/
+//!
+//! Only the wire format is handled here; the boxes are opaque and have to be opened by the
+//! security handshake.
+
+use bytes::BufMut;
+use nom::{
+    bytes::complete::{tag, take},
+    number::complete::be_u64,
+};
+
+use crate::error::{fail, with_kind, IResult, ZmtpErrorKind};
+use crate::{ByteSlice, Command};
+
+/// Length of public and secret keys.
+pub const CURVE_KEY_LEN: usize = 32;
+/// Length of the long nonces of WELCOME, cookie and vouch.
+pub const CURVE_LONG_NONCE_LEN: usize = 16;
+/// Length of the message authentication code each box starts with.
+pub const CURVE_MAC_LEN: usize = 16;
+/// Length of the cookie sent with WELCOME and INITIATE.
+pub const CURVE_COOKIE_LEN: usize = CURVE_LONG_NONCE_LEN + CURVE_MAC_LEN + 2 * CURVE_KEY_LEN;
+
+const HELLO_VERSION: [u8; 2] = [1, 0];
+const HELLO_PADDING_LEN: usize = 72;
+/// hello-box = 80OCTET, the box of 64 zero octets
+const HELLO_BOX_LEN: usize = CURVE_MAC_LEN + 64;
+/// welcome-box = 144OCTET, the box of the server's transient key and the cookie
+const WELCOME_BOX_LEN: usize = CURVE_MAC_LEN + CURVE_KEY_LEN + CURVE_COOKIE_LEN;
+/// The initiate box holds at least the client's permanent key and the vouch.
+const INITIATE_BOX_MIN_LEN: usize = CURVE_MAC_LEN + CURVE_KEY_LEN + CURVE_COOKIE_LEN;
+/// The message box holds at least the flags octet.
+const MESSAGE_BOX_MIN_LEN: usize = CURVE_MAC_LEN + 1;
+
+/// A command of the CURVE handshake or an encrypted frame.
+///
+/// Short nonces are 8 octet counters in network byte order, long nonces are 16 random octets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurveCommand<T> {
+    HELLO(CurveHello<T>),
+    WELCOME(CurveWelcome<T>),
+    INITIATE(CurveInitiate<T>),
+    READY(CurveReady<T>),
+    MESSAGE(CurveMessage<T>),
+}
+
+/// hello = %d5 "HELLO" version padding hello-client hello-nonce hello-box
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurveHello<T> {
+    /// The client's transient public key.
+    pub client_key: T,
+    pub nonce: u64,
+    pub hello_box: T,
+}
+
+/// welcome = %d7 "WELCOME" welcome-nonce welcome-box
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurveWelcome<T> {
+    pub nonce: T,
+    pub welcome_box: T,
+}
+
+/// initiate = %d8 "INITIATE" cookie initiate-nonce initiate-box
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurveInitiate<T> {
+    pub cookie: T,
+    pub nonce: u64,
+    pub initiate_box: T,
+}
+
+/// ready = %d5 "READY" ready-nonce ready-box
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurveReady<T> {
+    pub nonce: u64,
+    pub ready_box: T,
+}
+
+/// message = %d7 "MESSAGE" message-nonce message-box
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurveMessage<T> {
+    pub nonce: u64,
+    pub message_box: T,
+}
+
+impl From<(&bytes::Bytes, CurveCommand<&[u8]>)> for CurveCommand<bytes::Bytes> {
+    fn from(input: (&bytes::Bytes, CurveCommand<&[u8]>)) -> Self {
+        let (buffer, cmd) = input;
+        match cmd {
+            CurveCommand::HELLO(hello) => CurveCommand::HELLO(CurveHello {
+                client_key: buffer.slice_ref(hello.client_key),
+                nonce: hello.nonce,
+                hello_box: buffer.slice_ref(hello.hello_box),
+            }),
+            CurveCommand::WELCOME(welcome) => CurveCommand::WELCOME(CurveWelcome {
+                nonce: buffer.slice_ref(welcome.nonce),
+                welcome_box: buffer.slice_ref(welcome.welcome_box),
+            }),
+            CurveCommand::INITIATE(initiate) => CurveCommand::INITIATE(CurveInitiate {
+                cookie: buffer.slice_ref(initiate.cookie),
+                nonce: initiate.nonce,
+                initiate_box: buffer.slice_ref(initiate.initiate_box),
+            }),
+            CurveCommand::READY(ready) => CurveCommand::READY(CurveReady {
+                nonce: ready.nonce,
+                ready_box: buffer.slice_ref(ready.ready_box),
+            }),
+            CurveCommand::MESSAGE(message) => CurveCommand::MESSAGE(CurveMessage {
+                nonce: message.nonce,
+                message_box: buffer.slice_ref(message.message_box),
+            }),
+        }
+    }
+}
+
+impl<T> CurveCommand<T> {
+    pub fn name(&self) -> &'static [u8] {
+        match self {
+            CurveCommand::HELLO(_) => b"HELLO",
+            CurveCommand::WELCOME(_) => b"WELCOME",
+            CurveCommand::INITIATE(_) => b"INITIATE",
+            CurveCommand::READY(_) => b"READY",
+            CurveCommand::MESSAGE(_) => b"MESSAGE",
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> CurveCommand<T> {
+    /// Number of octets of the command data, i.e. without the command name.
+    pub(crate) fn data_len(&self) -> usize {
+        match self {
+            CurveCommand::HELLO(hello) => {
+                HELLO_VERSION.len()
+                    + HELLO_PADDING_LEN
+                    + hello.client_key.as_ref().len()
+                    + 8
+                    + hello.hello_box.as_ref().len()
+            }
+            CurveCommand::WELCOME(welcome) => {
+                welcome.nonce.as_ref().len() + welcome.welcome_box.as_ref().len()
+            }
+            CurveCommand::INITIATE(initiate) => {
+                initiate.cookie.as_ref().len() + 8 + initiate.initiate_box.as_ref().len()
+            }
+            CurveCommand::READY(ready) => 8 + ready.ready_box.as_ref().len(),
+            CurveCommand::MESSAGE(message) => 8 + message.message_box.as_ref().len(),
+        }
+    }
+
+    /// Write the command data; name and header are written by `Command::encode`.
+    pub(crate) fn encode<B: BufMut>(&self, dst: &mut B) {
+        match self {
+            CurveCommand::HELLO(hello) => {
+                dst.put_slice(&HELLO_VERSION);
+                dst.put_slice(&[0u8; HELLO_PADDING_LEN]);
+                dst.put_slice(hello.client_key.as_ref());
+                dst.put_u64(hello.nonce);
+                dst.put_slice(hello.hello_box.as_ref());
+            }
+            CurveCommand::WELCOME(welcome) => {
+                dst.put_slice(welcome.nonce.as_ref());
+                dst.put_slice(welcome.welcome_box.as_ref());
+            }
+            CurveCommand::INITIATE(initiate) => {
+                dst.put_slice(initiate.cookie.as_ref());
+                dst.put_u64(initiate.nonce);
+                dst.put_slice(initiate.initiate_box.as_ref());
+            }
+            CurveCommand::READY(ready) => {
+                dst.put_u64(ready.nonce);
+                dst.put_slice(ready.ready_box.as_ref());
+            }
+            CurveCommand::MESSAGE(message) => {
+                dst.put_u64(message.nonce);
+                dst.put_slice(message.message_box.as_ref());
+            }
+        }
+    }
+}
+
+/// Parse the data of a CURVE command; `name` was already checked to be a CURVE command.
+pub(crate) fn command_curve<'a>(
+    name: &[u8],
+    input: &'a [u8],
+    data_len: usize,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Command<&'a str, &'a [u8]>> {
+    let (remaining, data) = take(data_len)(input)?;
+
+    let (_, cmd) = match name {
+        b"HELLO" => curve_hello(data)?,
+        b"WELCOME" => curve_welcome(data)?,
+        b"INITIATE" => curve_initiate(data)?,
+        b"READY" => curve_ready(data)?,
+        _ => curve_message(data)?,
+    };
+    trace!(logger, "command curve:";
+        o!("name" => ByteSlice(name)),
+        o!("data_len" => data_len));
+
+    Ok((remaining, Command::CURVE(cmd)))
+}
+
+/// Fail unless the command data was consumed completely.
+fn complete<'a>(
+    data: &'a [u8],
+    cmd: CurveCommand<&'a [u8]>,
+) -> IResult<&'a [u8], CurveCommand<&'a [u8]>> {
+    if data.is_empty() {
+        Ok((data, cmd))
+    } else {
+        fail(data, ZmtpErrorKind::MalformedCommand)
+    }
+}
+
+/// Fail if fewer than `len` octets are left for a box.
+fn check_box_len(data: &[u8], len: usize) -> IResult<&[u8], ()> {
+    if data.len() < len {
+        fail(data, ZmtpErrorKind::MalformedCommand)
+    } else {
+        Ok((data, ()))
+    }
+}
+
+/// version = %x1 %x0
+/// padding = 72%x00
+/// hello-client = 32OCTET
+/// hello-nonce = 8OCTET
+fn curve_hello(data: &[u8]) -> IResult<&[u8], CurveCommand<&[u8]>> {
+    let (data, _) =
+        tag(HELLO_VERSION)(data).map_err(|e| with_kind(e, ZmtpErrorKind::MalformedCommand))?;
+    let (data, _) = take(HELLO_PADDING_LEN)(data)?;
+    let (data, client_key) = take(CURVE_KEY_LEN)(data)?;
+    let (data, nonce) = be_u64(data)?;
+    let (data, hello_box) = take(HELLO_BOX_LEN)(data)?;
+    complete(
+        data,
+        CurveCommand::HELLO(CurveHello {
+            client_key,
+            nonce,
+            hello_box,
+        }),
+    )
+}
+
+/// welcome-nonce = 16OCTET
+/// welcome-box = 144OCTET
+fn curve_welcome(data: &[u8]) -> IResult<&[u8], CurveCommand<&[u8]>> {
+    let (data, nonce) = take(CURVE_LONG_NONCE_LEN)(data)?;
+    let (data, welcome_box) = take(WELCOME_BOX_LEN)(data)?;
+    complete(
+        data,
+        CurveCommand::WELCOME(CurveWelcome { nonce, welcome_box }),
+    )
+}
+
+/// cookie = 96OCTET
+/// initiate-nonce = 8OCTET
+/// initiate-box = 144*OCTET
+fn curve_initiate(data: &[u8]) -> IResult<&[u8], CurveCommand<&[u8]>> {
+    let (data, cookie) = take(CURVE_COOKIE_LEN)(data)?;
+    let (data, nonce) = be_u64(data)?;
+    let (data, _) = check_box_len(data, INITIATE_BOX_MIN_LEN)?;
+    Ok((
+        &data[data.len()..],
+        CurveCommand::INITIATE(CurveInitiate {
+            cookie,
+            nonce,
+            initiate_box: data,
+        }),
+    ))
+}
+
+/// ready-nonce = 8OCTET
+/// ready-box = 16*OCTET
+fn curve_ready(data: &[u8]) -> IResult<&[u8], CurveCommand<&[u8]>> {
+    let (data, nonce) = be_u64(data)?;
+    let (data, _) = check_box_len(data, CURVE_MAC_LEN)?;
+    Ok((
+        &data[data.len()..],
+        CurveCommand::READY(CurveReady {
+            nonce,
+            ready_box: data,
+        }),
+    ))
+}
+
+/// message-nonce = 8OCTET
+/// message-box = 17*OCTET
+fn curve_message(data: &[u8]) -> IResult<&[u8], CurveCommand<&[u8]>> {
+    let (data, nonce) = be_u64(data)?;
+    let (data, _) = check_box_len(data, MESSAGE_BOX_MIN_LEN)?;
+    Ok((
+        &data[data.len()..],
+        CurveCommand::MESSAGE(CurveMessage {
+            nonce,
+            message_box: data,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use slog::*;
+
+    use super::*;
+    use crate::tests::make_logger;
+    use crate::*;
+
+    fn curve_frame<'a>(
+        input: &'a [u8],
+        logger: &mut slog::Logger,
+    ) -> IResult<&'a [u8], Frame<&'a str, &'a [u8]>> {
+        frame_limited(
+            input,
+            &SecurityMechanism::CURVE,
+            &Limits::unlimited(),
+            logger,
+        )
+    }
+
+    fn all_commands() -> Vec<Frame<&'static str, &'static [u8]>> {
+        vec![
+            CurveCommand::HELLO(CurveHello {
+                client_key: &[1u8; CURVE_KEY_LEN][..],
+                nonce: 1,
+                hello_box: &[2u8; HELLO_BOX_LEN][..],
+            }),
+            CurveCommand::WELCOME(CurveWelcome {
+                nonce: &[3u8; CURVE_LONG_NONCE_LEN][..],
+                welcome_box: &[4u8; WELCOME_BOX_LEN][..],
+            }),
+            CurveCommand::INITIATE(CurveInitiate {
+                cookie: &[5u8; CURVE_COOKIE_LEN][..],
+                nonce: 2,
+                initiate_box: &[6u8; INITIATE_BOX_MIN_LEN + 3][..],
+            }),
+            CurveCommand::READY(CurveReady {
+                nonce: 1,
+                ready_box: &[7u8; CURVE_MAC_LEN + 5][..],
+            }),
+            CurveCommand::MESSAGE(CurveMessage {
+                nonce: 3,
+                message_box: &[8u8; MESSAGE_BOX_MIN_LEN][..],
+            }),
+        ]
+        .into_iter()
+        .map(|cmd| Frame::Command(Command::CURVE(cmd)))
+        .collect()
+    }
+
+    #[test]
+    fn curve_commands_round_trip() {
+        let mut logger = make_logger().new(o!("test" => "curve_commands_round_trip"));
+
+        for cmd in all_commands() {
+            let mut encoded = Vec::new();
+            cmd.encode(&mut encoded).unwrap();
+            assert_eq!(encoded.len(), cmd.encoded_len());
+
+            let (rest, parsed) = curve_frame(&encoded, &mut logger).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, cmd);
+
+            for len in 0..encoded.len() {
+                assert!(curve_frame(&encoded[..len], &mut logger).is_err());
+            }
+        }
+
+        // the libzmq HELLO is 200 octets long
+        let mut encoded = Vec::new();
+        all_commands()[0].encode(&mut encoded).unwrap();
+        assert_eq!(&encoded[..2], &[0x04, 200][..]);
+    }
+
+    #[test]
+    fn malformed_curve_commands() {
+        let mut logger = make_logger().new(o!("test" => "malformed_curve_commands"));
+
+        let commands = all_commands();
+        let mut hello = Vec::new();
+        commands[0].encode(&mut hello).unwrap();
+
+        // unsupported version
+        let mut bad_version = hello.clone();
+        bad_version[8] = 2;
+        // box of a short command frame
+        let mut short_ready = Vec::new();
+        Frame::<&str, &[u8]>::Command(Command::CURVE(CurveCommand::READY(CurveReady {
+            nonce: 1,
+            ready_box: &[0u8; CURVE_MAC_LEN - 1][..],
+        })))
+        .encode(&mut short_ready)
+        .unwrap();
+
+        for input in [&bad_version[..], &short_ready[..]].iter() {
+            match curve_frame(input, &mut logger) {
+                Err(nom::Err::Error(e)) => assert_eq!(e.kind, ZmtpErrorKind::MalformedCommand),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use nom::error::{ErrorKind, ParseError};
+
+/// What went wrong while parsing ZMTP data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZmtpErrorKind {
+    /// The greeting does not start with `%xFF padding %x7F`.
+    BadSignature,
+    /// The security mechanism of the greeting is not supported.
+    UnsupportedMechanism,
+    /// The greeting announces a ZMTP revision that is not supported.
+    UnsupportedVersion,
+    /// The socket type octet of a ZMTP/2.0 greeting is unknown.
+    UnknownSocketType,
+    /// The name of a command frame is empty or contains characters other than ALPHA.
+    InvalidCommandName,
+    /// The data of a command does not match its definition.
+    MalformedCommand,
+    /// The frame header is not valid for the ZMTP revision of the peer.
+    MalformedFrame,
+    /// A metadata property could not be parsed.
+    MalformedProperty,
+    /// The data ended before the element was complete.
+    TruncatedFrame,
+    /// The announced frame size cannot be handled or exceeds `Limits::max_frame_size`.
+    FrameTooLarge,
+    /// The multipart message exceeds `Limits::max_message_size`.
+    MessageTooLarge,
+    /// A command carries more properties than `Limits::max_properties`.
+    TooManyProperties,
+    /// A property value is longer than `Limits::max_property_value_len`.
+    PropertyTooLarge,
+    /// Any other error reported by nom.
+    Nom(ErrorKind),
+}
+
+impl ZmtpErrorKind {
+    pub fn description(&self) -> &str {
+        match self {
+            ZmtpErrorKind::BadSignature => "bad signature",
+            ZmtpErrorKind::UnsupportedMechanism => "unsupported security mechanism",
+            ZmtpErrorKind::UnsupportedVersion => "unsupported version",
+            ZmtpErrorKind::UnknownSocketType => "unknown socket type",
+            ZmtpErrorKind::InvalidCommandName => "invalid command name",
+            ZmtpErrorKind::MalformedCommand => "malformed command",
+            ZmtpErrorKind::MalformedFrame => "malformed frame",
+            ZmtpErrorKind::MalformedProperty => "malformed property",
+            ZmtpErrorKind::TruncatedFrame => "truncated frame",
+            ZmtpErrorKind::FrameTooLarge => "frame too large",
+            ZmtpErrorKind::MessageTooLarge => "message too large",
+            ZmtpErrorKind::TooManyProperties => "too many properties",
+            ZmtpErrorKind::PropertyTooLarge => "property too large",
+            ZmtpErrorKind::Nom(kind) => kind.description(),
+        }
+    }
+}
+
+/// A value that can't be represented on the wire, so encoding it was refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodeError {
+    /// A command name isn't 1 to 255 ALPHA characters.
+    InvalidCommandName,
+    /// A property name isn't 1 to 255 characters out of ALPHA, DIGIT, "-", "_", "." and "+".
+    InvalidPropertyName,
+    /// A property value is longer than its 4 octet size allows.
+    PropertyTooLarge,
+    /// A string with a short-size, e.g. a PLAIN username, is longer than 255 octets.
+    StringTooLong,
+}
+
+impl core::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            EncodeError::InvalidCommandName => write!(f, "Invalid command name"),
+            EncodeError::InvalidPropertyName => write!(f, "Invalid property name"),
+            EncodeError::PropertyTooLarge => write!(f, "Property value too large"),
+            EncodeError::StringTooLong => write!(f, "String longer than 255 octets"),
+        }
+    }
+}
+
+/// Error type of all parsers of this crate.
+///
+/// `input` is the remaining input at the position the error occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZmtpError<I> {
+    pub input: I,
+    pub kind: ZmtpErrorKind,
+}
+
+impl<I> ZmtpError<I> {
+    pub fn new(input: I, kind: ZmtpErrorKind) -> Self {
+        ZmtpError { input, kind }
+    }
+}
+
+impl ZmtpError<&[u8]> {
+    /// Position of the error relative to the start of `buffer`, which the parsed input was taken
+    /// from.
+    pub fn offset(&self, buffer: &[u8]) -> usize {
+        buffer.len().saturating_sub(self.input.len())
+    }
+}
+
+impl<I> ParseError<I> for ZmtpError<I> {
+    fn from_error_kind(input: I, kind: ErrorKind) -> Self {
+        let kind = match kind {
+            // the complete parsers report running out of data as Eof
+            ErrorKind::Eof => ZmtpErrorKind::TruncatedFrame,
+            kind => ZmtpErrorKind::Nom(kind),
+        };
+        ZmtpError { input, kind }
+    }
+
+    fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+pub type IResult<I, O> = nom::IResult<I, O, ZmtpError<I>>;
+
+/// Create a nom error of the given kind.
+pub(crate) fn fail<I, O>(input: I, kind: ZmtpErrorKind) -> IResult<I, O> {
+    Err(nom::Err::Error(ZmtpError::new(input, kind)))
+}
+
+/// Create an unrecoverable nom error, used for exceeded limits.
+pub(crate) fn fail_hard<I, O>(input: I, kind: ZmtpErrorKind) -> IResult<I, O> {
+    Err(nom::Err::Failure(ZmtpError::new(input, kind)))
+}
+
+/// Replace the kind of a recoverable error, keeping its position.
+///
+/// Unrecoverable errors are passed on unchanged.
+pub(crate) fn with_kind<I>(
+    err: nom::Err<ZmtpError<I>>,
+    kind: ZmtpErrorKind,
+) -> nom::Err<ZmtpError<I>> {
+    match err {
+        nom::Err::Error(e) => nom::Err::Error(ZmtpError::new(e.input, kind)),
+        other => other,
+    }
+}
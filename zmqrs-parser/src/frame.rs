@@ -1,11 +1,14 @@
-use nom::{
-    number::complete::{be_u64, be_u8},
-    IResult,
-};
+use bytes::BufMut;
+use core::convert::TryFrom;
+use nom::number::streaming::{be_u64, be_u8};
 
-use crate::{command, message, Command, Message};
+use crate::error::{fail, fail_hard, EncodeError, IResult, ZmtpErrorKind};
+use crate::{command_limited, message, Command, Limits, Message, SecurityMechanism};
 
-#[derive(Debug, Default)]
+/// Frame sizes up to this value are encoded as a single octet.
+const MAX_SHORT_SIZE: usize = 255;
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct FrameFlags {
     /// A value of 1 indicates that the frame is a command frame.
     /// A value of 0 indicates that the frame is a message frame.
@@ -33,30 +36,117 @@ impl FrameFlags {
             is_command,
         }
     }
+
+    pub fn to_byte(&self) -> u8 {
+        let mut head_byte = 0u8;
+        if self.more_frames_to_follow {
+            head_byte |= 1u8;
+        }
+        if self.is_long {
+            head_byte |= 1u8 << 1;
+        }
+        if self.is_command {
+            head_byte |= 1u8 << 2;
+        }
+        head_byte
+    }
 }
-#[derive(Debug, Default)]
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct FrameHeader {
     pub flags: FrameFlags,
     pub frame_length: usize,
 }
 
-#[derive(Debug, Clone)]
+impl FrameHeader {
+    /// Create a header for a frame body of `frame_length` octets.
+    ///
+    /// The short or long size encoding is chosen depending on the length. The MORE flag is never
+    /// set for command frames.
+    pub fn new(is_command: bool, more_frames_to_follow: bool, frame_length: usize) -> Self {
+        FrameHeader {
+            flags: FrameFlags {
+                is_command,
+                is_long: frame_length > MAX_SHORT_SIZE,
+                more_frames_to_follow: more_frames_to_follow && !is_command,
+            },
+            frame_length,
+        }
+    }
+
+    /// Number of octets `encode` will write.
+    pub fn encoded_len(&self) -> usize {
+        if self.flags.is_long {
+            1 + 8
+        } else {
+            1 + 1
+        }
+    }
+
+    /// Write flags and size of the frame.
+    ///
+    /// frame-header = flags ( short-size | long-size )
+    pub fn encode<B: BufMut>(&self, dst: &mut B) {
+        dst.put_u8(self.flags.to_byte());
+        if self.flags.is_long {
+            dst.put_u64(self.frame_length as u64);
+        } else {
+            dst.put_u8(self.frame_length as u8);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Frame<S, T> {
     Command(Command<S, T>),
     Message(Message<T>),
 }
 
+impl<S: AsRef<[u8]>, T: AsRef<[u8]>> Frame<S, T> {
+    /// The header describing this frame on the wire.
+    pub fn header(&self) -> FrameHeader {
+        match self {
+            Frame::Command(c) => FrameHeader::new(true, false, c.encoded_len()),
+            Frame::Message(m) => FrameHeader::new(false, m.more(), m.encoded_len()),
+        }
+    }
+
+    /// Number of octets `encode` will write, including the header.
+    pub fn encoded_len(&self) -> usize {
+        let hdr = self.header();
+        hdr.encoded_len() + hdr.frame_length
+    }
+
+    /// Serialize the frame; the counterpart of `frame`.
+    ///
+    /// Nothing is written if the frame can't be encoded.
+    pub fn encode<B: BufMut>(&self, dst: &mut B) -> Result<(), EncodeError> {
+        if let Frame::Command(c) = self {
+            c.check()?;
+        }
+        self.header().encode(dst);
+        match self {
+            Frame::Command(c) => c.write(dst),
+            Frame::Message(m) => m.encode(dst),
+        }
+        Ok(())
+    }
+}
+
 impl From<(&bytes::Bytes, Frame<&str, &[u8]>)> for Frame<bytes::Bytes, bytes::Bytes> {
     fn from(input: (&bytes::Bytes, Frame<&str, &[u8]>)) -> Self {
         let (buffer, frame) = input;
 
         match frame {
-            Frame::Command(c) => Frame::Command(((buffer, c)).into()),
-            Frame::Message(m) => Frame::Message(((buffer, m)).into()),
+            Frame::Command(c) => Frame::Command((buffer, c).into()),
+            Frame::Message(m) => Frame::Message((buffer, m).into()),
         }
     }
 }
 
+/// Parse flags and size of a frame.
+///
+/// Returns `nom::Err::Incomplete` if the header is not completely available yet.
 pub fn frame_header<'a>(
     input: &'a [u8],
     logger: &mut slog::Logger,
@@ -65,7 +155,11 @@ pub fn frame_header<'a>(
     let flags = FrameFlags::from_byte(head_byte);
 
     let (input, frame_length) = if flags.is_long {
-        be_u64(input).map(|(input, v)| (input, v as usize))?
+        let (remaining, v) = be_u64(input)?;
+        match usize::try_from(v) {
+            Ok(v) => (remaining, v),
+            Err(_) => return fail(input, ZmtpErrorKind::FrameTooLarge),
+        }
     } else {
         be_u8(input).map(|(input, v)| (input, v as usize))?
     };
@@ -88,13 +182,15 @@ pub fn frame_header<'a>(
 pub fn frame_body<'a>(
     input: &'a [u8],
     hdr: &FrameHeader,
+    mechanism: &SecurityMechanism,
+    limits: &Limits,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Frame<&'a str, &'a [u8]>> {
     if hdr.flags.is_command {
-        let (input, cmd) = command(input, &hdr, logger)?;
+        let (input, cmd) = command_limited(input, hdr, mechanism, limits, logger)?;
         Ok((input, Frame::Command(cmd)))
     } else {
-        let (input, msg) = message(input, &hdr, logger)?;
+        let (input, msg) = message(input, hdr, logger)?;
         Ok((input, Frame::Message(msg)))
     }
 }
@@ -103,7 +199,112 @@ pub fn frame<'a>(
     input: &'a [u8],
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Frame<&'a str, &'a [u8]>> {
-    let (input, hdr) = frame_header(input, logger)?;
+    frame_limited(
+        input,
+        &SecurityMechanism::NULL,
+        &Limits::unlimited(),
+        logger,
+    )
+}
+
+/// Parse a single frame of a connection using `mechanism`, enforcing the given limits.
+pub fn frame_limited<'a>(
+    input: &'a [u8],
+    mechanism: &SecurityMechanism,
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Frame<&'a str, &'a [u8]>> {
+    let (body, hdr) = frame_header(input, logger)?;
+    if let Err(kind) = limits.check_header(&hdr, 0) {
+        return fail_hard(input, kind);
+    }
+
+    frame_body(body, &hdr, mechanism, limits, logger)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use hex_literal::hex;
+    use slog::*;
+
+    use crate::tests::make_logger;
+    use crate::{ByteSlice, Ping, Pong};
+
+    #[test]
+    fn test_flags_round_trip() {
+        for head_byte in 0u8..8 {
+            assert_eq!(FrameFlags::from_byte(head_byte).to_byte(), head_byte);
+        }
+    }
+
+    #[test]
+    fn test_header_size_encoding() {
+        let mut logger = make_logger().new(o!("test" => "test_header_size_encoding"));
+
+        for &(len, expected_len) in &[(0usize, 2usize), (255, 2), (256, 9), (70_000, 9)] {
+            let hdr = FrameHeader::new(false, true, len);
+            let mut buf = Vec::new();
+            hdr.encode(&mut buf);
+            assert_eq!(buf.len(), expected_len);
+            assert_eq!(buf.len(), hdr.encoded_len());
 
-    frame_body(input, &hdr, logger)
+            let (rest, parsed) = frame_header(&buf, &mut logger).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, hdr);
+        }
+    }
+
+    #[test]
+    fn test_command_never_has_more() {
+        let hdr = FrameHeader::new(true, true, 5);
+        assert_eq!(hdr.flags.to_byte(), 0x04);
+    }
+
+    #[test]
+    fn test_long_message_round_trip() {
+        let mut logger = make_logger().new(o!("test" => "test_long_message_round_trip"));
+
+        let payload = [0xabu8; 300];
+        let msg: Frame<&str, &[u8]> = Frame::Message(Message::new(&payload[..]).with_more(true));
+        let mut buf = Vec::new();
+        msg.encode(&mut buf).unwrap();
+
+        assert_eq!(buf.len(), msg.encoded_len());
+        assert_eq!(&buf[..9], &hex!("03 00 00 00 00 00 00 01 2c"));
+
+        let (rest, parsed) = frame(&buf, &mut logger).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed, msg);
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        let mut logger = make_logger().new(o!("test" => "test_command_round_trip"));
+
+        let commands: Vec<Command<&str, &[u8]>> = vec![
+            Command::ERROR(ByteSlice(&b"bad socket type"[..])),
+            Command::SUBSCRIBE(ByteSlice(&b"weather"[..])),
+            Command::CANCEL(ByteSlice(&b""[..])),
+            Command::PING(Ping {
+                ttl: 300,
+                context: &b"ctx"[..],
+            }),
+            Command::PONG(Pong {
+                context: &b"ctx"[..],
+            }),
+        ];
+
+        for cmd in commands {
+            let f = Frame::Command(cmd);
+            let mut buf = Vec::new();
+            f.encode(&mut buf).unwrap();
+            assert_eq!(buf.len(), f.encoded_len());
+
+            let (rest, parsed) = frame(&buf, &mut logger).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, f);
+        }
+    }
 }
@@ -1,11 +1,26 @@
+use bytes::BufMut;
 use core::convert::TryFrom;
 use nom::{
     bytes::complete::{tag, take},
     number::complete::be_u8,
-    IResult,
 };
 use slog::{Error, Record, Serializer};
 
+use crate::error::{with_kind, IResult, ZmtpError, ZmtpErrorKind};
+
+/// Length of the complete greeting in octets.
+pub const GREETING_LEN: usize = 64;
+
+/// Length of the partial greeting, i.e. signature and major version, sent during the first phase
+/// of the version exchange.
+pub const PARTIAL_GREETING_LEN: usize = 11;
+
+/// Signature as sent by libzmq; the padding is not significant.
+const SIGNATURE: [u8; 10] = [0xff, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x7f];
+
+const MECHANISM_LEN: usize = 20;
+const FILLER_LEN: usize = 31;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Greeting {
     pub version: Version,
@@ -13,6 +28,58 @@ pub struct Greeting {
     pub as_server: bool,
 }
 
+impl Default for Greeting {
+    /// ZMTP 3.1 with NULL security, not acting as server.
+    fn default() -> Self {
+        Greeting {
+            version: Version { major: 3, minor: 1 },
+            mechanism: SecurityMechanism::NULL,
+            as_server: false,
+        }
+    }
+}
+
+impl Greeting {
+    pub fn with_version(mut self, major: u8, minor: u8) -> Self {
+        self.version = Version { major, minor };
+        self
+    }
+
+    pub fn with_mechanism(mut self, mechanism: SecurityMechanism) -> Self {
+        self.mechanism = mechanism;
+        self
+    }
+
+    pub fn with_as_server(mut self, as_server: bool) -> Self {
+        self.as_server = as_server;
+        self
+    }
+
+    /// Write the complete 64 octet greeting.
+    ///
+    /// greeting = signature version mechanism as-server filler
+    pub fn encode<B: BufMut>(&self, dst: &mut B) {
+        self.encode_partial(dst);
+        self.encode_remaining(dst);
+    }
+
+    /// Write the first 11 octets of the greeting, i.e. the signature and the major version.
+    pub fn encode_partial<B: BufMut>(&self, dst: &mut B) {
+        dst.put_slice(&SIGNATURE);
+        dst.put_u8(self.version.major);
+    }
+
+    /// Write the remaining 53 octets of the greeting following the partial greeting.
+    pub fn encode_remaining<B: BufMut>(&self, dst: &mut B) {
+        let name = self.mechanism.name();
+        dst.put_u8(self.version.minor);
+        dst.put_slice(name);
+        dst.put_slice(&[0u8; MECHANISM_LEN][name.len()..]);
+        dst.put_u8(self.as_server as u8);
+        dst.put_slice(&[0u8; FILLER_LEN]);
+    }
+}
+
 impl slog::Value for Greeting {
     fn serialize(
         &self,
@@ -33,6 +100,13 @@ pub struct Version {
     pub minor: u8,
 }
 
+impl Version {
+    /// True if this version is `major.minor` or newer.
+    pub fn at_least(&self, major: u8, minor: u8) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
 impl slog::Value for Version {
     fn serialize(
         &self,
@@ -53,6 +127,15 @@ pub enum SecurityMechanism {
 }
 
 impl SecurityMechanism {
+    /// The mechanism name as sent in the greeting, without zero padding.
+    pub fn name(&self) -> &'static [u8] {
+        match self {
+            SecurityMechanism::NULL => b"NULL",
+            SecurityMechanism::PLAIN => b"PLAIN",
+            SecurityMechanism::CURVE => b"CURVE",
+        }
+    }
+
     fn description(&self) -> &'static str {
         match self {
             SecurityMechanism::NULL => "SecurityMechanism::NULL",
@@ -74,17 +157,17 @@ impl slog::Value for SecurityMechanism {
 }
 
 impl<'a> TryFrom<&'a [u8]> for SecurityMechanism {
-    type Error = nom::Err<(&'a [u8], nom::error::ErrorKind)>;
+    type Error = nom::Err<ZmtpError<&'a [u8]>>;
 
     fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
         // please note: all branches need to have the same length; data is zero-padded
-        match &value[0..5] {
-            b"NULL\0" => Ok(SecurityMechanism::NULL),
-            b"PLAIN" => Ok(SecurityMechanism::PLAIN),
-            b"CURVE" => Ok(SecurityMechanism::CURVE),
-            _ => Err(nom::Err::Error(nom::error::make_error(
+        match value.get(0..5) {
+            Some(b"NULL\0") => Ok(SecurityMechanism::NULL),
+            Some(b"PLAIN") => Ok(SecurityMechanism::PLAIN),
+            Some(b"CURVE") => Ok(SecurityMechanism::CURVE),
+            _ => Err(nom::Err::Error(ZmtpError::new(
                 value,
-                nom::error::ErrorKind::Eof,
+                ZmtpErrorKind::UnsupportedMechanism,
             ))),
         }
     }
@@ -97,8 +180,36 @@ pub fn greeting<'a>(input: &'a [u8], logger: &mut slog::Logger) -> IResult<&'a [
     // ;   The greeting announces the protocol details
     // greeting = signature version mechanism as-server filler
 
+    let (input, major) = partial_greeting(input, logger)?;
+    greeting_remaining(input, major, logger)
+}
+
+/// The first 11 octets of the greeting, i.e. signature and major version.
+///
+/// Returns the major version, which is all a peer needs to know in order to decide whether to
+/// send the rest of its greeting.
+pub fn partial_greeting<'a>(input: &'a [u8], logger: &mut slog::Logger) -> IResult<&'a [u8], u8> {
     let (input, _) = signature(input, logger)?;
-    let (input, version) = version(input, logger)?;
+    let (input, major) = be_u8(input)?;
+
+    trace!(logger, "partial greeting:"; o!("major" => major));
+
+    Ok((input, major))
+}
+
+/// The remaining 53 octets of the greeting following the partial greeting.
+pub fn greeting_remaining<'a>(
+    input: &'a [u8],
+    major: u8,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Greeting> {
+    // version = version-major version-minor
+    // version-major = %x03
+    // version-minor = %x01
+    let (input, minor) = be_u8(input)?;
+    let version = Version { major, minor };
+    trace!(logger, "version:"; o!("major" => major), o!("minor" => minor));
+
     let (input, sec_mechanism) = mechanism(input, logger)?;
     let (input, as_server) = as_server(input, logger)?;
     let (input, _) = filler(input, logger)?;
@@ -121,27 +232,15 @@ fn signature<'a>(input: &'a [u8], logger: &mut slog::Logger) -> IResult<&'a [u8]
     // signature = %xFF padding %x7F
     // padding = 8OCTET        ; Not significant
 
-    let (input, _) = tag([0xff])(input)?;
-    let (input, _) = take(8u8)(input)?;
-    let (input, _) = tag([0x7f])(input)?;
+    let bad_signature = |e| with_kind(e, ZmtpErrorKind::BadSignature);
+    let (input, _) = tag([0xff])(input).map_err(bad_signature)?;
+    let (input, _) = take(8u8)(input).map_err(bad_signature)?;
+    let (input, _) = tag([0x7f])(input).map_err(bad_signature)?;
     trace!(logger, "signature valid");
 
     Ok((input, ()))
 }
 
-fn version<'a>(input: &'a [u8], logger: &mut slog::Logger) -> IResult<&'a [u8], Version> {
-    // version = version-major version-minor
-    // version-major = %x03
-    // version-minor = %x01
-
-    let (input, major) = be_u8(input)?;
-    let (input, minor) = be_u8(input)?;
-
-    trace!(logger, "version:"; o!("major" => major), o!("minor" => minor));
-
-    Ok((input, Version { major, minor }))
-}
-
 fn mechanism<'a>(
     input: &'a [u8],
     logger: &mut slog::Logger,
@@ -151,8 +250,11 @@ fn mechanism<'a>(
     // mechanism-char = "A"-"Z" | DIGIT
     //      | "-" | "_" | "." | "+" | %x0
 
-    let (input, mechanism_str) = take(20u8)(input)?;
-    let mec = SecurityMechanism::try_from(mechanism_str)?;
+    let (remaining, mechanism_str) = take(MECHANISM_LEN)(input)?;
+    // report the error at the start of the mechanism
+    let mec = SecurityMechanism::try_from(mechanism_str)
+        .map_err(|_| nom::Err::Error(ZmtpError::new(input, ZmtpErrorKind::UnsupportedMechanism)))?;
+    let input = remaining;
 
     trace!(logger, "mechanism:"; o!("val" => &mec));
 
@@ -173,7 +275,7 @@ fn as_server<'a>(input: &'a [u8], logger: &mut slog::Logger) -> IResult<&'a [u8]
 fn filler<'a>(input: &'a [u8], logger: &mut slog::Logger) -> IResult<&'a [u8], ()> {
     // ;   The filler extends the greeting to 64 octets
     // filler = 31%x00             ; 31 zero octets
-    let (input, _) = tag([0u8; 31])(input)?;
+    let (input, _) = tag([0u8; FILLER_LEN])(input)?;
     trace!(logger, "filler:");
 
     Ok((input, ()))
@@ -211,4 +313,44 @@ pub mod tests {
             ))
         )
     }
+
+    #[test]
+    fn test_greeting_encode() {
+        let mut logger = make_logger().new(o!("test" => "test_greeting_encode"));
+
+        let intro = hex!(
+            "   ff 00 00 00 00 00 00 00  01 7f 03 00 4e 55 4c 4c
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
+        );
+        let g = Greeting::default().with_version(3, 0);
+
+        let mut buf = alloc::vec::Vec::new();
+        g.encode(&mut buf);
+        assert_eq!(buf.len(), GREETING_LEN);
+        assert_eq!(&buf[..], &intro[..]);
+
+        let mut partial = alloc::vec::Vec::new();
+        g.encode_partial(&mut partial);
+        assert_eq!(partial.len(), PARTIAL_GREETING_LEN);
+        assert_eq!(&partial[..], &intro[..PARTIAL_GREETING_LEN]);
+
+        for mechanism in [
+            SecurityMechanism::NULL,
+            SecurityMechanism::PLAIN,
+            SecurityMechanism::CURVE,
+        ]
+        .iter()
+        {
+            let g = Greeting::default()
+                .with_mechanism(mechanism.clone())
+                .with_as_server(true);
+            let mut buf = alloc::vec::Vec::new();
+            g.encode(&mut buf);
+            let (rest, parsed) = greeting(&buf, &mut logger).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, g);
+        }
+    }
 }
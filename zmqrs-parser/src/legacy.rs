@@ -0,0 +1,503 @@
+//! Greeting and frames of the ZMTP revisions before 3.0, as spoken by libzmq 2.x to 4.0.
+//!
+//! ZMTP/1.0 (https://rfc.zeromq.org/spec/13/) has no greeting; a peer starts right away with its
+//! identity frame. ZMTP/2.0 (https://rfc.zeromq.org/spec/15/) sends a short signature, the
+//! revision and the socket type, followed by the identity frame. Neither revision knows commands,
+//! so all frames are message frames.
+
+use bytes::BufMut;
+use core::convert::TryFrom;
+use nom::bytes::streaming::take;
+use nom::number::streaming::{be_u64, be_u8};
+
+use crate::error::{fail, fail_hard, IResult, ZmtpErrorKind};
+use crate::frame::frame_header;
+use crate::{message, ByteSlice, Frame, FrameFlags, FrameHeader, Limits, Message, SocketType};
+
+/// Revision octet of a ZMTP/2.0 greeting.
+const REVISION_20: u8 = 0x01;
+
+/// A ZMTP/1.0 length octet announcing an 8 octet length.
+const LONG_LENGTH: u8 = 0xff;
+
+/// Frame lengths below this value are encoded as a single octet by ZMTP/1.0.
+const MAX_SHORT_LENGTH: usize = 0xff;
+
+/// The protocol generation a peer speaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZmtpRevision {
+    /// ZMTP/1.0; the peer starts with its identity frame.
+    ZMTP10,
+    /// ZMTP/2.0; signature, revision and socket type precede the identity frame.
+    ZMTP20,
+    /// ZMTP/3.0 or newer; read the rest with `greeting`.
+    ZMTP30,
+}
+
+/// Find out which revision of ZMTP the peer speaks from the first octets it sent.
+///
+/// Needs up to 11 octets and consumes none of them. Returns `nom::Err::Incomplete` if more are
+/// needed.
+pub fn detect_revision<'a>(
+    input: &'a [u8],
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], ZmtpRevision> {
+    // A ZMTP/1.0 peer starts with its identity frame, whose first octet is %xFF for long frames
+    // only. Its tenth octet then holds the flags of the identity frame, which never has the MORE
+    // bit set, while the signature has %x7F there.
+    let (rest, first) = be_u8(input)?;
+    let revision = if first != 0xff {
+        ZmtpRevision::ZMTP10
+    } else {
+        let (rest, _) = take(8usize)(rest)?;
+        let (rest, flags) = be_u8(rest)?;
+        if flags & 0x01 == 0 {
+            ZmtpRevision::ZMTP10
+        } else {
+            match be_u8(rest)? {
+                (_, REVISION_20) => ZmtpRevision::ZMTP20,
+                (_, major) if major >= 3 => ZmtpRevision::ZMTP30,
+                _ => return fail(rest, ZmtpErrorKind::UnsupportedVersion),
+            }
+        }
+    };
+
+    trace!(logger, "revision:"; "revision" => ?revision);
+
+    Ok((input, revision))
+}
+
+/// What a ZMTP/1.0 or 2.0 peer sends before its first message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyGreeting<T> {
+    /// Socket type of a ZMTP/2.0 peer; ZMTP/1.0 peers don't announce it.
+    pub socket_type: Option<SocketType>,
+    /// Identity of the peer, empty for anonymous peers.
+    pub identity: T,
+}
+
+impl<T> LegacyGreeting<T> {
+    pub fn revision(&self) -> ZmtpRevision {
+        if self.socket_type.is_some() {
+            ZmtpRevision::ZMTP20
+        } else {
+            ZmtpRevision::ZMTP10
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> LegacyGreeting<T> {
+    /// Write the greeting including the identity frame; the counterpart of `legacy_greeting`.
+    pub fn encode<B: BufMut>(&self, dst: &mut B) {
+        let identity = Message::new(self.identity.as_ref());
+        if let Some(socket_type) = self.socket_type {
+            // libzmq puts the size of a ZMTP/1.0 identity frame into the padding, so that
+            // ZMTP/1.0 peers can skip the signature
+            dst.put_u8(0xff);
+            dst.put_u64(identity.encoded_len() as u64 + 1);
+            dst.put_u8(0x7f);
+            dst.put_u8(REVISION_20);
+            dst.put_u8(socket_type.legacy_code());
+        }
+        encode_legacy_frame(&identity, self.revision(), dst);
+    }
+}
+
+impl From<(&bytes::Bytes, LegacyGreeting<&[u8]>)> for LegacyGreeting<bytes::Bytes> {
+    fn from(input: (&bytes::Bytes, LegacyGreeting<&[u8]>)) -> Self {
+        let (buffer, greeting) = input;
+        LegacyGreeting {
+            socket_type: greeting.socket_type,
+            identity: buffer.slice_ref(greeting.identity),
+        }
+    }
+}
+
+/// Greeting of a ZMTP/1.0 or 2.0 peer, including its identity frame.
+///
+/// Fails with `ZmtpErrorKind::UnsupportedVersion` for ZMTP/3.0 peers. The identity frame is
+/// checked against `limits`.
+pub fn legacy_greeting<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], LegacyGreeting<&'a [u8]>> {
+    let (_, revision) = detect_revision(input, logger)?;
+    let (input, socket_type) = match revision {
+        ZmtpRevision::ZMTP10 => (input, None),
+        ZmtpRevision::ZMTP20 => {
+            // signature = %xFF 8OCTET %x7F, followed by the revision, all checked above
+            let (rest, _) = take(11usize)(input)?;
+            let (after_socket_type, code) = be_u8(rest)?;
+            match SocketType::from_legacy_code(code) {
+                Some(socket_type) => (after_socket_type, Some(socket_type)),
+                None => return fail(rest, ZmtpErrorKind::UnknownSocketType),
+            }
+        }
+        ZmtpRevision::ZMTP30 => return fail(&input[10..], ZmtpErrorKind::UnsupportedVersion),
+    };
+
+    let (rest, identity) = legacy_frame(input, revision, limits, logger)?;
+    let identity = match identity {
+        Frame::Message(msg) if !msg.more() => msg.into_data(),
+        // the identity is a single frame
+        _ => return fail(input, ZmtpErrorKind::MalformedFrame),
+    };
+
+    trace!(logger, "legacy greeting:";
+        "revision" => ?revision,
+        "socket_type" => ?socket_type,
+        "identity" => ByteSlice(identity));
+
+    Ok((
+        rest,
+        LegacyGreeting {
+            socket_type,
+            identity,
+        },
+    ))
+}
+
+/// Parse flags and size of a frame in the format of `revision`.
+///
+/// ZMTP/1.0 frames start with the length of flags and body, followed by the flags. ZMTP/2.0
+/// frames look like ZMTP/3.0 message frames. Returns `nom::Err::Incomplete` if the header is not
+/// completely available yet.
+pub fn legacy_frame_header<'a>(
+    input: &'a [u8],
+    revision: ZmtpRevision,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], FrameHeader> {
+    match revision {
+        ZmtpRevision::ZMTP10 => {
+            // frame = length flags body
+            let (rest, short_length) = be_u8(input)?;
+            let is_long = short_length == LONG_LENGTH;
+            let (rest, length) = if is_long {
+                let (rest, v) = be_u64(rest)?;
+                match usize::try_from(v) {
+                    Ok(v) => (rest, v),
+                    Err(_) => return fail(input, ZmtpErrorKind::FrameTooLarge),
+                }
+            } else {
+                (rest, short_length as usize)
+            };
+            // the length includes the flags octet
+            if length == 0 {
+                return fail(input, ZmtpErrorKind::MalformedFrame);
+            }
+            let (rest, flags) = be_u8(rest)?;
+
+            trace!(logger, "legacy frame_header:";
+                o!("more_frames_to_follow" => flags & 0x01 != 0),
+                o!("frame_length" => length - 1));
+
+            Ok((
+                rest,
+                FrameHeader {
+                    flags: FrameFlags {
+                        is_command: false,
+                        is_long,
+                        more_frames_to_follow: flags & 0x01 != 0,
+                    },
+                    frame_length: length - 1,
+                },
+            ))
+        }
+        ZmtpRevision::ZMTP20 => {
+            let (rest, hdr) = frame_header(input, logger)?;
+            if hdr.flags.is_command {
+                return fail(input, ZmtpErrorKind::MalformedFrame);
+            }
+            Ok((rest, hdr))
+        }
+        ZmtpRevision::ZMTP30 => frame_header(input, logger),
+    }
+}
+
+/// Parse a single message frame in the format of `revision`, enforcing the given limits.
+///
+/// Like `legacy_frame_header`, returns `nom::Err::Incomplete` if the frame is not completely
+/// available yet.
+pub fn legacy_frame<'a>(
+    input: &'a [u8],
+    revision: ZmtpRevision,
+    limits: &Limits,
+    logger: &mut slog::Logger,
+) -> IResult<&'a [u8], Frame<&'a str, &'a [u8]>> {
+    let (rest, hdr) = legacy_frame_header(input, revision, logger)?;
+    if let Err(kind) = limits.check_header(&hdr, 0) {
+        return fail_hard(input, kind);
+    }
+    let (rest, body) = take(hdr.frame_length)(rest)?;
+    let (_, msg) = message(body, &hdr, logger)?;
+    Ok((rest, Frame::Message(msg)))
+}
+
+/// Number of octets `encode_legacy_frame` will write.
+pub fn legacy_frame_len<T: AsRef<[u8]>>(msg: &Message<T>, revision: ZmtpRevision) -> usize {
+    let len = msg.encoded_len();
+    match revision {
+        ZmtpRevision::ZMTP10 if len + 1 < MAX_SHORT_LENGTH => 1 + 1 + len,
+        ZmtpRevision::ZMTP10 => 1 + 8 + 1 + len,
+        _ => Frame::<&str, _>::Message(Message::new(msg.data().as_ref())).encoded_len(),
+    }
+}
+
+/// Write a message frame in the format of `revision`.
+pub fn encode_legacy_frame<T: AsRef<[u8]>, B: BufMut>(
+    msg: &Message<T>,
+    revision: ZmtpRevision,
+    dst: &mut B,
+) {
+    match revision {
+        ZmtpRevision::ZMTP10 => {
+            let length = msg.encoded_len() + 1;
+            if length < MAX_SHORT_LENGTH {
+                dst.put_u8(length as u8);
+            } else {
+                dst.put_u8(LONG_LENGTH);
+                dst.put_u64(length as u64);
+            }
+            dst.put_u8(msg.more() as u8);
+            msg.encode(dst);
+        }
+        _ => {
+            FrameHeader::new(false, msg.more(), msg.encoded_len()).encode(dst);
+            msg.encode(dst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use hex_literal::hex;
+    use slog::*;
+
+    use crate::tests::make_logger;
+
+    #[test]
+    fn detect_revisions() {
+        let mut logger = make_logger().new(o!("test" => "detect_revisions"));
+
+        for (input, revision) in [
+            // anonymous ZMTP/1.0 identity
+            (&hex!("01 00")[..], ZmtpRevision::ZMTP10),
+            // ZMTP/1.0 identity in a long frame
+            (
+                &hex!("ff 00 00 00 00 00 00 00 02 00 41")[..],
+                ZmtpRevision::ZMTP10,
+            ),
+            (
+                &hex!("ff 00 00 00 00 00 00 00 01 7f 01 03")[..],
+                ZmtpRevision::ZMTP20,
+            ),
+            (
+                &hex!("ff 00 00 00 00 00 00 00 01 7f 03")[..],
+                ZmtpRevision::ZMTP30,
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(detect_revision(input, &mut logger), Ok((*input, *revision)));
+        }
+
+        let signature = hex!("ff 00 00 00 00 00 00 00 01 7f");
+        assert!(matches!(
+            detect_revision(&signature, &mut logger),
+            Err(nom::Err::Incomplete(_))
+        ));
+        let mut unknown = signature.to_vec();
+        unknown.push(0x02);
+        match detect_revision(&unknown, &mut logger) {
+            Err(nom::Err::Error(e)) => {
+                assert_eq!(e.kind, ZmtpErrorKind::UnsupportedVersion);
+                assert_eq!(e.offset(&unknown), 10);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn zmtp20_greeting() {
+        let mut logger = make_logger().new(o!("test" => "zmtp20_greeting"));
+
+        // libzmq 3.2 DEALER with identity "A", followed by the two part message "", "Hi"
+        let stream = hex!("ff 00 00 00 00 00 00 00 02 7f 01 05 00 01 41 01 00 00 02 48 69");
+        let (rest, greeting) = legacy_greeting(&stream, &Limits::unlimited(), &mut logger).unwrap();
+        assert_eq!(
+            greeting,
+            LegacyGreeting {
+                socket_type: Some(SocketType::DEALER),
+                identity: &b"A"[..],
+            }
+        );
+        assert_eq!(greeting.revision(), ZmtpRevision::ZMTP20);
+
+        let mut encoded = Vec::new();
+        greeting.encode(&mut encoded);
+        assert_eq!(&encoded[..], &stream[..stream.len() - rest.len()]);
+
+        let (rest, delimiter) = legacy_frame(
+            rest,
+            ZmtpRevision::ZMTP20,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(
+            delimiter,
+            Frame::Message(Message::new(&b""[..]).with_more(true))
+        );
+        let (rest, hi) = legacy_frame(
+            rest,
+            ZmtpRevision::ZMTP20,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(hi, Frame::Message(Message::new(&b"Hi"[..])));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn zmtp10_greeting_and_frames() {
+        let mut logger = make_logger().new(o!("test" => "zmtp10_greeting_and_frames"));
+
+        // anonymous identity, then "", "Hi"
+        let stream = hex!("01 00 01 01 03 00 48 69");
+        let (rest, greeting) = legacy_greeting(&stream, &Limits::unlimited(), &mut logger).unwrap();
+        assert_eq!(greeting.revision(), ZmtpRevision::ZMTP10);
+        assert!(greeting.identity.is_empty());
+
+        let mut encoded = Vec::new();
+        greeting.encode(&mut encoded);
+        for frame in [
+            Message::new(&b""[..]).with_more(true),
+            Message::new(&b"Hi"[..]),
+        ]
+        .iter()
+        {
+            encode_legacy_frame(frame, ZmtpRevision::ZMTP10, &mut encoded);
+        }
+        assert_eq!(&encoded[..], &stream[..]);
+
+        let (rest, delimiter) = legacy_frame(
+            rest,
+            ZmtpRevision::ZMTP10,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(
+            delimiter,
+            Frame::Message(Message::new(&b""[..]).with_more(true))
+        );
+        let (rest, _) = legacy_frame(
+            rest,
+            ZmtpRevision::ZMTP10,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn legacy_frame_sizes_round_trip() {
+        let mut logger = make_logger().new(o!("test" => "legacy_frame_sizes_round_trip"));
+
+        let data = [0x42u8; 300];
+        for revision in [ZmtpRevision::ZMTP10, ZmtpRevision::ZMTP20].iter() {
+            for &len in &[0usize, 253, 254, 255, 256, 300] {
+                let msg = Message::new(&data[..len]).with_more(len % 2 == 0);
+                let mut buf = Vec::new();
+                encode_legacy_frame(&msg, *revision, &mut buf);
+                assert_eq!(buf.len(), legacy_frame_len(&msg, *revision));
+
+                let (rest, parsed) =
+                    legacy_frame(&buf, *revision, &Limits::unlimited(), &mut logger).unwrap();
+                assert!(rest.is_empty());
+                assert_eq!(parsed, Frame::Message(msg));
+
+                assert!(matches!(
+                    legacy_frame(
+                        &buf[..buf.len() - 1],
+                        *revision,
+                        &Limits::unlimited(),
+                        &mut logger
+                    ),
+                    Err(nom::Err::Incomplete(_))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_legacy_input() {
+        let mut logger = make_logger().new(o!("test" => "malformed_legacy_input"));
+        let limits = Limits::unlimited();
+
+        fn kind<O: core::fmt::Debug>(res: IResult<&[u8], O>) -> ZmtpErrorKind {
+            match res {
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => e.kind,
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        // ZMTP/1.0 length without flags octet
+        assert_eq!(
+            kind(legacy_frame(
+                &hex!("00 00"),
+                ZmtpRevision::ZMTP10,
+                &limits,
+                &mut logger
+            )),
+            ZmtpErrorKind::MalformedFrame
+        );
+        // ZMTP/2.0 has no commands
+        assert_eq!(
+            kind(legacy_frame(
+                &hex!("04 04 50 49 4e 47"),
+                ZmtpRevision::ZMTP20,
+                &limits,
+                &mut logger
+            )),
+            ZmtpErrorKind::MalformedFrame
+        );
+        // socket type 42
+        assert_eq!(
+            kind(legacy_greeting(
+                &hex!("ff 00 00 00 00 00 00 00 01 7f 01 2a 00 00"),
+                &limits,
+                &mut logger
+            )),
+            ZmtpErrorKind::UnknownSocketType
+        );
+        // identity with MORE flag
+        assert_eq!(
+            kind(legacy_greeting(&hex!("01 01"), &limits, &mut logger)),
+            ZmtpErrorKind::MalformedFrame
+        );
+        // ZMTP/3.0 peers need `greeting`
+        assert_eq!(
+            kind(legacy_greeting(
+                &hex!("ff 00 00 00 00 00 00 00 01 7f 03 00"),
+                &limits,
+                &mut logger
+            )),
+            ZmtpErrorKind::UnsupportedVersion
+        );
+        // limits apply to the identity
+        assert_eq!(
+            kind(legacy_greeting(
+                &hex!("03 00 41 41"),
+                &Limits::unlimited().with_max_frame_size(1),
+                &mut logger
+            )),
+            ZmtpErrorKind::FrameTooLarge
+        );
+    }
+}
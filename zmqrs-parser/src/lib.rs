@@ -1,26 +1,45 @@
-#![feature(ptr_wrapping_offset_from)]
 #![cfg_attr(not(feature = "std"), no_std)]
 #![forbid(unsafe_code)]
 
 // http://zmtp.org/page:read-the-docs
 
 mod command;
+mod curve;
+mod error;
 mod frame;
 mod greeting;
+mod legacy;
+mod limits;
 mod message;
+mod socket_type;
+mod subscription;
 
-pub use command::{command, Command, Ping, Pong};
-pub use frame::{frame, Frame, FrameHeader};
-pub use greeting::{greeting, Greeting};
-pub use message::{message, Message};
-use nom::error::ErrorKind;
+pub use command::{command, command_limited, properties, Command, Hello, MetaData, Ping, Pong};
+pub use curve::{
+    CurveCommand, CurveHello, CurveInitiate, CurveMessage, CurveReady, CurveWelcome,
+    CURVE_COOKIE_LEN, CURVE_KEY_LEN, CURVE_LONG_NONCE_LEN, CURVE_MAC_LEN,
+};
+pub use error::{EncodeError, IResult, ZmtpError, ZmtpErrorKind};
+pub use frame::{frame, frame_body, frame_limited, Frame, FrameFlags, FrameHeader};
+pub use greeting::{
+    greeting, greeting_remaining, partial_greeting, Greeting, SecurityMechanism, Version,
+    GREETING_LEN, PARTIAL_GREETING_LEN,
+};
+pub use legacy::{
+    detect_revision, encode_legacy_frame, legacy_frame, legacy_frame_header, legacy_frame_len,
+    legacy_greeting, LegacyGreeting, ZmtpRevision,
+};
+pub use limits::Limits;
+pub use message::{message, Assembled, AssemblyError, Message, Multipart, MultipartAssembler};
+pub use socket_type::{SocketType, UnknownSocketType};
+pub use subscription::{uses_subscription_commands, Subscription};
 
 extern crate alloc;
 
 #[macro_use]
 pub extern crate slog;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ByteSlice<T>(pub T);
 
 impl From<(&bytes::Bytes, ByteSlice<&[u8]>)> for ByteSlice<bytes::Bytes> {
@@ -32,9 +51,6 @@ impl From<(&bytes::Bytes, ByteSlice<&[u8]>)> for ByteSlice<bytes::Bytes> {
 
 #[cfg(feature = "std")]
 mod if_std {
-    // TODO:
-    // Eigenes Parser-Struct entfernen, nur Decoder implementieren;
-    // Überlegen welche Typen ich nach außen geben muss.
     // TODO: Überlege, ob es sinnvoll wäre für diese properties-maps
     // die bytes in einer hash-map zu allozieren und diese dann rauszuschicken;
     // für die Daten selbst kann man diese von BytesMut abknabbern, aber für
@@ -42,179 +58,562 @@ mod if_std {
 
     use crate::prelude::*;
     use bytes::{Buf, Bytes, BytesMut};
-    use futures::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt};
-    use futures_codec::{Decoder, FramedRead};
-    use nom::AsBytes;
-    use std::error::Error;
+    use futures_codec::{Decoder, Encoder};
 
-    pub struct Parser {
-        logger: slog::Logger,
-        _settings: Greeting,
-        buffer: BytesMut,
-    }
+    /// Most octets reserved at once for a frame that is still arriving; the buffer grows further
+    /// as the data actually arrives, not as announced by the peer.
+    pub(crate) const MAX_RESERVE: usize = 64 * 1024;
 
     #[derive(Debug)]
     pub enum ParserError {
-        Unspecified,
+        /// The greeting does not start with a valid signature.
+        BadSignature {
+            offset: usize,
+        },
+        /// The greeting announces an unsupported security mechanism.
+        UnsupportedMechanism {
+            offset: usize,
+        },
+        /// The greeting announces an unsupported ZMTP revision.
+        UnsupportedVersion {
+            offset: usize,
+        },
+        /// The ZMTP/2.0 greeting announces an unknown socket type.
+        UnknownSocketType {
+            offset: usize,
+        },
+        /// A command frame carries an invalid command name.
+        InvalidCommandName {
+            offset: usize,
+        },
+        /// The data of a command does not match its definition.
+        MalformedCommand {
+            offset: usize,
+        },
+        /// The frame header is not valid for the ZMTP revision of the peer.
+        MalformedFrame {
+            offset: usize,
+        },
+        /// A metadata property could not be parsed.
+        MalformedProperty {
+            offset: usize,
+        },
+        /// The frame ended before its content was complete.
+        TruncatedFrame {
+            offset: usize,
+        },
+        /// The announced frame size cannot be handled or exceeds `Limits::max_frame_size`.
+        FrameTooLarge {
+            offset: usize,
+        },
+        /// The multipart message exceeds `Limits::max_message_size`.
+        MessageTooLarge {
+            offset: usize,
+        },
+        /// A command carries more properties than `Limits::max_properties`.
+        TooManyProperties {
+            offset: usize,
+        },
+        /// A property value is longer than `Limits::max_property_value_len`.
+        PropertyTooLarge {
+            offset: usize,
+        },
+        /// Any other malformed input.
+        Malformed {
+            offset: usize,
+            kind: nom::error::ErrorKind,
+        },
+        /// ZMTP/1.0 and 2.0 peers don't know command frames.
+        CommandNotSupported,
+        /// A frame to be sent can't be encoded.
+        Encode(EncodeError),
         IoError(std::io::Error),
     }
 
-    impl<'a> From<nom::Err<(&'a [u8], nom::error::ErrorKind)>> for ParserError {
-        fn from(_: nom::Err<(&'a [u8], nom::error::ErrorKind)>) -> Self {
-            ParserError::Unspecified
+    impl ParserError {
+        /// Convert a parser error; `buffer` is the input the failing parser was started on and
+        /// the offset is reported relative to it.
+        pub fn from_nom(buffer: &[u8], err: nom::Err<ZmtpError<&[u8]>>) -> Self {
+            Self::from_nom_at(buffer, err, 0)
+        }
+
+        /// Like `from_nom`, but `buffer` starts `base` octets into the greeting or frame.
+        fn from_nom_at(buffer: &[u8], err: nom::Err<ZmtpError<&[u8]>>, base: usize) -> Self {
+            let (offset, kind) = match err {
+                nom::Err::Incomplete(_) => (buffer.len(), ZmtpErrorKind::TruncatedFrame),
+                nom::Err::Error(e) | nom::Err::Failure(e) => (e.offset(buffer), e.kind),
+            };
+            Self::from_kind(kind, base + offset)
+        }
+
+        fn from_kind(kind: ZmtpErrorKind, offset: usize) -> Self {
+            match kind {
+                ZmtpErrorKind::BadSignature => ParserError::BadSignature { offset },
+                ZmtpErrorKind::UnsupportedMechanism => ParserError::UnsupportedMechanism { offset },
+                ZmtpErrorKind::UnsupportedVersion => ParserError::UnsupportedVersion { offset },
+                ZmtpErrorKind::UnknownSocketType => ParserError::UnknownSocketType { offset },
+                ZmtpErrorKind::InvalidCommandName => ParserError::InvalidCommandName { offset },
+                ZmtpErrorKind::MalformedCommand => ParserError::MalformedCommand { offset },
+                ZmtpErrorKind::MalformedFrame => ParserError::MalformedFrame { offset },
+                ZmtpErrorKind::MalformedProperty => ParserError::MalformedProperty { offset },
+                ZmtpErrorKind::TruncatedFrame => ParserError::TruncatedFrame { offset },
+                ZmtpErrorKind::FrameTooLarge => ParserError::FrameTooLarge { offset },
+                ZmtpErrorKind::MessageTooLarge => ParserError::MessageTooLarge { offset },
+                ZmtpErrorKind::TooManyProperties => ParserError::TooManyProperties { offset },
+                ZmtpErrorKind::PropertyTooLarge => ParserError::PropertyTooLarge { offset },
+                ZmtpErrorKind::Nom(kind) => ParserError::Malformed { offset, kind },
+            }
+        }
+
+        /// Position of the error relative to the start of the greeting or frame.
+        pub fn offset(&self) -> Option<usize> {
+            match self {
+                ParserError::BadSignature { offset }
+                | ParserError::UnsupportedMechanism { offset }
+                | ParserError::UnsupportedVersion { offset }
+                | ParserError::UnknownSocketType { offset }
+                | ParserError::InvalidCommandName { offset }
+                | ParserError::MalformedCommand { offset }
+                | ParserError::MalformedFrame { offset }
+                | ParserError::MalformedProperty { offset }
+                | ParserError::TruncatedFrame { offset }
+                | ParserError::FrameTooLarge { offset }
+                | ParserError::MessageTooLarge { offset }
+                | ParserError::TooManyProperties { offset }
+                | ParserError::PropertyTooLarge { offset }
+                | ParserError::Malformed { offset, .. } => Some(*offset),
+                ParserError::CommandNotSupported
+                | ParserError::Encode(_)
+                | ParserError::IoError(_) => None,
+            }
+        }
+    }
+
+    impl From<EncodeError> for ParserError {
+        fn from(e: EncodeError) -> Self {
+            ParserError::Encode(e)
         }
     }
 
-    impl<'a> From<std::io::Error> for ParserError {
+    impl From<std::io::Error> for ParserError {
         fn from(e: std::io::Error) -> Self {
-            ParserError::Unspecified
+            ParserError::IoError(e)
         }
     }
 
     impl core::fmt::Display for ParserError {
         fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
             match self {
-                ParserError::Unspecified => write!(f, "Unspecified error"),
+                ParserError::BadSignature { offset } => {
+                    write!(f, "Bad signature at offset {}", offset)
+                }
+                ParserError::UnsupportedMechanism { offset } => {
+                    write!(f, "Unsupported security mechanism at offset {}", offset)
+                }
+                ParserError::UnsupportedVersion { offset } => {
+                    write!(f, "Unsupported version at offset {}", offset)
+                }
+                ParserError::UnknownSocketType { offset } => {
+                    write!(f, "Unknown socket type at offset {}", offset)
+                }
+                ParserError::InvalidCommandName { offset } => {
+                    write!(f, "Invalid command name at offset {}", offset)
+                }
+                ParserError::MalformedCommand { offset } => {
+                    write!(f, "Malformed command at offset {}", offset)
+                }
+                ParserError::MalformedFrame { offset } => {
+                    write!(f, "Malformed frame at offset {}", offset)
+                }
+                ParserError::MalformedProperty { offset } => {
+                    write!(f, "Malformed property at offset {}", offset)
+                }
+                ParserError::TruncatedFrame { offset } => {
+                    write!(f, "Truncated frame at offset {}", offset)
+                }
+                ParserError::FrameTooLarge { offset } => {
+                    write!(f, "Frame too large at offset {}", offset)
+                }
+                ParserError::MessageTooLarge { offset } => {
+                    write!(f, "Message too large at offset {}", offset)
+                }
+                ParserError::TooManyProperties { offset } => {
+                    write!(f, "Too many properties at offset {}", offset)
+                }
+                ParserError::PropertyTooLarge { offset } => {
+                    write!(f, "Property too large at offset {}", offset)
+                }
+                ParserError::Malformed { offset, kind } => {
+                    write!(
+                        f,
+                        "Malformed input at offset {}: {}",
+                        offset,
+                        kind.description()
+                    )
+                }
+                ParserError::CommandNotSupported => {
+                    write!(f, "Commands are not supported by ZMTP/1.0 and 2.0 peers")
+                }
+                ParserError::Encode(e) => write!(f, "Cannot encode frame: {}", e),
                 ParserError::IoError(e) => write!(f, "IoError: {}", e),
             }
         }
     }
-    impl std::error::Error for ParserError {}
-/*
-    impl Parser {
-        pub fn new<L: Into<slog::Logger>>(
-            initial_data: &[u8],
-            logger: L,
-        ) -> nom::IResult<&[u8], Self> {
-            let mut logger: slog::Logger = logger.into();
-            let (input, settings) = greeting(initial_data, &mut logger)?;
-
-            Ok((
-                input,
-                Parser {
-                    logger,
-                    _settings: settings,
-                    buffer: BytesMut::new(),
-                },
-            ))
-        }
 
-        pub fn process_frame<'a>(
-            &'a mut self,
-            input: &'a [u8],
-        ) -> Result<(&'a [u8], Frame<&'a str, &'a [u8]>), ParserError> {
-            frame(input, &mut self.logger).map_err(ParserError::from)
-        }
-
-        #[cfg(feature = "std")]
-        pub async fn blupp<R>(&mut self, input: &mut R) -> Result<(), ParserError>
-        where
-            R: AsyncRead + core::marker::Unpin,
-        {
-            let initial_read_len = 9; // 1 flag byte + up to 8 size byte
-            assert!(self.buffer.len() == 0); // check, that nothing is left in buffer.
-            self.buffer.resize(initial_read_len, 0);
-
-            input
-                .read_exact(&mut self.buffer)
-                .await
-                .map_err(ParserError::from)?;
-
-            let (remaining, header) =
-                frame_header(&self.buffer, &mut self.logger).map_err(ParserError::from)?;
-            {
-                let consumed = self.buffer.len() - remaining.len();
-                self.buffer.advance(consumed);
+    impl std::error::Error for ParserError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                ParserError::IoError(e) => Some(e),
+                _ => None,
             }
-
-            Ok(())
         }
     }
-*/
-    enum Greeting {
-        MajorVesion(core::num::NonZeroU8),
-        MinorAndSecMechanism(Version, SecurityMechanism),
+
+    fn filter_short_read<'a, V>(
+        buffer: &'a [u8],
+        res: IResult<&'a [u8], V>,
+    ) -> Result<Option<(&'a [u8], V)>, ParserError> {
+        match res {
+            Ok(v) => Ok(Some(v)),
+            Err(nom::Err::Incomplete(_)) => Ok(None), // will try again if more from the buffer is read
+            Err(e) => Err(ParserError::from_nom(buffer, e)),
+        }
     }
 
-    struct GreetingParser {
+    /// Decoder for the 64 octet greeting every connection starts with.
+    pub struct GreetingCodec {
         logger: slog::Logger,
-        state: Option<Greeting>,
     }
 
-    fn filter_short_read<'a, V>(res: nom::IResult<&'a [u8], V>) -> Result<Option<(&'a [u8], V)>, ParserError> {
-        match res {
-            Ok(v) => Ok(Some(v)),
-            Err(nom::Err::Incomplete(_)) => return Ok(None), // will try again if more from the buffer is read
-            Err(e) => return Err(e.into()),
+    impl GreetingCodec {
+        pub fn new<L: Into<slog::Logger>>(logger: L) -> Self {
+            GreetingCodec {
+                logger: logger.into(),
+            }
         }
     }
 
-    impl Decoder for GreetingParser {
-        type Item = Frame<Bytes, Bytes>;
+    impl Decoder for GreetingCodec {
+        type Item = Greeting;
         type Error = ParserError;
 
         fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-           filter_short_read(frame_header(src.as_ref(), &mut self.logger))?
-                .and_then(|(pos, hdr)|
-            {
-                let n_consumed = src.len() - pos.len();
-
-                if hdr.frame_length + n_consumed < src.len() {
-                    // will try again if more from the buffer is read
-                    Ok(None)
-                } else {
-                    src.advance(n_consumed);
+            if src.len() < GREETING_LEN {
+                // will try again if more from the buffer is read
+                src.reserve(GREETING_LEN - src.len());
+                return Ok(None);
+            }
+
+            let buffer = &src[..GREETING_LEN];
+            let (_, parsed_greeting) =
+                greeting(buffer, &mut self.logger).map_err(|e| ParserError::from_nom(buffer, e))?;
+            src.advance(GREETING_LEN);
+            Ok(Some(parsed_greeting))
+        }
+    }
+
+    /// Codec for ZMTP frames following the greeting.
+    ///
+    /// Wrap an `AsyncRead + AsyncWrite` in a `futures_codec::Framed` with this codec to get a
+    /// `Stream` and `Sink` of frames.
+    pub struct FrameCodec {
+        logger: slog::Logger,
+        limits: Limits,
+        mechanism: SecurityMechanism,
+        revision: ZmtpRevision,
+        /// Size of the frames received so far of the current multipart message.
+        message_size: usize,
+    }
 
-                    // the actual parsing
-                    let frame_bytes = src.split_to(hdr.frame_length).freeze();
-                    let (_, parsed_frame) = frame_body(&frame_bytes, &hdr, &mut self.logger)?;
-                    let owned_frame = Frame::<Bytes, Bytes>::from((&frame_bytes, parsed_frame));
-                    Ok(Some(owned_frame))
-                }t
-            })
+    impl FrameCodec {
+        pub fn new<L: Into<slog::Logger>>(logger: L) -> Self {
+            FrameCodec {
+                logger: logger.into(),
+                limits: Limits::unlimited(),
+                mechanism: SecurityMechanism::NULL,
+                revision: ZmtpRevision::ZMTP30,
+                message_size: 0,
+            }
+        }
+
+        pub fn with_limits(mut self, limits: Limits) -> Self {
+            self.limits = limits;
+            self
+        }
 
+        /// Recognize the handshake commands of `mechanism`; NULL by default.
+        pub fn with_mechanism(mut self, mechanism: SecurityMechanism) -> Self {
+            self.mechanism = mechanism;
+            self
+        }
 
+        /// Use the frame format of a ZMTP/1.0 or 2.0 peer; ZMTP/3.0 by default.
+        pub fn with_revision(mut self, revision: ZmtpRevision) -> Self {
+            self.revision = revision;
+            self
         }
     }
 
-    struct FrameParser {
-        logger: slog::Logger,
+    impl Encoder for FrameCodec {
+        type Item = Frame<Bytes, Bytes>;
+        type Error = ParserError;
+
+        fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+            match (self.revision, item) {
+                (ZmtpRevision::ZMTP30, item) => {
+                    let len = item.encoded_len();
+                    trace!(self.logger, "encode frame:"; o!("length" => len));
+
+                    dst.reserve(len);
+                    item.encode(dst)?;
+                }
+                (revision, Frame::Message(msg)) => {
+                    let len = legacy_frame_len(&msg, revision);
+                    trace!(self.logger, "encode legacy frame:"; o!("length" => len));
+
+                    dst.reserve(len);
+                    encode_legacy_frame(&msg, revision, dst);
+                }
+                (_, Frame::Command(_)) => return Err(ParserError::CommandNotSupported),
+            }
+            Ok(())
+        }
     }
 
-    impl Decoder for FrameParser {
+    impl Decoder for FrameCodec {
         type Item = Frame<Bytes, Bytes>;
         type Error = ParserError;
 
         fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-            let (hdr_bytes, hdr) = match frame_header(src.as_ref(), &mut self.logger) {
-                Ok((pos, hdr)) => {
-                    let n_consumed = src.len() - pos.len();
-                    (n_consumed, hdr)
-                }
-                Err(nom::Err::Incomplete(_)) => return Ok(None), // will try again if more from the buffer is read
-                Err(e) => return Err(e.into()),
+            let parsed_header = match self.revision {
+                ZmtpRevision::ZMTP30 => frame_header(src, &mut self.logger),
+                revision => legacy_frame_header(src, revision, &mut self.logger),
+            };
+            let (hdr_bytes, hdr) = match filter_short_read(src, parsed_header)? {
+                Some((pos, hdr)) => (src.len() - pos.len(), hdr),
+                None => return Ok(None),
+            };
+
+            // check the limits before reserving space for the frame
+            let total_len = match hdr_bytes.checked_add(hdr.frame_length) {
+                Some(total_len) => total_len,
+                None => return Err(ParserError::FrameTooLarge { offset: 0 }),
             };
+            if let Err(kind) = self.limits.check_header(&hdr, self.message_size) {
+                warn!(self.logger, "frame exceeds limits";
+                    o!("frame_length" => hdr.frame_length),
+                    o!("message_size" => self.message_size));
+                return Err(ParserError::from_kind(kind, 0));
+            }
 
-            if hdr.frame_length + hdr_bytes < src.len() {
+            if src.len() < total_len {
                 // will try again if more from the buffer is read
+                src.reserve((total_len - src.len()).min(MAX_RESERVE));
                 return Ok(None);
             }
-            src.advance(hdr_bytes);
 
-            // the actual parsing
-            let frame_bytes = src.split_to(hdr.frame_length).freeze();
-            let (_, parsed_frame) = frame_body(&frame_bytes, &hdr, &mut self.logger)?;
+            // the actual parsing; keep the header so that errors are reported relative to the
+            // start of the frame
+            let frame_bytes = src.split_to(total_len).freeze();
+            let body = &frame_bytes[hdr_bytes..];
+            let (_, parsed_frame) = match self.revision {
+                ZmtpRevision::ZMTP30 => {
+                    frame_body(body, &hdr, &self.mechanism, &self.limits, &mut self.logger)
+                }
+                // there are no commands before ZMTP/3.0
+                _ => message(body, &hdr, &mut self.logger)
+                    .map(|(rest, msg)| (rest, Frame::Message(msg))),
+            }
+            .map_err(|e| ParserError::from_nom(&frame_bytes, e))?;
+
+            if !hdr.flags.is_command {
+                self.message_size = if hdr.flags.more_frames_to_follow {
+                    self.message_size + hdr.frame_length
+                } else {
+                    0
+                };
+            }
             let owned_frame = Frame::<Bytes, Bytes>::from((&frame_bytes, parsed_frame));
             Ok(Some(owned_frame))
         }
     }
+
+    /// Item produced by the `ConnectionCodec`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ConnectionItem {
+        /// Major version of the peer, taken from the partial greeting. Only produced if the codec
+        /// was created `with_partial_greeting`.
+        MajorVersion(u8),
+        Greeting(Greeting),
+        /// Greeting of a ZMTP/1.0 or 2.0 peer. Only produced if the codec was created
+        /// `with_legacy_peers`; the following frames are decoded in the format of that revision.
+        LegacyGreeting(LegacyGreeting<Bytes>),
+        Frame(Frame<Bytes, Bytes>),
+    }
+
+    enum ConnectionState {
+        /// Waiting for the whole 64 octet greeting.
+        Greeting,
+        /// Waiting for signature and major version.
+        PartialGreeting,
+        /// Waiting for the remaining 53 octets of the greeting.
+        GreetingRemaining(u8),
+        /// Greeting done, only frames follow.
+        Frames,
+    }
+
+    /// Decoder for a whole ZMTP connection.
+    ///
+    /// Yields the greeting first and frames afterwards. The frames are parsed for the security
+    /// mechanism announced in the greeting.
+    pub struct ConnectionCodec {
+        logger: slog::Logger,
+        state: ConnectionState,
+        /// Whether the revision of the peer still needs to be detected.
+        detect_legacy: bool,
+        frames: FrameCodec,
+    }
+
+    impl ConnectionCodec {
+        pub fn new<L: Into<slog::Logger>>(logger: L) -> Self {
+            let logger = logger.into();
+            ConnectionCodec {
+                frames: FrameCodec::new(logger.clone()),
+                logger,
+                state: ConnectionState::Greeting,
+                detect_legacy: false,
+            }
+        }
+
+        pub fn with_limits(mut self, limits: Limits) -> Self {
+            self.frames = self.frames.with_limits(limits);
+            self
+        }
+
+        /// Read the greeting in two steps as required for the version negotiation: the 11 octet
+        /// partial greeting yields `ConnectionItem::MajorVersion`, the remaining 53 octets yield
+        /// `ConnectionItem::Greeting`.
+        pub fn with_partial_greeting(mut self) -> Self {
+            self.state = ConnectionState::PartialGreeting;
+            self
+        }
+
+        /// Accept ZMTP/1.0 and 2.0 peers as well, see `ConnectionItem::LegacyGreeting`.
+        pub fn with_legacy_peers(mut self) -> Self {
+            self.detect_legacy = true;
+            self
+        }
+
+        /// True once the greeting was decoded and frames follow.
+        pub fn greeting_done(&self) -> bool {
+            matches!(self.state, ConnectionState::Frames)
+        }
+
+        /// True if less than `len` octets are buffered; reserves space for the missing ones.
+        fn wait_for(src: &mut BytesMut, len: usize) -> bool {
+            if src.len() < len {
+                // will try again if more from the buffer is read
+                src.reserve(len - src.len());
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    impl Decoder for ConnectionCodec {
+        type Item = ConnectionItem;
+        type Error = ParserError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            match self.state {
+                ConnectionState::Greeting | ConnectionState::PartialGreeting
+                    if self.detect_legacy =>
+                {
+                    let revision =
+                        match filter_short_read(src, detect_revision(src, &mut self.logger))? {
+                            Some((_, revision)) => revision,
+                            None => return Ok(None),
+                        };
+                    if revision == ZmtpRevision::ZMTP30 {
+                        self.detect_legacy = false;
+                        return self.decode(src);
+                    }
+
+                    let limits = &self.frames.limits;
+                    let greeting_len = match filter_short_read(
+                        src,
+                        legacy_greeting(src, limits, &mut self.logger),
+                    )? {
+                        Some((rest, _)) => src.len() - rest.len(),
+                        None => return Ok(None),
+                    };
+                    let greeting_bytes = src.split_to(greeting_len).freeze();
+                    let (_, parsed) = legacy_greeting(&greeting_bytes, limits, &mut self.logger)
+                        .map_err(|e| ParserError::from_nom(&greeting_bytes, e))?;
+                    self.state = ConnectionState::Frames;
+                    self.frames.revision = revision;
+                    Ok(Some(ConnectionItem::LegacyGreeting(
+                        (&greeting_bytes, parsed).into(),
+                    )))
+                }
+                ConnectionState::Greeting => {
+                    if Self::wait_for(src, GREETING_LEN) {
+                        return Ok(None);
+                    }
+                    let buffer = &src[..GREETING_LEN];
+                    let (_, parsed) = greeting(buffer, &mut self.logger)
+                        .map_err(|e| ParserError::from_nom(buffer, e))?;
+                    src.advance(GREETING_LEN);
+                    self.state = ConnectionState::Frames;
+                    self.frames.mechanism = parsed.mechanism.clone();
+                    Ok(Some(ConnectionItem::Greeting(parsed)))
+                }
+                ConnectionState::PartialGreeting => {
+                    if Self::wait_for(src, PARTIAL_GREETING_LEN) {
+                        return Ok(None);
+                    }
+                    let buffer = &src[..PARTIAL_GREETING_LEN];
+                    let (_, major) = partial_greeting(buffer, &mut self.logger)
+                        .map_err(|e| ParserError::from_nom(buffer, e))?;
+                    src.advance(PARTIAL_GREETING_LEN);
+                    self.state = ConnectionState::GreetingRemaining(major);
+                    Ok(Some(ConnectionItem::MajorVersion(major)))
+                }
+                ConnectionState::GreetingRemaining(major) => {
+                    let remaining_len = GREETING_LEN - PARTIAL_GREETING_LEN;
+                    if Self::wait_for(src, remaining_len) {
+                        return Ok(None);
+                    }
+                    // report errors relative to the start of the greeting
+                    let buffer = &src[..remaining_len];
+                    let (_, parsed) = greeting_remaining(buffer, major, &mut self.logger)
+                        .map_err(|e| ParserError::from_nom_at(buffer, e, PARTIAL_GREETING_LEN))?;
+                    src.advance(remaining_len);
+                    self.state = ConnectionState::Frames;
+                    self.frames.mechanism = parsed.mechanism.clone();
+                    Ok(Some(ConnectionItem::Greeting(parsed)))
+                }
+                ConnectionState::Frames => Ok(self.frames.decode(src)?.map(ConnectionItem::Frame)),
+            }
+        }
+    }
 } // std
 
+#[cfg(feature = "std")]
+pub use if_std::{ConnectionCodec, ConnectionItem, FrameCodec, GreetingCodec, ParserError};
+
 pub mod prelude {
     pub use crate::command::*;
+    pub use crate::curve::*;
+    pub use crate::error::*;
     pub use crate::frame::*;
     pub use crate::greeting::*;
+    pub use crate::legacy::*;
+    pub use crate::limits::*;
     pub use crate::message::*;
+    pub use crate::socket_type::*;
+    pub use crate::subscription::*;
 }
 
 #[cfg(test)]
@@ -259,6 +658,430 @@ pub mod tests {
         frame(&server_answer, logger).unwrap();
     }
 
+    #[test]
+    fn client_server_chat_encode() {
+        let logger = &mut make_logger().new(o!("test" => "client_server_chat_encode"));
+
+        let server_ready = hex!(
+            "   04 19 05 52 45 41 44 59  0b 53 6f 63 6b 65 74 2d
+                54 79 70 65 00 00 00 03  52 45 50"
+        );
+        let client_ready_and_data = hex!(
+            "   04 26 05 52 45 41 44 59  0b 53 6f 63 6b 65 74 2d
+                54 79 70 65 00 00 00 03  52 45 51 08 49 64 65 6e
+                74 69 74 79 00 00 00 00  01 00 00 05 48 65 6c 6c
+                6f"
+        );
+        let server_answer = hex!("01 00 00 05 57 6f 72 6c 64");
+
+        for capture in [
+            &server_ready[..],
+            &client_ready_and_data[..],
+            &server_answer[..],
+        ]
+        .iter()
+        {
+            let mut remaining: &[u8] = capture;
+            let mut encoded = alloc::vec::Vec::new();
+            while !remaining.is_empty() {
+                let (rest, f) = frame(remaining, logger).unwrap();
+                f.encode(&mut encoded).unwrap();
+                remaining = rest;
+            }
+            assert_eq!(&encoded[..], *capture);
+        }
+    }
+
+    #[test]
+    fn frame_codec_sink_and_stream() {
+        use futures::{executor::block_on, io::Cursor, SinkExt};
+        use futures_codec::{Decoder, Framed};
+
+        let logger = make_logger().new(o!("test" => "frame_codec_sink_and_stream"));
+
+        // empty delimiter frame followed by the reply
+        let server_answer = hex!("01 00 00 05 57 6f 72 6c 64");
+        let delimiter = Frame::<bytes::Bytes, bytes::Bytes>::Message(
+            Message::new(bytes::Bytes::new()).with_more(true),
+        );
+        let world = Frame::<bytes::Bytes, bytes::Bytes>::Message(Message::new(
+            bytes::Bytes::from_static(b"World"),
+        ));
+
+        let mut framed = Framed::new(Cursor::new(Vec::new()), FrameCodec::new(logger.clone()));
+        block_on(framed.send(delimiter)).unwrap();
+        block_on(framed.send(world.clone())).unwrap();
+        let written = framed.release().0.into_inner();
+        assert_eq!(&written[..], &server_answer[..]);
+
+        let mut codec = FrameCodec::new(logger);
+        let mut src = bytes::BytesMut::from(&written[2..]);
+        let received = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(received, world);
+        assert!(src.is_empty());
+    }
+
+    /// Feed `stream` to the decoder in two chunks split at every possible offset.
+    fn decode_split_at_every_offset<D>(stream: &[u8], mut make_decoder: impl FnMut() -> D)
+    where
+        D: futures_codec::Decoder,
+        D::Item: PartialEq + core::fmt::Debug,
+        D::Error: core::fmt::Debug,
+    {
+        let mut expected = Vec::new();
+        {
+            let mut decoder = make_decoder();
+            let mut src = bytes::BytesMut::from(stream);
+            while let Some(item) = decoder.decode(&mut src).unwrap() {
+                expected.push(item);
+            }
+            assert!(src.is_empty());
+        }
+
+        for split in 0..=stream.len() {
+            let (first, second) = stream.split_at(split);
+            let mut decoder = make_decoder();
+            let mut src = bytes::BytesMut::new();
+            let mut decoded = Vec::new();
+
+            for chunk in [first, second].iter() {
+                src.extend_from_slice(chunk);
+                while let Some(item) = decoder.decode(&mut src).unwrap() {
+                    decoded.push(item);
+                }
+            }
+            assert!(src.is_empty(), "split at {}", split);
+            assert_eq!(decoded, expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn frame_codec_partial_reads() {
+        use futures_codec::Decoder;
+
+        let logger = make_logger().new(o!("test" => "frame_codec_partial_reads"));
+
+        let client_ready_and_data = hex!(
+            "   04 26 05 52 45 41 44 59  0b 53 6f 63 6b 65 74 2d
+                54 79 70 65 00 00 00 03  52 45 51 08 49 64 65 6e
+                74 69 74 79 00 00 00 00  01 00 00 05 48 65 6c 6c
+                6f"
+        );
+
+        // READY, delimiter and "Hello"
+        let mut codec = FrameCodec::new(logger.clone());
+        let mut src = bytes::BytesMut::from(&client_ready_and_data[..]);
+        let mut n_frames = 0;
+        while codec.decode(&mut src).unwrap().is_some() {
+            n_frames += 1;
+        }
+        assert_eq!(n_frames, 3);
+
+        decode_split_at_every_offset(&client_ready_and_data, || FrameCodec::new(logger.clone()));
+    }
+
+    #[test]
+    fn frame_codec_reserves_missing_octets() {
+        use crate::if_std::MAX_RESERVE;
+        use futures_codec::Decoder;
+
+        let logger = make_logger().new(o!("test" => "frame_codec_reserves_missing_octets"));
+
+        // long message frame of 1000 octets of which only the header is available
+        let mut src = bytes::BytesMut::from(&hex!("00 00 00 00 00 00 00 03 e8")[..]);
+        src[0] = 0x02;
+        let mut codec = FrameCodec::new(logger);
+
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert_eq!(src.len(), 9);
+        assert!(src.capacity() >= 9 + 1000);
+
+        src.extend_from_slice(&[0u8; 1000]);
+        let f = codec.decode(&mut src).unwrap().unwrap();
+        assert!(src.is_empty());
+        match f {
+            Frame::Message(m) => assert_eq!(m.data().len(), 1000),
+            Frame::Command(_) => panic!("expected message frame"),
+        }
+
+        // for a frame of 1 MiB, space is only reserved for the next chunk
+        src.extend_from_slice(&hex!("02 00 00 00 00 00 10 00 00"));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.capacity() >= 9 + MAX_RESERVE);
+        assert!(src.capacity() < 1 << 20);
+
+        for _ in 0..16 {
+            src.extend_from_slice(&[0u8; MAX_RESERVE]);
+        }
+        match codec.decode(&mut src).unwrap().unwrap() {
+            Frame::Message(m) => assert_eq!(m.data().len(), 1 << 20),
+            Frame::Command(_) => panic!("expected message frame"),
+        }
+    }
+
+    #[test]
+    fn greeting_codec_partial_reads() {
+        let logger = make_logger().new(o!("test" => "greeting_codec_partial_reads"));
+
+        let intro = hex!(
+            "   ff 00 00 00 00 00 00 00  01 7f 03 00 4e 55 4c 4c
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
+        );
+
+        decode_split_at_every_offset(&intro, || GreetingCodec::new(logger.clone()));
+    }
+
+    /// Client side of the hello_world capture: greeting, READY, "Hello"
+    fn client_stream() -> Vec<u8> {
+        let mut stream = hex!(
+            "   ff 00 00 00 00 00 00 00  01 7f 03 00 4e 55 4c 4c
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00
+                00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
+        )
+        .to_vec();
+        stream.extend_from_slice(&hex!(
+            "   04 26 05 52 45 41 44 59  0b 53 6f 63 6b 65 74 2d
+                54 79 70 65 00 00 00 03  52 45 51 08 49 64 65 6e
+                74 69 74 79 00 00 00 00  01 00 00 05 48 65 6c 6c
+                6f"
+        ));
+        stream
+    }
+
+    #[test]
+    fn connection_codec_greeting_then_frames() {
+        use futures_codec::Decoder;
+
+        let logger = make_logger().new(o!("test" => "connection_codec_greeting_then_frames"));
+        let stream = client_stream();
+
+        let mut codec = ConnectionCodec::new(logger.clone());
+        let mut src = bytes::BytesMut::from(&stream[..]);
+        let mut items = Vec::new();
+        while let Some(item) = codec.decode(&mut src).unwrap() {
+            items.push(item);
+        }
+        assert!(codec.greeting_done());
+        assert_eq!(items.len(), 4);
+        assert_eq!(
+            items[0],
+            ConnectionItem::Greeting(Greeting::default().with_version(3, 0))
+        );
+        match &items[1] {
+            ConnectionItem::Frame(Frame::Command(Command::READY(_))) => {}
+            other => panic!("expected READY, got {:?}", other),
+        }
+
+        decode_split_at_every_offset(&stream, || ConnectionCodec::new(logger.clone()));
+    }
+
+    #[test]
+    fn connection_codec_partial_greeting() {
+        use futures_codec::Decoder;
+
+        let logger = make_logger().new(o!("test" => "connection_codec_partial_greeting"));
+        let stream = client_stream();
+
+        let mut codec = ConnectionCodec::new(logger.clone()).with_partial_greeting();
+        let mut src = bytes::BytesMut::from(&stream[..10]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&stream[10..11]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ConnectionItem::MajorVersion(3))
+        );
+        assert!(src.is_empty());
+
+        src.extend_from_slice(&stream[11..63]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&stream[63..64]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ConnectionItem::Greeting(
+                Greeting::default().with_version(3, 0)
+            ))
+        );
+        assert!(codec.greeting_done());
+        assert!(src.is_empty());
+
+        decode_split_at_every_offset(&stream, || {
+            ConnectionCodec::new(logger.clone()).with_partial_greeting()
+        });
+    }
+
+    #[test]
+    fn connection_codec_legacy_peers() {
+        use futures_codec::{Decoder, Encoder};
+
+        let logger = make_logger().new(o!("test" => "connection_codec_legacy_peers"));
+
+        // libzmq 3.2 REQ with identity "A", followed by the request "", "Hello"
+        let zmtp20 =
+            hex!("ff 00 00 00 00 00 00 00 02 7f 01 03 00 01 41 01 00 00 05 48 65 6c 6c 6f");
+        let mut codec = ConnectionCodec::new(logger.clone()).with_legacy_peers();
+        let mut src = bytes::BytesMut::from(&zmtp20[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ConnectionItem::LegacyGreeting(LegacyGreeting {
+                socket_type: Some(SocketType::REQ),
+                identity: bytes::Bytes::from_static(b"A"),
+            }))
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ConnectionItem::Frame(Frame::Message(
+                Message::new(bytes::Bytes::new()).with_more(true)
+            )))
+        );
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(src.is_empty());
+        decode_split_at_every_offset(&zmtp20, || {
+            ConnectionCodec::new(logger.clone()).with_legacy_peers()
+        });
+
+        // the same request from a ZMTP/1.0 peer
+        let zmtp10 = hex!("02 00 41 01 01 06 00 48 65 6c 6c 6f");
+        decode_split_at_every_offset(&zmtp10, || {
+            ConnectionCodec::new(logger.clone()).with_legacy_peers()
+        });
+
+        // ZMTP/3.0 peers are still understood
+        let stream = client_stream();
+        let mut codec = ConnectionCodec::new(logger.clone())
+            .with_partial_greeting()
+            .with_legacy_peers();
+        let mut src = bytes::BytesMut::from(&stream[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ConnectionItem::MajorVersion(3))
+        );
+        decode_split_at_every_offset(&stream, || {
+            ConnectionCodec::new(logger.clone()).with_legacy_peers()
+        });
+
+        // frames to legacy peers are encoded in their format, commands are rejected
+        let mut frames = FrameCodec::new(logger).with_revision(ZmtpRevision::ZMTP10);
+        let mut dst = bytes::BytesMut::new();
+        frames
+            .encode(
+                Frame::Message(Message::new(bytes::Bytes::from_static(b"Hello"))),
+                &mut dst,
+            )
+            .unwrap();
+        assert_eq!(&dst[..], &zmtp10[5..]);
+        assert!(matches!(
+            frames.encode(
+                Frame::Command(Command::PONG(Pong {
+                    context: bytes::Bytes::new()
+                })),
+                &mut dst
+            ),
+            Err(ParserError::CommandNotSupported)
+        ));
+    }
+
+    #[test]
+    fn parser_error_kinds_and_offsets() {
+        use futures_codec::Decoder;
+
+        let logger = make_logger().new(o!("test" => "parser_error_kinds_and_offsets"));
+        let decode_frame = |input: &[u8]| {
+            let mut src = bytes::BytesMut::from(input);
+            FrameCodec::new(logger.clone())
+                .decode(&mut src)
+                .unwrap_err()
+        };
+        let decode_greeting = |input: &[u8]| {
+            let mut src = bytes::BytesMut::from(input);
+            GreetingCodec::new(logger.clone())
+                .decode(&mut src)
+                .unwrap_err()
+        };
+
+        let mut intro = client_stream()[..GREETING_LEN].to_vec();
+        intro[9] = 0x7e;
+        match decode_greeting(&intro) {
+            ParserError::BadSignature { offset: 9 } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let mut intro = client_stream()[..GREETING_LEN].to_vec();
+        intro[12..15].copy_from_slice(b"FOO");
+        match decode_greeting(&intro) {
+            ParserError::UnsupportedMechanism { offset: 12 } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // "HEL-O"
+        match decode_frame(&hex!("04 06 05 48 45 4c 2d 4f")) {
+            ParserError::InvalidCommandName { offset: 3 } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // property name "a " contains an invalid character
+        match decode_frame(&hex!("04 0d 05 52 45 41 44 59 02 61 20 00 00 00 00")) {
+            ParserError::MalformedProperty { offset: 10 } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // error reason announces 5 octets, but the frame ends after 1
+        match decode_frame(&hex!("04 08 05 45 52 52 4f 52 05 61")) {
+            ParserError::TruncatedFrame { offset: 9 } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        match ParserError::from(io_error) {
+            ParserError::IoError(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn frame_codec_limits() {
+        use futures_codec::Decoder;
+
+        let logger = make_logger().new(o!("test" => "frame_codec_limits"));
+
+        // a frame of 2^60 octets is refused without reserving memory
+        let mut src = bytes::BytesMut::from(&hex!("02 10 00 00 00 00 00 00 00")[..]);
+        let capacity = src.capacity();
+        let mut codec = FrameCodec::new(logger.clone())
+            .with_limits(Limits::unlimited().with_max_frame_size(1 << 20));
+        match codec.decode(&mut src) {
+            Err(ParserError::FrameTooLarge { offset: 0 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(src.capacity(), capacity);
+
+        // three parts of 5 octets each, but at most 12 octets per message
+        let mut src = bytes::BytesMut::from(
+            &hex!("01 05 61 61 61 61 61 01 05 62 62 62 62 62 00 05 63 63 63 63 63")[..],
+        );
+        let mut codec = FrameCodec::new(logger.clone())
+            .with_limits(Limits::unlimited().with_max_message_size(12));
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        match codec.decode(&mut src) {
+            Err(ParserError::MessageTooLarge { offset: 0 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the limit applies per message
+        let mut src = bytes::BytesMut::from(
+            &hex!("01 05 61 61 61 61 61 00 05 62 62 62 62 62 00 05 63 63 63 63 63")[..],
+        );
+        let mut codec =
+            FrameCodec::new(logger).with_limits(Limits::unlimited().with_max_message_size(10));
+        for _ in 0..3 {
+            assert!(codec.decode(&mut src).unwrap().is_some());
+        }
+    }
+
     #[test]
     fn test_extract_from_slice() {
         let b = bytes::Bytes::from("Hallo Welt");
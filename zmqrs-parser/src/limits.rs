@@ -0,0 +1,176 @@
+use crate::error::ZmtpErrorKind;
+use crate::FrameHeader;
+
+/// Limits the parser enforces on data received from a peer.
+///
+/// All limits are disabled by default, like `ZMQ_MAXMSGSIZE` of libzmq. Exceeding a limit is
+/// reported as soon as the offending size is known, i.e. before any buffer is reserved for it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Maximum size of a single frame body in octets.
+    pub max_frame_size: Option<usize>,
+
+    /// Maximum total size of all frames of a multipart message in octets.
+    pub max_message_size: Option<usize>,
+
+    /// Maximum number of metadata properties in a single command.
+    pub max_properties: Option<usize>,
+
+    /// Maximum length of a single metadata property value in octets.
+    pub max_property_value_len: Option<usize>,
+}
+
+impl Limits {
+    /// No limits at all.
+    pub fn unlimited() -> Self {
+        Limits::default()
+    }
+
+    pub fn with_max_frame_size(mut self, max: usize) -> Self {
+        self.max_frame_size = Some(max);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = Some(max);
+        self
+    }
+
+    pub fn with_max_properties(mut self, max: usize) -> Self {
+        self.max_properties = Some(max);
+        self
+    }
+
+    pub fn with_max_property_value_len(mut self, max: usize) -> Self {
+        self.max_property_value_len = Some(max);
+        self
+    }
+
+    /// Check a frame header against the size limits.
+    ///
+    /// `message_size` is the size of the preceding frames of the same multipart message.
+    pub fn check_header(
+        &self,
+        hdr: &FrameHeader,
+        message_size: usize,
+    ) -> Result<(), ZmtpErrorKind> {
+        if exceeds(hdr.frame_length, self.max_frame_size) {
+            return Err(ZmtpErrorKind::FrameTooLarge);
+        }
+        if !hdr.flags.is_command
+            && exceeds(
+                message_size.saturating_add(hdr.frame_length),
+                self.max_message_size,
+            )
+        {
+            return Err(ZmtpErrorKind::MessageTooLarge);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_property_count(&self, count: usize) -> Result<(), ZmtpErrorKind> {
+        if exceeds(count, self.max_properties) {
+            Err(ZmtpErrorKind::TooManyProperties)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn check_property_value_len(&self, len: usize) -> Result<(), ZmtpErrorKind> {
+        if exceeds(len, self.max_property_value_len) {
+            Err(ZmtpErrorKind::PropertyTooLarge)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn exceeds(value: usize, limit: Option<usize>) -> bool {
+    matches!(limit, Some(limit) if value > limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::*;
+
+    use crate::tests::make_logger;
+    use crate::{frame, frame_limited, SecurityMechanism, ZmtpErrorKind};
+
+    const CLIENT_READY: &[u8] =
+        b"\x04\x26\x05READY\x0bSocket-Type\x00\x00\x00\x03REQ\x08Identity\x00\x00\x00\x00";
+
+    fn limit_error(input: &[u8], limits: &Limits) -> Option<ZmtpErrorKind> {
+        let mut logger = make_logger().new(o!("test" => "limits"));
+        match frame_limited(input, &SecurityMechanism::NULL, limits, &mut logger) {
+            Ok(_) => None,
+            Err(nom::Err::Failure(e)) => Some(e.kind),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let mut logger = make_logger().new(o!("test" => "unlimited_by_default"));
+        assert!(frame(CLIENT_READY, &mut logger).is_ok());
+        assert_eq!(limit_error(CLIENT_READY, &Limits::default()), None);
+    }
+
+    #[test]
+    fn frame_size_limit() {
+        // the long header announces an exabyte sized frame
+        let huge = b"\x02\x10\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            limit_error(huge, &Limits::unlimited().with_max_frame_size(1024)),
+            Some(ZmtpErrorKind::FrameTooLarge)
+        );
+        assert_eq!(
+            limit_error(CLIENT_READY, &Limits::unlimited().with_max_frame_size(0x26)),
+            None
+        );
+        assert_eq!(
+            limit_error(CLIENT_READY, &Limits::unlimited().with_max_frame_size(0x25)),
+            Some(ZmtpErrorKind::FrameTooLarge)
+        );
+    }
+
+    #[test]
+    fn metadata_limits() {
+        assert_eq!(
+            limit_error(CLIENT_READY, &Limits::unlimited().with_max_properties(2)),
+            None
+        );
+        assert_eq!(
+            limit_error(CLIENT_READY, &Limits::unlimited().with_max_properties(1)),
+            Some(ZmtpErrorKind::TooManyProperties)
+        );
+        assert_eq!(
+            limit_error(
+                CLIENT_READY,
+                &Limits::unlimited().with_max_property_value_len(3)
+            ),
+            None
+        );
+        assert_eq!(
+            limit_error(
+                CLIENT_READY,
+                &Limits::unlimited().with_max_property_value_len(2)
+            ),
+            Some(ZmtpErrorKind::PropertyTooLarge)
+        );
+    }
+
+    #[test]
+    fn message_size_ignores_commands() {
+        let hdr = FrameHeader::new(true, false, 100);
+        let limits = Limits::unlimited().with_max_message_size(10);
+        assert_eq!(limits.check_header(&hdr, 0), Ok(()));
+
+        let hdr = FrameHeader::new(false, true, 6);
+        assert_eq!(limits.check_header(&hdr, 4), Ok(()));
+        assert_eq!(
+            limits.check_header(&hdr, 5),
+            Err(ZmtpErrorKind::MessageTooLarge)
+        );
+    }
+}
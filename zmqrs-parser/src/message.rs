@@ -1,18 +1,222 @@
-use nom::{bytes::complete::take, IResult};
+use alloc::vec::Vec;
+use bytes::BufMut;
+use nom::bytes::complete::take;
 
-use crate::{ByteSlice, FrameHeader};
+use crate::error::IResult;
+use crate::{ByteSlice, Command, Frame, FrameHeader};
 
-#[derive(Debug, Clone)]
-pub struct Message<T>(
-    // TODO: think about what to do here. Since we can have multi-part messages,
-    // maybe some kind of Vec<&[u8]> makes sense to copy only little data
-    ByteSlice<T>,
-);
+/// A single message frame; see `Multipart` for whole messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message<T> {
+    data: ByteSlice<T>,
+
+    /// The MORE flag of the frame carrying this message part.
+    more: bool,
+}
+
+impl<T> Message<T> {
+    /// A single message frame without the MORE flag.
+    pub fn new(data: T) -> Self {
+        Message {
+            data: ByteSlice(data),
+            more: false,
+        }
+    }
+
+    /// Set whether more frames of the same message follow this one.
+    pub fn with_more(mut self, more: bool) -> Self {
+        self.more = more;
+        self
+    }
+
+    pub fn data(&self) -> &T {
+        &self.data.0
+    }
+
+    pub fn into_data(self) -> T {
+        self.data.0
+    }
+
+    /// True if more frames of the same message follow this one.
+    pub fn more(&self) -> bool {
+        self.more
+    }
+}
+
+impl<T: AsRef<[u8]>> Message<T> {
+    /// Number of octets of the frame body.
+    pub fn encoded_len(&self) -> usize {
+        self.data.0.as_ref().len()
+    }
+
+    /// Write the frame body; the header is written by `Frame::encode`.
+    pub fn encode<B: BufMut>(&self, dst: &mut B) {
+        dst.put_slice(self.data.0.as_ref());
+    }
+}
 
 impl From<(&bytes::Bytes, Message<&[u8]>)> for Message<bytes::Bytes> {
     fn from(input: (&bytes::Bytes, Message<&[u8]>)) -> Self {
         let (buffer, subset) = input;
-        Message((buffer, subset.0).into())
+        Message {
+            data: (buffer, subset.data).into(),
+            more: subset.more,
+        }
+    }
+}
+
+/// A complete message consisting of one or more frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Multipart<T> {
+    parts: Vec<T>,
+}
+
+impl<T> Default for Multipart<T> {
+    fn default() -> Self {
+        Multipart { parts: Vec::new() }
+    }
+}
+
+impl<T> Multipart<T> {
+    pub fn new() -> Self {
+        Multipart::default()
+    }
+
+    pub fn push(&mut self, part: T) {
+        self.parts.push(part);
+    }
+
+    pub fn with_part(mut self, part: T) -> Self {
+        self.push(part);
+        self
+    }
+
+    pub fn parts(&self) -> &[T] {
+        &self.parts
+    }
+
+    pub fn into_parts(self) -> Vec<T> {
+        self.parts
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.parts.iter()
+    }
+
+    /// The message frames to send; all but the last one carry the MORE flag.
+    pub fn into_frames(self) -> impl Iterator<Item = Message<T>> {
+        let last = self.parts.len().saturating_sub(1);
+        self.parts
+            .into_iter()
+            .enumerate()
+            .map(move |(i, part)| Message::new(part).with_more(i != last))
+    }
+}
+
+impl<T> From<Vec<T>> for Multipart<T> {
+    fn from(parts: Vec<T>) -> Self {
+        Multipart { parts }
+    }
+}
+
+impl<T> IntoIterator for Multipart<T> {
+    type Item = T;
+    type IntoIter = alloc::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.parts.into_iter()
+    }
+}
+
+/// Output of the `MultipartAssembler`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Assembled<S, T> {
+    Command(Command<S, T>),
+    Message(Multipart<T>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblyError {
+    /// A command frame arrived while a multipart message was incomplete.
+    CommandInsideMessage { received_parts: usize },
+}
+
+impl core::fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            AssemblyError::CommandInsideMessage { received_parts } => write!(
+                f,
+                "Command frame after {} parts of an incomplete message",
+                received_parts
+            ),
+        }
+    }
+}
+
+/// Collects message frames until one without the MORE flag arrives.
+///
+/// An implementation SHALL always deliver messages atomically, so nothing is returned before the
+/// last frame of a message was pushed.
+#[derive(Debug)]
+pub struct MultipartAssembler<T> {
+    pending: Multipart<T>,
+}
+
+impl<T> Default for MultipartAssembler<T> {
+    fn default() -> Self {
+        MultipartAssembler {
+            pending: Multipart::new(),
+        }
+    }
+}
+
+impl<T> MultipartAssembler<T> {
+    pub fn new() -> Self {
+        MultipartAssembler::default()
+    }
+
+    /// True if a message was started but is not complete yet.
+    pub fn in_message(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Feed the next received frame.
+    ///
+    /// Returns a command as soon as it arrives and a message once its last frame arrived. A
+    /// command in the middle of a multipart message is rejected and the incomplete message is
+    /// dropped.
+    pub fn push<S>(
+        &mut self,
+        frame: Frame<S, T>,
+    ) -> Result<Option<Assembled<S, T>>, AssemblyError> {
+        match frame {
+            Frame::Command(cmd) => {
+                if self.in_message() {
+                    let received_parts = self.pending.len();
+                    self.pending = Multipart::new();
+                    Err(AssemblyError::CommandInsideMessage { received_parts })
+                } else {
+                    Ok(Some(Assembled::Command(cmd)))
+                }
+            }
+            Frame::Message(msg) => {
+                let more = msg.more();
+                self.pending.push(msg.into_data());
+                if more {
+                    Ok(None)
+                } else {
+                    Ok(Some(Assembled::Message(core::mem::take(&mut self.pending))))
+                }
+            }
+        }
     }
 }
 
@@ -27,10 +231,89 @@ pub fn message<'a>(
     hdr: &FrameHeader,
     logger: &mut slog::Logger,
 ) -> IResult<&'a [u8], Message<&'a [u8]>> {
-    // TODO: Think about multi-frame messages
     let (input, msg) = take(hdr.frame_length)(input)?;
     trace!(logger, "message:";
         o!("length" => msg.len()),
+        o!("more" => hdr.flags.more_frames_to_follow),
         o!("content" => ByteSlice(msg)));
-    Ok((input, Message(ByteSlice(msg))))
+    Ok((
+        input,
+        Message::new(msg).with_more(hdr.flags.more_frames_to_follow),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::*;
+
+    use crate::frame;
+    use crate::tests::make_logger;
+
+    #[test]
+    fn assemble_request_envelope() {
+        let mut logger = make_logger().new(o!("test" => "assemble_request_envelope"));
+
+        // delimiter followed by "Hello", as sent by the hello_world client
+        let mut input = &b"\x01\x00\x00\x05Hello"[..];
+        let mut assembler = MultipartAssembler::new();
+
+        let (rest, delimiter) = frame(input, &mut logger).unwrap();
+        input = rest;
+        assert_eq!(assembler.push(delimiter), Ok(None));
+        assert!(assembler.in_message());
+
+        let (rest, hello) = frame(input, &mut logger).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            assembler.push(hello),
+            Ok(Some(Assembled::Message(
+                Multipart::new()
+                    .with_part(&b""[..])
+                    .with_part(&b"Hello"[..])
+            )))
+        );
+        assert!(!assembler.in_message());
+    }
+
+    #[test]
+    fn command_inside_message_is_rejected() {
+        let mut assembler = MultipartAssembler::new();
+
+        let first: Frame<&str, &[u8]> = Frame::Message(Message::new(&b"a"[..]).with_more(true));
+        let ping: Frame<&str, &[u8]> = Frame::Command(Command::PING(crate::Ping {
+            ttl: 0,
+            context: &b""[..],
+        }));
+        let last: Frame<&str, &[u8]> = Frame::Message(Message::new(&b"b"[..]));
+
+        assert_eq!(assembler.push(first), Ok(None));
+        assert_eq!(
+            assembler.push(ping.clone()),
+            Err(AssemblyError::CommandInsideMessage { received_parts: 1 })
+        );
+
+        // the incomplete message was dropped
+        assert_eq!(
+            assembler.push(last),
+            Ok(Some(Assembled::Message(Multipart::from(vec![&b"b"[..]]))))
+        );
+        match assembler.push(ping) {
+            Ok(Some(Assembled::Command(Command::PING(_)))) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multipart_into_frames() {
+        let msg = Multipart::from(vec![&b""[..], &b"World"[..]]);
+        let frames: Vec<_> = msg.into_frames().collect();
+        assert_eq!(
+            frames,
+            vec![
+                Message::new(&b""[..]).with_more(true),
+                Message::new(&b"World"[..])
+            ]
+        );
+    }
 }
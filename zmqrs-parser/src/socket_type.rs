@@ -0,0 +1,185 @@
+use core::convert::TryFrom;
+
+/// Socket types as announced in the "Socket-Type" metadata property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketType {
+    PAIR,
+    PUB,
+    SUB,
+    REQ,
+    REP,
+    DEALER,
+    ROUTER,
+    PULL,
+    PUSH,
+    XPUB,
+    XSUB,
+}
+
+const ALL: [SocketType; 11] = [
+    SocketType::PAIR,
+    SocketType::PUB,
+    SocketType::SUB,
+    SocketType::REQ,
+    SocketType::REP,
+    SocketType::DEALER,
+    SocketType::ROUTER,
+    SocketType::PULL,
+    SocketType::PUSH,
+    SocketType::XPUB,
+    SocketType::XSUB,
+];
+
+impl SocketType {
+    /// The name as sent on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SocketType::PAIR => "PAIR",
+            SocketType::PUB => "PUB",
+            SocketType::SUB => "SUB",
+            SocketType::REQ => "REQ",
+            SocketType::REP => "REP",
+            SocketType::DEALER => "DEALER",
+            SocketType::ROUTER => "ROUTER",
+            SocketType::PULL => "PULL",
+            SocketType::PUSH => "PUSH",
+            SocketType::XPUB => "XPUB",
+            SocketType::XSUB => "XSUB",
+        }
+    }
+
+    pub fn as_bytes(&self) -> &'static [u8] {
+        self.name().as_bytes()
+    }
+
+    /// The socket type octet of a ZMTP/2.0 greeting.
+    pub fn legacy_code(&self) -> u8 {
+        // the order of `ALL` is the numbering of libzmq
+        ALL.iter().position(|t| t == self).unwrap_or_default() as u8
+    }
+
+    /// Look up the socket type octet of a ZMTP/2.0 greeting.
+    pub fn from_legacy_code(code: u8) -> Option<SocketType> {
+        ALL.get(code as usize).copied()
+    }
+
+    /// True if a socket of this type may talk to a peer of type `peer`.
+    ///
+    /// This is the table of valid socket combinations of ZMTP 3.0; any other combination is
+    /// invalid and the connection should be closed.
+    pub fn is_compatible_with(&self, peer: SocketType) -> bool {
+        use SocketType::*;
+        match self {
+            REQ => matches!(peer, REP | ROUTER),
+            REP => matches!(peer, REQ | DEALER),
+            DEALER => matches!(peer, REP | DEALER | ROUTER),
+            ROUTER => matches!(peer, REQ | DEALER | ROUTER),
+            PUB | XPUB => matches!(peer, SUB | XSUB),
+            SUB | XSUB => matches!(peer, PUB | XPUB),
+            PUSH => peer == PULL,
+            PULL => peer == PUSH,
+            PAIR => peer == PAIR,
+        }
+    }
+}
+
+impl core::fmt::Display for SocketType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.write_str(self.name())
+    }
+}
+
+/// The socket type name is not one of the names defined by ZMTP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownSocketType;
+
+impl core::fmt::Display for UnknownSocketType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        write!(f, "Unknown socket type")
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for SocketType {
+    type Error = UnknownSocketType;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        ALL.iter()
+            .find(|t| t.as_bytes().eq_ignore_ascii_case(value))
+            .copied()
+            .ok_or(UnknownSocketType)
+    }
+}
+
+impl core::str::FromStr for SocketType {
+    type Err = UnknownSocketType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SocketType::try_from(s.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn wire_names_round_trip() {
+        for t in ALL.iter() {
+            assert_eq!(SocketType::try_from(t.as_bytes()), Ok(*t));
+            assert_eq!(t.to_string().parse::<SocketType>(), Ok(*t));
+        }
+        assert_eq!(SocketType::try_from(&b"rep"[..]), Ok(SocketType::REP));
+        assert_eq!(SocketType::try_from(&b"STREAM"[..]), Err(UnknownSocketType));
+        assert_eq!(SocketType::try_from(&b""[..]), Err(UnknownSocketType));
+    }
+
+    #[test]
+    fn legacy_codes() {
+        for t in ALL.iter() {
+            assert_eq!(SocketType::from_legacy_code(t.legacy_code()), Some(*t));
+        }
+        assert_eq!(SocketType::REQ.legacy_code(), 3);
+        assert_eq!(SocketType::XSUB.legacy_code(), 10);
+        assert_eq!(SocketType::from_legacy_code(11), None);
+    }
+
+    #[test]
+    fn compatibility_matrix_is_symmetric() {
+        for a in ALL.iter() {
+            for b in ALL.iter() {
+                assert_eq!(
+                    a.is_compatible_with(*b),
+                    b.is_compatible_with(*a),
+                    "{} <-> {}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compatibility_matrix() {
+        use SocketType::*;
+
+        assert!(REQ.is_compatible_with(REP));
+        assert!(REQ.is_compatible_with(ROUTER));
+        assert!(!REQ.is_compatible_with(PUB));
+        assert!(!REQ.is_compatible_with(REQ));
+        assert!(DEALER.is_compatible_with(DEALER));
+        assert!(XPUB.is_compatible_with(XSUB));
+        assert!(!PUB.is_compatible_with(PUB));
+        assert!(PUSH.is_compatible_with(PULL));
+        assert!(!PUSH.is_compatible_with(PUSH));
+        assert!(PAIR.is_compatible_with(PAIR));
+        assert!(!PAIR.is_compatible_with(DEALER));
+
+        let n_valid = ALL
+            .iter()
+            .flat_map(|a| ALL.iter().map(move |b| a.is_compatible_with(*b)))
+            .filter(|valid| *valid)
+            .count();
+        assert_eq!(n_valid, 21);
+    }
+}
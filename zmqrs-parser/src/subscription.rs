@@ -0,0 +1,220 @@
+use bytes::BufMut;
+
+use crate::greeting::Version;
+use crate::{ByteSlice, Command, Frame, FrameHeader, Message};
+
+/// Marker octet of a subscription sent as message frame in ZMTP 3.0.
+const SUBSCRIBE_MARKER: u8 = 0x01;
+/// Marker octet of a cancellation sent as message frame in ZMTP 3.0.
+const CANCEL_MARKER: u8 = 0x00;
+
+/// A subscription or its cancellation, independent of the wire format.
+///
+/// ZMTP 3.0 sends subscriptions as message frames, whose first octet is 1 for a subscription
+/// and 0 for a cancellation. ZMTP 3.1 uses the SUBSCRIBE and CANCEL commands instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subscription<T> {
+    Subscribe(T),
+    Cancel(T),
+}
+
+/// True if the SUBSCRIBE and CANCEL commands are used with a peer of this version.
+pub fn uses_subscription_commands(version: &Version) -> bool {
+    version.at_least(3, 1)
+}
+
+impl<T> Subscription<T> {
+    pub fn topic(&self) -> &T {
+        match self {
+            Subscription::Subscribe(topic) => topic,
+            Subscription::Cancel(topic) => topic,
+        }
+    }
+}
+
+impl<'a> Subscription<&'a [u8]> {
+    /// Interpret a frame received from a peer of the given version.
+    ///
+    /// A ZMTP 3.0 peer only sends the message form. ZMTP 3.1 peers send commands, but the message
+    /// form is accepted as well, like libzmq does. Returns `None` if the frame is no subscription.
+    pub fn from_frame<S, T: AsRef<[u8]>>(
+        frame: &'a Frame<S, T>,
+        version: &Version,
+    ) -> Option<Self> {
+        match frame {
+            Frame::Command(Command::SUBSCRIBE(topic)) if uses_subscription_commands(version) => {
+                Some(Subscription::Subscribe(topic.0.as_ref()))
+            }
+            Frame::Command(Command::CANCEL(topic)) if uses_subscription_commands(version) => {
+                Some(Subscription::Cancel(topic.0.as_ref()))
+            }
+            Frame::Message(msg) if !msg.more() => match msg.data().as_ref().split_first() {
+                Some((&SUBSCRIBE_MARKER, topic)) => Some(Subscription::Subscribe(topic)),
+                Some((&CANCEL_MARKER, topic)) => Some(Subscription::Cancel(topic)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> Subscription<T> {
+    fn header(&self, version: &Version) -> FrameHeader {
+        let topic_len = self.topic().as_ref().len();
+        if uses_subscription_commands(version) {
+            let name_len = match self {
+                Subscription::Subscribe(_) => b"SUBSCRIBE".len(),
+                Subscription::Cancel(_) => b"CANCEL".len(),
+            };
+            FrameHeader::new(true, false, 1 + name_len + topic_len)
+        } else {
+            FrameHeader::new(false, false, 1 + topic_len)
+        }
+    }
+
+    /// Number of octets `encode` will write, including the frame header.
+    pub fn encoded_len(&self, version: &Version) -> usize {
+        let hdr = self.header(version);
+        hdr.encoded_len() + hdr.frame_length
+    }
+
+    /// Write the whole frame in the format understood by a peer of the given version.
+    pub fn encode<B: BufMut>(&self, version: &Version, dst: &mut B) {
+        self.header(version).encode(dst);
+        let topic = self.topic().as_ref();
+        if uses_subscription_commands(version) {
+            let cmd: Command<&str, &[u8]> = match self {
+                Subscription::Subscribe(_) => Command::SUBSCRIBE(ByteSlice(topic)),
+                Subscription::Cancel(_) => Command::CANCEL(ByteSlice(topic)),
+            };
+            cmd.write(dst);
+        } else {
+            let marker = match self {
+                Subscription::Subscribe(_) => SUBSCRIBE_MARKER,
+                Subscription::Cancel(_) => CANCEL_MARKER,
+            };
+            dst.put_u8(marker);
+            dst.put_slice(topic);
+        }
+    }
+}
+
+impl Subscription<bytes::Bytes> {
+    /// The frame to send to a peer of the given version.
+    pub fn into_frame(self, version: &Version) -> Frame<bytes::Bytes, bytes::Bytes> {
+        if uses_subscription_commands(version) {
+            match self {
+                Subscription::Subscribe(topic) => {
+                    Frame::Command(Command::SUBSCRIBE(ByteSlice(topic)))
+                }
+                Subscription::Cancel(topic) => Frame::Command(Command::CANCEL(ByteSlice(topic))),
+            }
+        } else {
+            let (marker, topic) = match &self {
+                Subscription::Subscribe(topic) => (SUBSCRIBE_MARKER, topic),
+                Subscription::Cancel(topic) => (CANCEL_MARKER, topic),
+            };
+            let mut body = bytes::BytesMut::with_capacity(1 + topic.len());
+            body.put_u8(marker);
+            body.put_slice(topic);
+            Frame::Message(Message::new(body.freeze()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use slog::*;
+
+    use crate::frame;
+    use crate::tests::make_logger;
+
+    const ZMTP_30: Version = Version { major: 3, minor: 0 };
+    const ZMTP_31: Version = Version { major: 3, minor: 1 };
+
+    #[test]
+    fn encode_by_version() {
+        let sub = Subscription::Subscribe(&b"weather"[..]);
+        let cancel = Subscription::Cancel(&b"weather"[..]);
+
+        let mut buf = Vec::new();
+        sub.encode(&ZMTP_30, &mut buf);
+        assert_eq!(&buf[..], &b"\x00\x08\x01weather"[..]);
+        assert_eq!(buf.len(), sub.encoded_len(&ZMTP_30));
+
+        let mut buf = Vec::new();
+        cancel.encode(&ZMTP_30, &mut buf);
+        assert_eq!(&buf[..], &b"\x00\x08\x00weather"[..]);
+
+        let mut buf = Vec::new();
+        sub.encode(&ZMTP_31, &mut buf);
+        assert_eq!(&buf[..], &b"\x04\x11\x09SUBSCRIBEweather"[..]);
+        assert_eq!(buf.len(), sub.encoded_len(&ZMTP_31));
+
+        let mut buf = Vec::new();
+        cancel.encode(&ZMTP_31, &mut buf);
+        assert_eq!(&buf[..], &b"\x04\x0e\x06CANCELweather"[..]);
+    }
+
+    #[test]
+    fn round_trip_both_versions() {
+        let mut logger = make_logger().new(o!("test" => "round_trip_both_versions"));
+
+        for version in [ZMTP_30, ZMTP_31].iter() {
+            for sub in [
+                Subscription::Subscribe(&b"weather"[..]),
+                Subscription::Cancel(&b""[..]),
+            ]
+            .iter()
+            {
+                let mut buf = Vec::new();
+                sub.encode(version, &mut buf);
+                let (rest, parsed) = frame(&buf, &mut logger).unwrap();
+                assert!(rest.is_empty());
+                assert_eq!(
+                    Subscription::from_frame(&parsed, version).as_ref(),
+                    Some(sub)
+                );
+
+                let owned = match sub {
+                    Subscription::Subscribe(t) => {
+                        Subscription::Subscribe(bytes::Bytes::copy_from_slice(t))
+                    }
+                    Subscription::Cancel(t) => {
+                        Subscription::Cancel(bytes::Bytes::copy_from_slice(t))
+                    }
+                };
+                let mut from_frame = Vec::new();
+                owned.into_frame(version).encode(&mut from_frame).unwrap();
+                assert_eq!(from_frame, buf);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_by_version() {
+        let cmd: Frame<&str, &[u8]> = Frame::Command(Command::SUBSCRIBE(ByteSlice(&b"a"[..])));
+        let msg: Frame<&str, &[u8]> = Frame::Message(Message::new(&b"\x01a"[..]));
+        let data: Frame<&str, &[u8]> = Frame::Message(Message::new(&b"hello"[..]));
+        let empty: Frame<&str, &[u8]> = Frame::Message(Message::new(&b""[..]));
+
+        // 3.0 peers cannot send the commands
+        assert_eq!(Subscription::from_frame(&cmd, &ZMTP_30), None);
+        assert_eq!(
+            Subscription::from_frame(&cmd, &ZMTP_31),
+            Some(Subscription::Subscribe(&b"a"[..]))
+        );
+        assert_eq!(
+            Subscription::from_frame(&msg, &ZMTP_30),
+            Some(Subscription::Subscribe(&b"a"[..]))
+        );
+        assert_eq!(
+            Subscription::from_frame(&msg, &ZMTP_31),
+            Some(Subscription::Subscribe(&b"a"[..]))
+        );
+        assert_eq!(Subscription::from_frame(&data, &ZMTP_30), None);
+        assert_eq!(Subscription::from_frame(&empty, &ZMTP_30), None);
+    }
+}
@@ -0,0 +1,873 @@
+//! The state machine of a single ZMTP connection, independent of any IO.
+//!
+//! Received data is passed in with `Connection::feed`, data to send is taken out with
+//! `Connection::poll_output` and everything the application needs to know about is reported by
+//! `Connection::poll_event`. The same core can thus be driven by async runtimes, blocking sockets
+//! and tests alike.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use bytes::{Bytes, BytesMut};
+use core::convert::TryFrom;
+use core::time::Duration;
+use futures_codec::Decoder;
+
+use zmqrs_parser::{
+    uses_subscription_commands, Assembled, AssemblyError, ByteSlice, Command, ConnectionCodec,
+    ConnectionItem, EncodeError, Frame, Greeting, Limits, MetaData, Multipart, MultipartAssembler,
+    ParserError, Pong, SecurityMechanism, SocketType, Subscription, Version,
+};
+
+use crate::heartbeat::{Clock, Heartbeat, HeartbeatError, HeartbeatOptions};
+
+/// Error reason sent to a peer whose socket type does not match ours.
+pub const INCOMPATIBLE_SOCKET_TYPE: &[u8] = b"Invalid socket type";
+
+/// Largest frame body a `Connection` accepts unless configured otherwise, see `with_limits`.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Which side of the security handshake this end takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// The side that connected.
+    Client,
+    /// The side that accepted the connection.
+    Server,
+}
+
+/// True if the PING and PONG commands can be sent to a peer of this version.
+fn uses_heartbeat_commands(version: &Version) -> bool {
+    version.at_least(3, 1)
+}
+
+/// True if peers of this socket type send subscriptions, which ZMTP 3.0 peers do as messages.
+fn receives_subscriptions(socket_type: SocketType) -> bool {
+    matches!(socket_type, SocketType::PUB | SocketType::XPUB)
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The data of the peer could not be parsed.
+    Parser(ParserError),
+    /// The peer speaks a ZMTP version older than 3.0.
+    UnsupportedVersion { major: u8 },
+    /// The command does not exist in the ZMTP version agreed on with the peer.
+    UnsupportedCommand,
+    /// The peer announced a different security mechanism.
+    MechanismMismatch {
+        ours: SecurityMechanism,
+        peer: SecurityMechanism,
+    },
+    /// The peer did not announce a socket type or one that cannot talk to ours.
+    IncompatibleSocketType,
+    /// The peer sent a command not allowed at this point of the handshake.
+    UnexpectedCommand,
+    /// The peer rejected the handshake with the given reason.
+    Rejected(Bytes),
+    /// The peer sent a command in the middle of a multipart message.
+    Assembly(AssemblyError),
+    /// Data can only be sent once the handshake is done.
+    NotReady,
+    /// The connection failed before and cannot be used anymore.
+    Inoperable,
+    /// The peer stopped answering heartbeats.
+    Heartbeat(HeartbeatError),
+}
+
+impl core::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            ProtocolError::Parser(e) => write!(f, "Parser error: {}", e),
+            ProtocolError::UnsupportedVersion { major } => {
+                write!(f, "Unsupported ZMTP version {}.x, need 3.0 or newer", major)
+            }
+            ProtocolError::UnsupportedCommand => {
+                write!(f, "Command not supported by the ZMTP version of the peer")
+            }
+            ProtocolError::MechanismMismatch { ours, peer } => write!(
+                f,
+                "Security mechanism mismatch: ours is {}, the peer's {}",
+                core::str::from_utf8(ours.name()).unwrap_or("?"),
+                core::str::from_utf8(peer.name()).unwrap_or("?")
+            ),
+            ProtocolError::IncompatibleSocketType => write!(f, "Incompatible socket type"),
+            ProtocolError::UnexpectedCommand => write!(f, "Unexpected command"),
+            ProtocolError::Rejected(reason) => write!(
+                f,
+                "Handshake rejected: {}",
+                core::str::from_utf8(reason).unwrap_or("<invalid reason>")
+            ),
+            ProtocolError::Assembly(e) => write!(f, "{}", e),
+            ProtocolError::NotReady => write!(f, "Handshake not done yet"),
+            ProtocolError::Inoperable => write!(f, "Connection is inoperable"),
+            ProtocolError::Heartbeat(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ParserError> for ProtocolError {
+    fn from(e: ParserError) -> Self {
+        ProtocolError::Parser(e)
+    }
+}
+
+impl From<EncodeError> for ProtocolError {
+    fn from(e: EncodeError) -> Self {
+        ProtocolError::Parser(e.into())
+    }
+}
+
+impl From<HeartbeatError> for ProtocolError {
+    fn from(e: HeartbeatError) -> Self {
+        ProtocolError::Heartbeat(e)
+    }
+}
+
+impl From<AssemblyError> for ProtocolError {
+    fn from(e: AssemblyError) -> Self {
+        ProtocolError::Assembly(e)
+    }
+}
+
+/// What happened on the connection.
+#[derive(Debug)]
+pub enum Event {
+    /// The greeting of the peer arrived.
+    Greeting(Greeting),
+    /// The handshake is done; carries the metadata of the peer.
+    Ready(MetaData<Bytes, Bytes>),
+    /// A complete message arrived.
+    Message(Multipart<Bytes>),
+    /// A command other than those of the handshake arrived, e.g. SUBSCRIBE.
+    ///
+    /// PING and PONG are handled by the connection itself.
+    Command(Command<Bytes, Bytes>),
+    /// The connection cannot be used anymore; close it once the output was sent.
+    Failed(ProtocolError),
+}
+
+enum ProtocolState {
+    /// Before any data exchange.
+    Init,
+    /// The client sends a partial greeting (11 octets) greeting to the server, and at the same time
+    /// (before receiving anything from the client), the server also sends a partial greeting.
+    VersionExchange1,
+    /// The client and server read the major version number (%x03) and send the rest of their
+    /// greeting to each other.
+    VersionExchange2 { other_major_version: u8 },
+
+    /// The client and server now perform the secutity handshake.
+    /// Depending on the security mechanism, there might be _internal_ states.
+    SecurityHandshake,
+
+    /// The client sends a frame with connection metadata, i.e. SocketType
+    /// The server validates the socket type, accepts it
+    MetaDataExchange,
+
+    /// The connection is agreed uppon. Now data is exchanged.
+    WaitingForCommandOrMessage,
+
+    /// Inoperable state - i.e. both sides did not agree on the connection.
+    Inoperable,
+}
+
+/// A ZMTP connection using the NULL security mechanism.
+pub struct Connection {
+    logger: slog::Logger,
+    role: Role,
+    greeting: Greeting,
+    socket_type: SocketType,
+    metadata: MetaData<Bytes, Bytes>,
+    state: ProtocolState,
+    peer_greeting: Option<Greeting>,
+    version: Option<Version>,
+    codec: ConnectionCodec,
+    assembler: MultipartAssembler<Bytes>,
+    heartbeat: Option<Heartbeat<Box<dyn Clock + Send>>>,
+    input: BytesMut,
+    output: BytesMut,
+    events: VecDeque<Event>,
+}
+
+impl Connection {
+    pub fn new<L: Into<slog::Logger>>(role: Role, socket_type: SocketType, logger: L) -> Self {
+        let logger = logger.into();
+        Connection {
+            codec: ConnectionCodec::new(logger.clone())
+                .with_partial_greeting()
+                .with_limits(Limits::unlimited().with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)),
+            logger,
+            role,
+            // as-server is zero for the NULL mechanism
+            greeting: Greeting::default(),
+            socket_type,
+            metadata: MetaData::new().with_socket_type(Bytes::from_static(socket_type.as_bytes())),
+            state: ProtocolState::Init,
+            peer_greeting: None,
+            version: None,
+            assembler: MultipartAssembler::new(),
+            heartbeat: None,
+            input: BytesMut::new(),
+            output: BytesMut::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Send the "Identity" property, e.g. for a REQ or DEALER socket talking to a ROUTER.
+    pub fn with_identity(mut self, identity: Bytes) -> Self {
+        self.metadata = self.metadata.with_identity(identity);
+        self
+    }
+
+    /// Announce a different ZMTP version than 3.1, e.g. 3.0 to act like an older peer.
+    pub fn with_version(mut self, major: u8, minor: u8) -> Self {
+        self.greeting = self.greeting.with_version(major, minor);
+        self
+    }
+
+    /// Limits for the data received from the peer, replacing the default of
+    /// `DEFAULT_MAX_FRAME_SIZE`; pass `Limits::unlimited()` to accept frames of any size.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.codec = self.codec.with_limits(limits);
+        self
+    }
+
+    /// Send heartbeats once the handshake is done; needs a ZMTP 3.1 peer.
+    pub fn with_heartbeat<C: Clock + Send + 'static>(
+        mut self,
+        options: HeartbeatOptions,
+        clock: C,
+    ) -> Self {
+        self.heartbeat = Some(Heartbeat::new(options, Box::new(clock)));
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The greeting of the peer, once it arrived.
+    pub fn peer_greeting(&self) -> Option<&Greeting> {
+        self.peer_greeting.as_ref()
+    }
+
+    /// The ZMTP version both sides agreed on, i.e. the older one of ours and the peer's.
+    ///
+    /// Known once the greeting of the peer arrived.
+    pub fn version(&self) -> Option<&Version> {
+        self.version.as_ref()
+    }
+
+    /// True once the handshake is done and data can be sent.
+    pub fn is_ready(&self) -> bool {
+        matches!(self.state, ProtocolState::WaitingForCommandOrMessage)
+    }
+
+    /// Pass in data received from the peer.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.start();
+        if matches!(self.state, ProtocolState::Inoperable) {
+            return;
+        }
+        self.input.extend_from_slice(data);
+
+        loop {
+            let item = match self.codec.decode(&mut self.input) {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(e) => {
+                    self.fail(e.into());
+                    break;
+                }
+            };
+            if let Err(e) = self.handle(item) {
+                self.fail(e);
+                break;
+            }
+        }
+    }
+
+    /// Data to send to the peer, if any.
+    pub fn poll_output(&mut self) -> Option<Bytes> {
+        self.start();
+        if self.output.is_empty() {
+            None
+        } else {
+            Some(self.output.split().freeze())
+        }
+    }
+
+    /// The next event, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// The time of the heartbeat clock `handle_timeout` needs to be called at, if any.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        match self.state {
+            ProtocolState::WaitingForCommandOrMessage => self.heartbeat.as_ref()?.next_deadline(),
+            _ => None,
+        }
+    }
+
+    /// The time left until `handle_timeout` needs to be called, if any.
+    pub fn time_to_timeout(&self) -> Option<Duration> {
+        match self.state {
+            ProtocolState::WaitingForCommandOrMessage => self.heartbeat.as_ref()?.time_left(),
+            _ => None,
+        }
+    }
+
+    /// Send due heartbeats or fail the connection if the peer went silent.
+    pub fn handle_timeout(&mut self) {
+        if !self.is_ready() {
+            return;
+        }
+        let ping = match self.heartbeat.as_mut().map(Heartbeat::poll) {
+            Some(Ok(Some(ping))) => ping,
+            Some(Err(e)) => return self.fail(e.into()),
+            _ => return,
+        };
+        trace!(self.logger, "sending heartbeat"; "ttl" => ping.ttl);
+        if let Err(e) = self.send_frame(Frame::Command(Command::PING(ping))) {
+            self.fail(e);
+        }
+    }
+
+    /// Queue a message for sending.
+    pub fn send_message(&mut self, msg: Multipart<Bytes>) -> Result<(), ProtocolError> {
+        self.check_ready()?;
+        for part in msg.into_frames() {
+            self.send_frame(Frame::Message(part))?;
+        }
+        Ok(())
+    }
+
+    /// Queue a command for sending, e.g. SUBSCRIBE or PING.
+    ///
+    /// SUBSCRIBE and CANCEL are sent in the format the peer understands, see `send_subscription`.
+    /// PING and PONG fail with `UnsupportedCommand` for ZMTP 3.0 peers.
+    pub fn send_command(&mut self, cmd: Command<Bytes, Bytes>) -> Result<(), ProtocolError> {
+        let version = self.check_ready()?;
+        match cmd {
+            Command::SUBSCRIBE(topic) => self.send_subscription(Subscription::Subscribe(topic.0)),
+            Command::CANCEL(topic) => self.send_subscription(Subscription::Cancel(topic.0)),
+            Command::PING(_) | Command::PONG(_) if !uses_heartbeat_commands(&version) => {
+                Err(ProtocolError::UnsupportedCommand)
+            }
+            cmd => self.send_frame(Frame::Command(cmd)),
+        }
+    }
+
+    /// Queue a subscription for sending, as command for ZMTP 3.1 peers and as message for
+    /// ZMTP 3.0 peers.
+    pub fn send_subscription(
+        &mut self,
+        subscription: Subscription<Bytes>,
+    ) -> Result<(), ProtocolError> {
+        let version = self.check_ready()?;
+        self.send_frame(subscription.into_frame(&version))
+    }
+
+    /// Returns the agreed on version if data can be sent.
+    fn check_ready(&self) -> Result<Version, ProtocolError> {
+        match (&self.state, &self.version) {
+            (ProtocolState::WaitingForCommandOrMessage, Some(version)) => Ok(version.clone()),
+            (ProtocolState::Inoperable, _) => Err(ProtocolError::Inoperable),
+            _ => Err(ProtocolError::NotReady),
+        }
+    }
+
+    fn send_frame(&mut self, frame: Frame<Bytes, Bytes>) -> Result<(), ProtocolError> {
+        self.output.reserve(frame.encoded_len());
+        frame.encode(&mut self.output)?;
+        Ok(())
+    }
+
+    /// Send the partial greeting, unless that happened already.
+    fn start(&mut self) {
+        if let ProtocolState::Init = self.state {
+            self.greeting.encode_partial(&mut self.output);
+            self.state = ProtocolState::VersionExchange1;
+        }
+    }
+
+    fn fail(&mut self, e: ProtocolError) {
+        warn!(self.logger, "connection failed"; "error" => %e);
+        self.state = ProtocolState::Inoperable;
+        self.events.push_back(Event::Failed(e));
+    }
+
+    fn handle(&mut self, item: ConnectionItem) -> Result<(), ProtocolError> {
+        match (&self.state, item) {
+            (ProtocolState::VersionExchange1, ConnectionItem::MajorVersion(major)) => {
+                // ZMTP 1.0 and 2.0 peers cannot be talked to, so don't send them the rest of the
+                // greeting.
+                if major < 3 {
+                    return Err(ProtocolError::UnsupportedVersion { major });
+                }
+                self.greeting.encode_remaining(&mut self.output);
+                self.state = ProtocolState::VersionExchange2 {
+                    other_major_version: major,
+                };
+                Ok(())
+            }
+            (
+                ProtocolState::VersionExchange2 {
+                    other_major_version,
+                },
+                ConnectionItem::Greeting(greeting),
+            ) => {
+                debug_assert_eq!(greeting.version.major, *other_major_version);
+                self.peer_greeting = Some(greeting.clone());
+                if greeting.mechanism != self.greeting.mechanism {
+                    return Err(ProtocolError::MechanismMismatch {
+                        ours: self.greeting.mechanism.clone(),
+                        peer: greeting.mechanism,
+                    });
+                }
+                let version = if greeting
+                    .version
+                    .at_least(self.greeting.version.major, self.greeting.version.minor)
+                {
+                    self.greeting.version.clone()
+                } else {
+                    greeting.version.clone()
+                };
+                debug!(self.logger, "version negotiated"; "version" => &version);
+                self.version = Some(version);
+                self.events.push_back(Event::Greeting(greeting));
+                self.state = ProtocolState::SecurityHandshake;
+                self.security_handshake()
+            }
+            (ProtocolState::MetaDataExchange, ConnectionItem::Frame(frame)) => match frame {
+                Frame::Command(Command::READY(peer)) => {
+                    self.check_socket_type(&peer)?;
+                    debug!(self.logger, "handshake done"; "role" => ?self.role);
+                    self.start_heartbeat();
+                    self.state = ProtocolState::WaitingForCommandOrMessage;
+                    self.events.push_back(Event::Ready(peer));
+                    Ok(())
+                }
+                Frame::Command(Command::ERROR(reason)) => Err(ProtocolError::Rejected(reason.0)),
+                _ => Err(ProtocolError::UnexpectedCommand),
+            },
+            (ProtocolState::WaitingForCommandOrMessage, ConnectionItem::Frame(frame)) => {
+                if let Some(heartbeat) = self.heartbeat.as_mut() {
+                    heartbeat.received();
+                }
+                if let Some(cmd) = self.legacy_subscription(&frame) {
+                    self.events.push_back(Event::Command(cmd));
+                    return Ok(());
+                }
+                match self.assembler.push(frame)? {
+                    Some(Assembled::Message(msg)) => self.events.push_back(Event::Message(msg)),
+                    Some(Assembled::Command(Command::PING(ping))) => {
+                        let pong = match self.heartbeat.as_mut() {
+                            Some(heartbeat) => heartbeat.handle_ping(ping),
+                            None => Pong {
+                                context: ping.context,
+                            },
+                        };
+                        self.send_frame(Frame::Command(Command::PONG(pong)))?;
+                    }
+                    Some(Assembled::Command(Command::PONG(_))) => {}
+                    Some(Assembled::Command(cmd)) => self.events.push_back(Event::Command(cmd)),
+                    None => {}
+                }
+                Ok(())
+            }
+            (_, _) => Err(ProtocolError::UnexpectedCommand),
+        }
+    }
+
+    /// The command a ZMTP 3.1 peer would have sent instead of this subscription message.
+    fn legacy_subscription(&self, frame: &Frame<Bytes, Bytes>) -> Option<Command<Bytes, Bytes>> {
+        let version = self.version.as_ref()?;
+        if uses_subscription_commands(version)
+            || !receives_subscriptions(self.socket_type)
+            || self.assembler.in_message()
+        {
+            return None;
+        }
+        let data = match frame {
+            Frame::Message(msg) => msg.data(),
+            Frame::Command(_) => return None,
+        };
+        // the topic follows the marker
+        let topic = ByteSlice(data.slice(1..));
+        match Subscription::from_frame(frame, version)? {
+            Subscription::Subscribe(_) => Some(Command::SUBSCRIBE(topic)),
+            Subscription::Cancel(_) => Some(Command::CANCEL(topic)),
+        }
+    }
+
+    /// Start the heartbeat timers, unless the peer doesn't know PING and PONG.
+    fn start_heartbeat(&mut self) {
+        let supported = self.version.as_ref().is_some_and(uses_heartbeat_commands);
+        match self.heartbeat.as_mut() {
+            Some(heartbeat) if supported => heartbeat.start(),
+            Some(_) => {
+                debug!(self.logger, "heartbeats disabled, the peer speaks ZMTP 3.0");
+                self.heartbeat = None;
+            }
+            None => {}
+        }
+    }
+
+    /// The NULL mechanism has no handshake of its own; continue with the metadata.
+    fn security_handshake(&mut self) -> Result<(), ProtocolError> {
+        self.send_frame(Frame::Command(Command::READY(self.metadata.clone())))?;
+        self.state = ProtocolState::MetaDataExchange;
+        Ok(())
+    }
+
+    /// Reject peers of the wrong socket type with an ERROR command.
+    fn check_socket_type(&mut self, peer: &MetaData<Bytes, Bytes>) -> Result<(), ProtocolError> {
+        let compatible = peer
+            .socket_type()
+            .and_then(|name| SocketType::try_from(name.as_ref()).ok())
+            .is_some_and(|peer| self.socket_type.is_compatible_with(peer));
+        if compatible {
+            Ok(())
+        } else {
+            self.send_frame(Frame::Command(Command::ERROR(ByteSlice(
+                Bytes::from_static(INCOMPATIBLE_SOCKET_TYPE),
+            ))))?;
+            Err(ProtocolError::IncompatibleSocketType)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heartbeat::ManualClock;
+    use zmqrs_parser::Ping;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// Pass all pending output of `from` to `to`.
+    fn transfer(from: &mut Connection, to: &mut Connection) {
+        while let Some(data) = from.poll_output() {
+            to.feed(&data);
+        }
+    }
+
+    fn events(conn: &mut Connection) -> Vec<Event> {
+        core::iter::from_fn(|| conn.poll_event()).collect()
+    }
+
+    fn handshake(client: &mut Connection, server: &mut Connection) {
+        for _ in 0..3 {
+            transfer(client, server);
+            transfer(server, client);
+        }
+    }
+
+    #[test]
+    fn null_handshake_and_messages() {
+        let mut client =
+            Connection::new(Role::Client, SocketType::REQ, logger()).with_identity(Bytes::new());
+        let mut server = Connection::new(Role::Server, SocketType::REP, logger());
+        assert!(client.send_message(Multipart::new()).is_err());
+
+        handshake(&mut client, &mut server);
+        assert!(client.is_ready());
+        assert!(server.is_ready());
+
+        match &events(&mut server)[..] {
+            [Event::Greeting(greeting), Event::Ready(peer)] => {
+                assert_eq!(greeting, &Greeting::default());
+                assert_eq!(peer.socket_type(), Some(&Bytes::from_static(b"REQ")));
+                assert_eq!(peer.identity(), Some(&Bytes::new()));
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+        match &events(&mut client)[..] {
+            [Event::Greeting(_), Event::Ready(peer)] => {
+                assert_eq!(peer.socket_type(), Some(&Bytes::from_static(b"REP")))
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        let request = Multipart::from(vec![Bytes::new(), Bytes::from_static(b"Hello")]);
+        client.send_message(request.clone()).unwrap();
+        transfer(&mut client, &mut server);
+        match &events(&mut server)[..] {
+            [Event::Message(msg)] => assert_eq!(msg, &request),
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_with_byte_wise_input() {
+        let mut client = Connection::new(Role::Client, SocketType::DEALER, logger());
+        let mut server = Connection::new(Role::Server, SocketType::ROUTER, logger());
+
+        for _ in 0..3 {
+            while let Some(data) = client.poll_output() {
+                for octet in data.iter() {
+                    server.feed(&[*octet]);
+                }
+            }
+            transfer(&mut server, &mut client);
+        }
+        assert!(client.is_ready());
+        assert!(server.is_ready());
+    }
+
+    #[test]
+    fn libzmq_req_client() {
+        // greeting and READY as sent by the hello_world client
+        let mut server = Connection::new(Role::Server, SocketType::REP, logger());
+        server.feed(b"\xff\x00\x00\x00\x00\x00\x00\x00\x01\x7f\x03");
+        let mut rest = Vec::new();
+        rest.push(1u8);
+        rest.extend_from_slice(b"NULL");
+        rest.extend_from_slice(&[0u8; 16 + 1 + 31]);
+        server.feed(&rest);
+        server.feed(
+            b"\x04\x26\x05READY\x0bSocket-Type\x00\x00\x00\x03REQ\x08Identity\x00\x00\x00\x00",
+        );
+        server.feed(b"\x01\x00\x00\x05Hello");
+        assert!(server.is_ready());
+
+        let mut output = Vec::new();
+        while let Some(data) = server.poll_output() {
+            output.extend_from_slice(&data);
+        }
+        assert_eq!(output.len(), 64 + 27);
+        assert_eq!(
+            &output[64..],
+            &b"\x04\x19\x05READY\x0bSocket-Type\x00\x00\x00\x03REP"[..]
+        );
+
+        let events = events(&mut server);
+        match events.last() {
+            Some(Event::Message(msg)) => {
+                assert_eq!(
+                    msg.parts(),
+                    &[Bytes::new(), Bytes::from_static(b"Hello")][..]
+                )
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn incompatible_socket_types() {
+        let mut client = Connection::new(Role::Client, SocketType::REQ, logger());
+        let mut server = Connection::new(Role::Server, SocketType::PUB, logger());
+        handshake(&mut client, &mut server);
+
+        assert!(!server.is_ready());
+        match events(&mut server).last() {
+            Some(Event::Failed(ProtocolError::IncompatibleSocketType)) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+        match events(&mut client).last() {
+            Some(Event::Failed(ProtocolError::IncompatibleSocketType))
+            | Some(Event::Failed(ProtocolError::Rejected(_))) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(server.send_message(Multipart::new()).is_err());
+    }
+
+    fn negotiate(client_minor: u8, server_minor: u8) -> (Connection, Connection) {
+        let mut client =
+            Connection::new(Role::Client, SocketType::SUB, logger()).with_version(3, client_minor);
+        let mut server =
+            Connection::new(Role::Server, SocketType::PUB, logger()).with_version(3, server_minor);
+        handshake(&mut client, &mut server);
+        assert!(client.is_ready());
+        assert!(server.is_ready());
+        (client, server)
+    }
+
+    #[test]
+    fn downgrade_to_zmtp_30() {
+        let zmtp_30 = Version { major: 3, minor: 0 };
+        for (client_minor, server_minor) in [(0, 1), (1, 0)] {
+            let (mut client, mut server) = negotiate(client_minor, server_minor);
+            assert_eq!(client.version(), Some(&zmtp_30));
+            assert_eq!(server.version(), Some(&zmtp_30));
+            events(&mut server);
+
+            // subscriptions are sent as messages, but reported like those of ZMTP 3.1 peers
+            client
+                .send_command(Command::SUBSCRIBE(ByteSlice(Bytes::from_static(b"topic"))))
+                .unwrap();
+            client
+                .send_command(Command::CANCEL(ByteSlice(Bytes::from_static(b"topic"))))
+                .unwrap();
+            client
+                .send_message(Multipart::from(vec![Bytes::from_static(b"\x02other")]))
+                .unwrap();
+            transfer(&mut client, &mut server);
+            match &events(&mut server)[..] {
+                [Event::Command(Command::SUBSCRIBE(subscribed)), Event::Command(Command::CANCEL(cancelled)), Event::Message(msg)] =>
+                {
+                    assert_eq!(subscribed.0, Bytes::from_static(b"topic"));
+                    assert_eq!(cancelled.0, Bytes::from_static(b"topic"));
+                    assert_eq!(msg.parts(), &[Bytes::from_static(b"\x02other")][..])
+                }
+                other => panic!("unexpected events {:?}", other),
+            }
+
+            // there is no PING in ZMTP 3.0
+            match client.send_command(Command::PING(Ping {
+                ttl: 0,
+                context: Bytes::new(),
+            })) {
+                Err(ProtocolError::UnsupportedCommand) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn zmtp_31_commands() {
+        let (mut client, mut server) = negotiate(1, 1);
+        assert_eq!(client.version(), Some(&Version { major: 3, minor: 1 }));
+        events(&mut server);
+
+        client
+            .send_subscription(Subscription::Subscribe(Bytes::from_static(b"topic")))
+            .unwrap();
+        client
+            .send_command(Command::PING(Ping {
+                ttl: 0,
+                context: Bytes::from_static(b"ctx"),
+            }))
+            .unwrap();
+        transfer(&mut client, &mut server);
+        match &events(&mut server)[..] {
+            [Event::Command(Command::SUBSCRIBE(topic))] => {
+                assert_eq!(topic.0, Bytes::from_static(b"topic"))
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        // the PING was answered
+        assert_eq!(
+            server.poll_output(),
+            Some(Bytes::from_static(b"\x04\x08\x04PONGctx"))
+        );
+    }
+
+    #[test]
+    fn newer_peer_uses_our_version() {
+        let mut server = Connection::new(Role::Server, SocketType::REP, logger());
+        let mut greeting = Vec::new();
+        Greeting::default().with_version(4, 2).encode(&mut greeting);
+        server.feed(&greeting);
+        assert_eq!(server.version(), Some(&Version { major: 3, minor: 1 }));
+    }
+
+    #[test]
+    fn huge_frames_are_rejected_by_default() {
+        let mut server = Connection::new(Role::Server, SocketType::REP, logger());
+        let mut greeting = Vec::new();
+        Greeting::default().encode(&mut greeting);
+        server.feed(&greeting);
+        events(&mut server);
+
+        // a long command header announcing an exabyte sized frame
+        server.feed(b"\x06\x10\x00\x00\x00\x00\x00\x00\x00");
+        match &events(&mut server)[..] {
+            [Event::Failed(ProtocolError::Parser(ParserError::FrameTooLarge { .. }))] => {}
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_zmtp_20() {
+        let mut server = Connection::new(Role::Server, SocketType::REP, logger());
+        server.feed(b"\xff\x00\x00\x00\x00\x00\x00\x00\x01\x7f\x01");
+
+        match events(&mut server).last() {
+            Some(Event::Failed(ProtocolError::UnsupportedVersion { major: 1 })) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+        // only the partial greeting went out
+        assert_eq!(server.poll_output().map(|data| data.len()), Some(11));
+        assert!(server.version().is_none());
+    }
+
+    #[test]
+    fn heartbeats() {
+        let clock = ManualClock::new();
+        let options = HeartbeatOptions::default()
+            .with_interval(Duration::from_secs(1))
+            .with_ttl(Duration::from_secs(5));
+        let mut client = Connection::new(Role::Client, SocketType::DEALER, logger())
+            .with_heartbeat(options.clone(), clock.clone());
+        let mut server = Connection::new(Role::Server, SocketType::ROUTER, logger())
+            .with_heartbeat(HeartbeatOptions::default(), clock.clone());
+        assert_eq!(client.poll_timeout(), None);
+        handshake(&mut client, &mut server);
+        events(&mut client);
+        events(&mut server);
+        assert_eq!(client.poll_timeout(), Some(Duration::from_secs(1)));
+        assert_eq!(server.poll_timeout(), None);
+
+        // the server answers the PING by itself and honours the TTL
+        clock.advance(Duration::from_secs(1));
+        client.handle_timeout();
+        transfer(&mut client, &mut server);
+        assert!(events(&mut server).is_empty());
+        assert_eq!(server.poll_timeout(), Some(Duration::from_secs(6)));
+        transfer(&mut server, &mut client);
+        assert!(events(&mut client).is_empty());
+        assert_eq!(client.poll_timeout(), Some(Duration::from_secs(2)));
+
+        // the server goes silent
+        clock.advance(Duration::from_secs(1));
+        client.handle_timeout();
+        clock.advance(Duration::from_secs(1));
+        client.handle_timeout();
+        match events(&mut client).last() {
+            Some(Event::Failed(ProtocolError::Heartbeat(HeartbeatError::Timeout))) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(!client.is_ready());
+
+        // and so does the client
+        clock.advance(Duration::from_secs(3));
+        server.handle_timeout();
+        match events(&mut server).last() {
+            Some(Event::Failed(ProtocolError::Heartbeat(HeartbeatError::TtlExpired))) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn no_heartbeats_with_zmtp_30() {
+        let options = HeartbeatOptions::default().with_interval(Duration::from_secs(1));
+        let mut client = Connection::new(Role::Client, SocketType::DEALER, logger())
+            .with_heartbeat(options, ManualClock::new());
+        let mut server =
+            Connection::new(Role::Server, SocketType::ROUTER, logger()).with_version(3, 0);
+        handshake(&mut client, &mut server);
+        assert!(client.is_ready());
+        assert_eq!(client.poll_timeout(), None);
+    }
+
+    #[test]
+    fn mechanism_mismatch() {
+        let mut server = Connection::new(Role::Server, SocketType::REP, logger());
+        let mut greeting = Vec::new();
+        Greeting::default()
+            .with_mechanism(SecurityMechanism::PLAIN)
+            .encode(&mut greeting);
+        server.feed(&greeting);
+
+        match events(&mut server).last() {
+            Some(Event::Failed(ProtocolError::MechanismMismatch { .. })) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,914 @@
+//! Handshake and encryption of the CURVE security mechanism, see https://rfc.zeromq.org/spec/26/
+//!
+//! Client and server each own a permanent key pair and the client knows the server's permanent
+//! public key in advance. During the handshake both create transient key pairs, which are used
+//! for the boxes of READY and of all MESSAGE commands. The server keeps no state between HELLO and
+//! INITIATE besides the cookie key; everything else is recovered from the cookie.
+//!
+//! Boxes use crypto_box and secretbox of NaCl, i.e. X25519 and XSalsa20Poly1305.
+
+use alloc::vec::Vec;
+use bytes::Bytes;
+use crypto_box::aead::{consts::U24, Aead, AeadCore, KeyInit};
+use crypto_box::rand_core::{CryptoRng, RngCore};
+use crypto_box::{Nonce, PublicKey, SalsaBox, SecretKey};
+use xsalsa20poly1305::XSalsa20Poly1305;
+
+use zmqrs_parser::{
+    frame_body, properties, ByteSlice, Command, CurveCommand, CurveHello, CurveInitiate,
+    CurveMessage, CurveReady, CurveWelcome, EncodeError, Frame, FrameHeader, Limits, MetaData,
+    SecurityMechanism, CURVE_COOKIE_LEN, CURVE_KEY_LEN, CURVE_LONG_NONCE_LEN,
+};
+
+/// Error reason the server sends if the client's permanent key is rejected.
+pub const INVALID_CLIENT_KEY: &[u8] = b"Invalid client key";
+
+const HELLO_NONCE_PREFIX: &[u8; 16] = b"CurveZMQHELLO---";
+const WELCOME_NONCE_PREFIX: &[u8; 8] = b"WELCOME-";
+const COOKIE_NONCE_PREFIX: &[u8; 8] = b"COOKIE--";
+const VOUCH_NONCE_PREFIX: &[u8; 8] = b"VOUCH---";
+const INITIATE_NONCE_PREFIX: &[u8; 16] = b"CurveZMQINITIATE";
+const READY_NONCE_PREFIX: &[u8; 16] = b"CurveZMQREADY---";
+const CLIENT_MESSAGE_NONCE_PREFIX: &[u8; 16] = b"CurveZMQMESSAGEC";
+const SERVER_MESSAGE_NONCE_PREFIX: &[u8; 16] = b"CurveZMQMESSAGES";
+
+/// The hello box holds 64 zero octets, so that the box is as long as the welcome box.
+const HELLO_PLAINTEXT_LEN: usize = 64;
+/// Length of the vouch: long nonce and the box of both transient keys.
+const VOUCH_LEN: usize = CURVE_COOKIE_LEN;
+
+/// Flags of the frame carried in a message box.
+const MESSAGE_FLAG_MORE: u8 = 0x01;
+const MESSAGE_FLAG_COMMAND: u8 = 0x02;
+
+type LongNonce = [u8; CURVE_LONG_NONCE_LEN];
+
+/// A permanent or transient key pair.
+#[derive(Clone)]
+pub struct CurveKeyPair {
+    public: PublicKey,
+    secret: SecretKey,
+}
+
+impl core::fmt::Debug for CurveKeyPair {
+    /// Shows the public key only.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.debug_struct("CurveKeyPair")
+            .field("public", self.public.as_bytes())
+            .finish()
+    }
+}
+
+impl CurveKeyPair {
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        Self::from_secret_key(SecretKey::generate(rng))
+    }
+
+    /// The key pair belonging to a stored secret key.
+    pub fn from_secret(secret: [u8; CURVE_KEY_LEN]) -> Self {
+        Self::from_secret_key(SecretKey::from(secret))
+    }
+
+    fn from_secret_key(secret: SecretKey) -> Self {
+        CurveKeyPair {
+            public: secret.public_key(),
+            secret,
+        }
+    }
+
+    pub fn public(&self) -> &[u8; CURVE_KEY_LEN] {
+        self.public.as_bytes()
+    }
+}
+
+/// Decides whether a client with the given permanent public key may connect.
+///
+/// Closures `FnMut(&[u8; 32]) -> bool` implement this trait.
+pub trait CurveAuthenticator {
+    fn authenticate(&mut self, client_key: &[u8; CURVE_KEY_LEN]) -> bool;
+}
+
+impl<F: FnMut(&[u8; CURVE_KEY_LEN]) -> bool> CurveAuthenticator for F {
+    fn authenticate(&mut self, client_key: &[u8; CURVE_KEY_LEN]) -> bool {
+        self(client_key)
+    }
+}
+
+/// What to do after a command of the peer was handled.
+#[derive(Debug)]
+pub enum CurveStep {
+    /// Send the command and wait for the next command of the peer.
+    Send(Command<Bytes, Bytes>),
+    /// The handshake is complete; send `reply` if present. `peer` is the metadata of the peer,
+    /// all further frames are sent and received through `session`.
+    Done {
+        reply: Option<Command<Bytes, Bytes>>,
+        peer: MetaData<Bytes, Bytes>,
+        session: CurveSession,
+    },
+    /// The handshake failed; send the ERROR command and close the connection.
+    Close(Command<Bytes, Bytes>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurveError {
+    /// The peer sent a command that is not allowed at this point of the handshake.
+    UnexpectedCommand { expected: &'static str },
+    /// The server rejected the handshake with the given reason.
+    Rejected(Bytes),
+    /// A box could not be opened, i.e. it was not created with the expected keys or modified.
+    Unauthenticated,
+    /// A short nonce was not larger than the previous one of the peer.
+    ReusedNonce,
+    /// The content of an opened box does not match its definition.
+    MalformedBox,
+    /// Our metadata or frame can't be encoded.
+    Encode(EncodeError),
+}
+
+impl core::fmt::Display for CurveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            CurveError::UnexpectedCommand { expected } => {
+                write!(f, "Unexpected command, expected {}", expected)
+            }
+            CurveError::Rejected(reason) => write!(
+                f,
+                "Handshake rejected: {}",
+                core::str::from_utf8(reason).unwrap_or("<invalid reason>")
+            ),
+            CurveError::Unauthenticated => write!(f, "Box could not be opened"),
+            CurveError::ReusedNonce => write!(f, "Nonce was used before"),
+            CurveError::MalformedBox => write!(f, "Malformed box content"),
+            CurveError::Encode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<EncodeError> for CurveError {
+    fn from(e: EncodeError) -> Self {
+        CurveError::Encode(e)
+    }
+}
+
+fn short_nonce(prefix: &[u8; 16], nonce: u64) -> Nonce {
+    let mut full = [0u8; 24];
+    full[..16].copy_from_slice(prefix);
+    full[16..].copy_from_slice(&nonce.to_be_bytes());
+    full.into()
+}
+
+fn long_nonce(prefix: &[u8; 8], nonce: &[u8]) -> Nonce {
+    let mut full = [0u8; 24];
+    full[..8].copy_from_slice(prefix);
+    full[8..].copy_from_slice(nonce);
+    full.into()
+}
+
+fn seal<C: Aead + AeadCore<NonceSize = U24>>(
+    cipher: &C,
+    nonce: &Nonce,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    cipher
+        .encrypt(nonce, plaintext)
+        .expect("encryption into a Vec cannot fail")
+}
+
+fn open<C: Aead + AeadCore<NonceSize = U24>>(
+    cipher: &C,
+    nonce: &Nonce,
+    sealed: &[u8],
+) -> Result<Vec<u8>, CurveError> {
+    cipher
+        .decrypt(nonce, sealed)
+        .map_err(|_| CurveError::Unauthenticated)
+}
+
+fn public_key(bytes: &[u8]) -> Result<PublicKey, CurveError> {
+    let mut key = [0u8; CURVE_KEY_LEN];
+    if bytes.len() != CURVE_KEY_LEN {
+        return Err(CurveError::MalformedBox);
+    }
+    key.copy_from_slice(bytes);
+    Ok(PublicKey::from(key))
+}
+
+fn metadata(plaintext: Vec<u8>) -> Result<MetaData<Bytes, Bytes>, CurveError> {
+    let mut logger = slog::Logger::root(slog::Discard, slog::o!());
+    let buffer = Bytes::from(plaintext);
+    let (_, md) = properties(&buffer, &Limits::unlimited(), &mut logger)
+        .map_err(|_| CurveError::MalformedBox)?;
+    Ok((&buffer, md).into())
+}
+
+fn encode_metadata(metadata: &MetaData<Bytes, Bytes>) -> Result<Vec<u8>, CurveError> {
+    let mut plaintext = Vec::with_capacity(metadata.encoded_len());
+    metadata.encode(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// Short nonces of one direction; they start at 1 and strictly increase.
+#[derive(Debug, Clone, Copy, Default)]
+struct NonceCounter(u64);
+
+impl NonceCounter {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+
+    /// Check that `nonce` is larger than all nonces seen before.
+    fn check(&self, nonce: u64) -> Result<(), CurveError> {
+        if nonce > self.0 {
+            Ok(())
+        } else {
+            Err(CurveError::ReusedNonce)
+        }
+    }
+
+    /// Remember `nonce` of a box that was opened, i.e. authenticated.
+    ///
+    /// Only then, so that a forged nonce can't make the genuine ones look reused.
+    fn accept(&mut self, nonce: u64) {
+        self.0 = nonce;
+    }
+}
+
+/// Encryption of the frames exchanged after the handshake.
+pub struct CurveSession {
+    cipher: SalsaBox,
+    send_prefix: &'static [u8; 16],
+    receive_prefix: &'static [u8; 16],
+    sent: NonceCounter,
+    received: NonceCounter,
+}
+
+impl core::fmt::Debug for CurveSession {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.debug_struct("CurveSession")
+            .field("sent", &self.sent)
+            .field("received", &self.received)
+            .finish()
+    }
+}
+
+impl CurveSession {
+    /// Put a frame into a MESSAGE command.
+    pub fn encrypt(
+        &mut self,
+        frame: &Frame<Bytes, Bytes>,
+    ) -> Result<Command<Bytes, Bytes>, CurveError> {
+        let hdr = frame.header();
+        let mut plaintext = Vec::with_capacity(1 + hdr.frame_length);
+        let mut flags = 0;
+        if hdr.flags.more_frames_to_follow {
+            flags |= MESSAGE_FLAG_MORE;
+        }
+        if hdr.flags.is_command {
+            flags |= MESSAGE_FLAG_COMMAND;
+        }
+        plaintext.push(flags);
+        match frame {
+            Frame::Command(cmd) => cmd.encode(&mut plaintext)?,
+            Frame::Message(msg) => msg.encode(&mut plaintext),
+        }
+
+        let nonce = self.sent.next();
+        let message_box = seal(
+            &self.cipher,
+            &short_nonce(self.send_prefix, nonce),
+            &plaintext,
+        );
+        Ok(Command::CURVE(CurveCommand::MESSAGE(CurveMessage {
+            nonce,
+            message_box: message_box.into(),
+        })))
+    }
+
+    /// Open a MESSAGE command of the peer and parse the frame it carries.
+    pub fn decrypt(
+        &mut self,
+        message: &CurveMessage<Bytes>,
+    ) -> Result<Frame<Bytes, Bytes>, CurveError> {
+        self.received.check(message.nonce)?;
+        let plaintext = open(
+            &self.cipher,
+            &short_nonce(self.receive_prefix, message.nonce),
+            &message.message_box,
+        )?;
+        self.received.accept(message.nonce);
+
+        let buffer = Bytes::from(plaintext);
+        let flags = *buffer.first().ok_or(CurveError::MalformedBox)?;
+        let body = &buffer[1..];
+        let hdr = FrameHeader::new(
+            flags & MESSAGE_FLAG_COMMAND != 0,
+            flags & MESSAGE_FLAG_MORE != 0,
+            body.len(),
+        );
+        let mut logger = slog::Logger::root(slog::Discard, slog::o!());
+        let (_, frame) = frame_body(
+            body,
+            &hdr,
+            &SecurityMechanism::NULL,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .map_err(|_| CurveError::MalformedBox)?;
+        Ok((&buffer, frame).into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClientState {
+    SendHello,
+    ExpectWelcome,
+    ExpectReady,
+    Done,
+}
+
+/// Client side of the CURVE handshake.
+pub struct CurveClient {
+    state: ClientState,
+    permanent: CurveKeyPair,
+    transient: CurveKeyPair,
+    server_key: PublicKey,
+    /// Created with the server's transient key once WELCOME arrived.
+    cipher: Option<SalsaBox>,
+    vouch_nonce: LongNonce,
+    metadata: MetaData<Bytes, Bytes>,
+    sent: NonceCounter,
+}
+
+impl core::fmt::Debug for CurveClient {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.debug_struct("CurveClient")
+            .field("state", &self.state)
+            .field("permanent", &self.permanent)
+            .finish()
+    }
+}
+
+impl CurveClient {
+    /// `server_key` is the server's permanent public key, `metadata` is sent with the INITIATE
+    /// command.
+    pub fn new<R: RngCore + CryptoRng>(
+        permanent: CurveKeyPair,
+        server_key: [u8; CURVE_KEY_LEN],
+        metadata: MetaData<Bytes, Bytes>,
+        rng: &mut R,
+    ) -> Self {
+        let mut vouch_nonce = LongNonce::default();
+        rng.fill_bytes(&mut vouch_nonce);
+        CurveClient {
+            state: ClientState::SendHello,
+            permanent,
+            transient: CurveKeyPair::generate(rng),
+            server_key: PublicKey::from(server_key),
+            cipher: None,
+            vouch_nonce,
+            metadata,
+            sent: NonceCounter::default(),
+        }
+    }
+
+    /// The HELLO command starting the handshake.
+    pub fn hello(&mut self) -> Command<Bytes, Bytes> {
+        self.state = ClientState::ExpectWelcome;
+        let nonce = self.sent.next();
+        let cipher = SalsaBox::new(&self.server_key, &self.transient.secret);
+        let hello_box = seal(
+            &cipher,
+            &short_nonce(HELLO_NONCE_PREFIX, nonce),
+            &[0u8; HELLO_PLAINTEXT_LEN],
+        );
+        Command::CURVE(CurveCommand::HELLO(CurveHello {
+            client_key: Bytes::copy_from_slice(self.transient.public()),
+            nonce,
+            hello_box: hello_box.into(),
+        }))
+    }
+
+    /// Handle the next command of the server.
+    pub fn handle(&mut self, cmd: Command<Bytes, Bytes>) -> Result<CurveStep, CurveError> {
+        match (self.state, cmd) {
+            (ClientState::ExpectWelcome, Command::CURVE(CurveCommand::WELCOME(welcome))) => {
+                let initiate = self.initiate(&welcome)?;
+                self.state = ClientState::ExpectReady;
+                Ok(CurveStep::Send(initiate))
+            }
+            (ClientState::ExpectReady, Command::CURVE(CurveCommand::READY(ready))) => {
+                let peer = self.ready(&ready)?;
+                self.state = ClientState::Done;
+                let session = CurveSession {
+                    cipher: self.cipher.take().ok_or(CurveError::MalformedBox)?,
+                    send_prefix: CLIENT_MESSAGE_NONCE_PREFIX,
+                    receive_prefix: SERVER_MESSAGE_NONCE_PREFIX,
+                    sent: self.sent,
+                    received: NonceCounter(ready.nonce),
+                };
+                Ok(CurveStep::Done {
+                    reply: None,
+                    peer,
+                    session,
+                })
+            }
+            (ClientState::ExpectWelcome, Command::ERROR(reason))
+            | (ClientState::ExpectReady, Command::ERROR(reason)) => {
+                self.state = ClientState::Done;
+                Err(CurveError::Rejected(reason.0))
+            }
+            (ClientState::ExpectWelcome, _) => Err(CurveError::UnexpectedCommand {
+                expected: "WELCOME",
+            }),
+            (ClientState::ExpectReady, _) => {
+                Err(CurveError::UnexpectedCommand { expected: "READY" })
+            }
+            (ClientState::SendHello, _) | (ClientState::Done, _) => {
+                Err(CurveError::UnexpectedCommand { expected: "none" })
+            }
+        }
+    }
+
+    /// Open the welcome box and vouch for the transient key.
+    fn initiate(
+        &mut self,
+        welcome: &CurveWelcome<Bytes>,
+    ) -> Result<Command<Bytes, Bytes>, CurveError> {
+        let cipher = SalsaBox::new(&self.server_key, &self.transient.secret);
+        let plaintext = open(
+            &cipher,
+            &long_nonce(WELCOME_NONCE_PREFIX, &welcome.nonce),
+            &welcome.welcome_box,
+        )?;
+        let (server_transient, cookie) = plaintext.split_at(CURVE_KEY_LEN);
+        let server_transient = public_key(server_transient)?;
+
+        // vouch = long nonce + Box [C',S](C->S')
+        let mut vouch = Vec::with_capacity(VOUCH_LEN);
+        vouch.extend_from_slice(&self.vouch_nonce);
+        let mut vouched = Vec::with_capacity(2 * CURVE_KEY_LEN);
+        vouched.extend_from_slice(self.transient.public());
+        vouched.extend_from_slice(self.server_key.as_bytes());
+        vouch.extend(seal(
+            &SalsaBox::new(&server_transient, &self.permanent.secret),
+            &long_nonce(VOUCH_NONCE_PREFIX, &self.vouch_nonce),
+            &vouched,
+        ));
+
+        let mut plaintext = Vec::new();
+        plaintext.extend_from_slice(self.permanent.public());
+        plaintext.extend(vouch);
+        plaintext.extend(encode_metadata(&self.metadata)?);
+
+        let cipher = SalsaBox::new(&server_transient, &self.transient.secret);
+        let nonce = self.sent.next();
+        let initiate_box = seal(
+            &cipher,
+            &short_nonce(INITIATE_NONCE_PREFIX, nonce),
+            &plaintext,
+        );
+        self.cipher = Some(cipher);
+
+        Ok(Command::CURVE(CurveCommand::INITIATE(CurveInitiate {
+            cookie: Bytes::copy_from_slice(cookie),
+            nonce,
+            initiate_box: initiate_box.into(),
+        })))
+    }
+
+    fn ready(&mut self, ready: &CurveReady<Bytes>) -> Result<MetaData<Bytes, Bytes>, CurveError> {
+        let cipher = self.cipher.as_ref().ok_or(CurveError::MalformedBox)?;
+        let plaintext = open(
+            cipher,
+            &short_nonce(READY_NONCE_PREFIX, ready.nonce),
+            &ready.ready_box,
+        )?;
+        metadata(plaintext)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerState {
+    ExpectHello,
+    ExpectInitiate,
+    Done,
+}
+
+/// Server side of the CURVE handshake, checking the client's permanent key with an
+/// authenticator.
+pub struct CurveServer<A> {
+    state: ServerState,
+    permanent: CurveKeyPair,
+    transient: CurveKeyPair,
+    /// Key of the cookie box, only known to the server.
+    cookie_key: [u8; CURVE_KEY_LEN],
+    cookie_nonce: LongNonce,
+    welcome_nonce: LongNonce,
+    authenticator: A,
+    metadata: MetaData<Bytes, Bytes>,
+    received: NonceCounter,
+}
+
+impl<A> core::fmt::Debug for CurveServer<A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.debug_struct("CurveServer")
+            .field("state", &self.state)
+            .field("permanent", &self.permanent)
+            .finish()
+    }
+}
+
+impl<A: CurveAuthenticator> CurveServer<A> {
+    /// `metadata` is sent with the READY command.
+    pub fn new<R: RngCore + CryptoRng>(
+        permanent: CurveKeyPair,
+        authenticator: A,
+        metadata: MetaData<Bytes, Bytes>,
+        rng: &mut R,
+    ) -> Self {
+        let mut cookie_key = [0u8; CURVE_KEY_LEN];
+        let mut cookie_nonce = LongNonce::default();
+        let mut welcome_nonce = LongNonce::default();
+        rng.fill_bytes(&mut cookie_key);
+        rng.fill_bytes(&mut cookie_nonce);
+        rng.fill_bytes(&mut welcome_nonce);
+        CurveServer {
+            state: ServerState::ExpectHello,
+            permanent,
+            transient: CurveKeyPair::generate(rng),
+            cookie_key,
+            cookie_nonce,
+            welcome_nonce,
+            authenticator,
+            metadata,
+            received: NonceCounter::default(),
+        }
+    }
+
+    /// The authenticator, e.g. to look up what it learned about the client.
+    pub fn authenticator(&self) -> &A {
+        &self.authenticator
+    }
+
+    /// Handle the next command of the client.
+    pub fn handle(&mut self, cmd: Command<Bytes, Bytes>) -> Result<CurveStep, CurveError> {
+        match (self.state, cmd) {
+            (ServerState::ExpectHello, Command::CURVE(CurveCommand::HELLO(hello))) => {
+                let welcome = self.welcome(&hello)?;
+                self.state = ServerState::ExpectInitiate;
+                Ok(CurveStep::Send(welcome))
+            }
+            (ServerState::ExpectInitiate, Command::CURVE(CurveCommand::INITIATE(initiate))) => {
+                self.state = ServerState::Done;
+                self.ready(&initiate)
+            }
+            (ServerState::ExpectHello, _) => {
+                Err(CurveError::UnexpectedCommand { expected: "HELLO" })
+            }
+            (ServerState::ExpectInitiate, _) => Err(CurveError::UnexpectedCommand {
+                expected: "INITIATE",
+            }),
+            (ServerState::Done, _) => Err(CurveError::UnexpectedCommand { expected: "none" }),
+        }
+    }
+
+    /// Check the hello box and send the transient key along with the cookie.
+    fn welcome(&mut self, hello: &CurveHello<Bytes>) -> Result<Command<Bytes, Bytes>, CurveError> {
+        self.received.check(hello.nonce)?;
+        let client_transient = public_key(&hello.client_key)?;
+        let cipher = SalsaBox::new(&client_transient, &self.permanent.secret);
+        let plaintext = open(
+            &cipher,
+            &short_nonce(HELLO_NONCE_PREFIX, hello.nonce),
+            &hello.hello_box,
+        )?;
+        self.received.accept(hello.nonce);
+        if plaintext.iter().any(|v| *v != 0) {
+            return Err(CurveError::MalformedBox);
+        }
+
+        // cookie = long nonce + Box [C',s'](K)
+        let mut cookie = Vec::with_capacity(CURVE_COOKIE_LEN);
+        cookie.extend_from_slice(&self.cookie_nonce);
+        let mut cookie_content = Vec::with_capacity(2 * CURVE_KEY_LEN);
+        cookie_content.extend_from_slice(client_transient.as_bytes());
+        cookie_content.extend_from_slice(&self.transient.secret.as_bytes()[..]);
+        cookie.extend(seal(
+            &XSalsa20Poly1305::new(&self.cookie_key.into()),
+            &long_nonce(COOKIE_NONCE_PREFIX, &self.cookie_nonce),
+            &cookie_content,
+        ));
+
+        let mut plaintext = Vec::with_capacity(CURVE_KEY_LEN + CURVE_COOKIE_LEN);
+        plaintext.extend_from_slice(self.transient.public());
+        plaintext.extend(cookie);
+        let welcome_box = seal(
+            &cipher,
+            &long_nonce(WELCOME_NONCE_PREFIX, &self.welcome_nonce),
+            &plaintext,
+        );
+
+        Ok(Command::CURVE(CurveCommand::WELCOME(CurveWelcome {
+            nonce: Bytes::copy_from_slice(&self.welcome_nonce),
+            welcome_box: welcome_box.into(),
+        })))
+    }
+
+    /// Recover the transient keys from the cookie, check the vouch and authenticate the client.
+    fn ready(&mut self, initiate: &CurveInitiate<Bytes>) -> Result<CurveStep, CurveError> {
+        self.received.check(initiate.nonce)?;
+        let (cookie_nonce, cookie_box) = initiate.cookie.split_at(CURVE_LONG_NONCE_LEN);
+        let cookie = open(
+            &XSalsa20Poly1305::new(&self.cookie_key.into()),
+            &long_nonce(COOKIE_NONCE_PREFIX, cookie_nonce),
+            cookie_box,
+        )?;
+        let client_transient = public_key(&cookie[..CURVE_KEY_LEN])?;
+        let mut server_transient = [0u8; CURVE_KEY_LEN];
+        server_transient.copy_from_slice(&cookie[CURVE_KEY_LEN..]);
+        let server_transient = SecretKey::from(server_transient);
+
+        let cipher = SalsaBox::new(&client_transient, &server_transient);
+        let plaintext = open(
+            &cipher,
+            &short_nonce(INITIATE_NONCE_PREFIX, initiate.nonce),
+            &initiate.initiate_box,
+        )?;
+        self.received.accept(initiate.nonce);
+        if plaintext.len() < CURVE_KEY_LEN + VOUCH_LEN {
+            return Err(CurveError::MalformedBox);
+        }
+        let (client_key, rest) = plaintext.split_at(CURVE_KEY_LEN);
+        let (vouch, md) = rest.split_at(VOUCH_LEN);
+        let client_permanent = public_key(client_key)?;
+
+        let (vouch_nonce, vouch_box) = vouch.split_at(CURVE_LONG_NONCE_LEN);
+        let vouched = open(
+            &SalsaBox::new(&client_permanent, &server_transient),
+            &long_nonce(VOUCH_NONCE_PREFIX, vouch_nonce),
+            vouch_box,
+        )?;
+        if vouched[..CURVE_KEY_LEN] != client_transient.as_bytes()[..]
+            || vouched[CURVE_KEY_LEN..] != self.permanent.public()[..]
+        {
+            return Err(CurveError::MalformedBox);
+        }
+        let peer = metadata(md.to_vec())?;
+
+        if !self.authenticator.authenticate(client_permanent.as_bytes()) {
+            return Ok(CurveStep::Close(Command::ERROR(ByteSlice(
+                Bytes::from_static(INVALID_CLIENT_KEY),
+            ))));
+        }
+
+        let mut sent = NonceCounter::default();
+        let nonce = sent.next();
+        let ready_box = seal(
+            &cipher,
+            &short_nonce(READY_NONCE_PREFIX, nonce),
+            &encode_metadata(&self.metadata)?,
+        );
+        let reply = Command::CURVE(CurveCommand::READY(CurveReady {
+            nonce,
+            ready_box: ready_box.into(),
+        }));
+
+        Ok(CurveStep::Done {
+            reply: Some(reply),
+            peer,
+            session: CurveSession {
+                cipher,
+                send_prefix: SERVER_MESSAGE_NONCE_PREFIX,
+                receive_prefix: CLIENT_MESSAGE_NONCE_PREFIX,
+                sent,
+                received: self.received,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rand_core::OsRng;
+    use zmqrs_parser::{Message, Ping};
+
+    /// Encode and parse `cmd` again, as done by the codecs.
+    fn transfer(cmd: Command<Bytes, Bytes>) -> Command<Bytes, Bytes> {
+        let mut logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut buf = BytesMut::new();
+        Frame::Command(cmd).encode(&mut buf).unwrap();
+        let buf = buf.freeze();
+        let (rest, parsed) = zmqrs_parser::frame_limited(
+            &buf,
+            &SecurityMechanism::CURVE,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .unwrap();
+        assert!(rest.is_empty());
+        match Frame::<Bytes, Bytes>::from((&buf, parsed)) {
+            Frame::Command(cmd) => cmd,
+            other => panic!("expected a command, got {:?}", other),
+        }
+    }
+
+    fn metadata(socket_type: &'static [u8]) -> MetaData<Bytes, Bytes> {
+        MetaData::new().with_socket_type(Bytes::from_static(socket_type))
+    }
+
+    type Authenticator = Box<dyn FnMut(&[u8; CURVE_KEY_LEN]) -> bool>;
+
+    struct Handshake {
+        client: CurveClient,
+        server: CurveServer<Authenticator>,
+    }
+
+    fn setup(server_key: Option<[u8; CURVE_KEY_LEN]>, accept: bool) -> Handshake {
+        let server_keys = CurveKeyPair::generate(&mut OsRng);
+        let client_keys = CurveKeyPair::generate(&mut OsRng);
+        let expected_client = *client_keys.public();
+        let client = CurveClient::new(
+            client_keys.clone(),
+            server_key.unwrap_or(*server_keys.public()),
+            metadata(b"DEALER"),
+            &mut OsRng,
+        );
+        let server = CurveServer::new(
+            server_keys,
+            Box::new(move |key: &[u8; CURVE_KEY_LEN]| accept && *key == expected_client)
+                as Authenticator,
+            metadata(b"ROUTER"),
+            &mut OsRng,
+        );
+        Handshake { client, server }
+    }
+
+    fn send(step: Result<CurveStep, CurveError>) -> Command<Bytes, Bytes> {
+        match step {
+            Ok(CurveStep::Send(cmd)) => transfer(cmd),
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    /// Run the whole handshake, returning the sessions of client and server.
+    fn handshake() -> (CurveSession, CurveSession) {
+        let Handshake {
+            mut client,
+            mut server,
+        } = setup(None, true);
+
+        let welcome = send(server.handle(transfer(client.hello())));
+        let initiate = send(client.handle(welcome));
+        let (ready, server_session) = match server.handle(initiate) {
+            Ok(CurveStep::Done {
+                reply: Some(ready),
+                peer,
+                session,
+            }) => {
+                assert_eq!(peer.socket_type(), Some(&Bytes::from_static(b"DEALER")));
+                (transfer(ready), session)
+            }
+            other => panic!("unexpected step {:?}", other),
+        };
+        match client.handle(ready) {
+            Ok(CurveStep::Done {
+                reply: None,
+                peer,
+                session,
+            }) => {
+                assert_eq!(peer.socket_type(), Some(&Bytes::from_static(b"ROUTER")));
+                (session, server_session)
+            }
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    fn message_of(cmd: Command<Bytes, Bytes>) -> CurveMessage<Bytes> {
+        match transfer(cmd) {
+            Command::CURVE(CurveCommand::MESSAGE(msg)) => msg,
+            other => panic!("expected MESSAGE, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_and_messages() {
+        let (mut client, mut server) = handshake();
+
+        let frames: Vec<Frame<Bytes, Bytes>> = vec![
+            Frame::Message(Message::new(Bytes::from_static(b"")).with_more(true)),
+            Frame::Message(Message::new(Bytes::from_static(b"Hello"))),
+            Frame::Command(Command::PING(Ping {
+                ttl: 10,
+                context: Bytes::from_static(b"ctx"),
+            })),
+        ];
+        for frame in &frames {
+            let msg = message_of(client.encrypt(frame).unwrap());
+            assert_eq!(server.decrypt(&msg).as_ref(), Ok(frame));
+
+            let msg = message_of(server.encrypt(frame).unwrap());
+            assert_eq!(client.decrypt(&msg).as_ref(), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn replayed_and_modified_messages_are_rejected() {
+        let (mut client, mut server) = handshake();
+        let frame = Frame::Message(Message::new(Bytes::from_static(b"Hello")));
+
+        let msg = message_of(client.encrypt(&frame).unwrap());
+        assert!(server.decrypt(&msg).is_ok());
+        assert_eq!(server.decrypt(&msg), Err(CurveError::ReusedNonce));
+
+        let msg = message_of(client.encrypt(&frame).unwrap());
+        let mut modified = msg.message_box.to_vec();
+        modified[20] ^= 1;
+        let modified = CurveMessage {
+            nonce: msg.nonce,
+            message_box: modified.into(),
+        };
+        assert_eq!(server.decrypt(&modified), Err(CurveError::Unauthenticated));
+
+        // a message of the client cannot be reflected back to it
+        let msg = message_of(client.encrypt(&frame).unwrap());
+        assert_eq!(client.decrypt(&msg), Err(CurveError::Unauthenticated));
+    }
+
+    #[test]
+    fn forged_nonces_are_not_remembered() {
+        let (mut client, mut server) = handshake();
+        let frame = Frame::Message(Message::new(Bytes::from_static(b"Hello")));
+
+        let forged = CurveMessage {
+            nonce: u64::MAX,
+            message_box: Bytes::from(vec![0u8; 32]),
+        };
+        assert_eq!(server.decrypt(&forged), Err(CurveError::Unauthenticated));
+        let msg = message_of(client.encrypt(&frame).unwrap());
+        assert_eq!(server.decrypt(&msg), Ok(frame));
+
+        // the same during the handshake
+        let Handshake {
+            mut client,
+            mut server,
+        } = setup(None, true);
+        let hello = match transfer(client.hello()) {
+            Command::CURVE(CurveCommand::HELLO(hello)) => hello,
+            other => panic!("expected HELLO, got {:?}", other),
+        };
+        let mut forged_box = hello.hello_box.to_vec();
+        forged_box[0] ^= 1;
+        let forged = CurveHello {
+            nonce: u64::MAX,
+            hello_box: forged_box.into(),
+            ..hello.clone()
+        };
+        assert_eq!(
+            server
+                .handle(Command::CURVE(CurveCommand::HELLO(forged)))
+                .err(),
+            Some(CurveError::Unauthenticated)
+        );
+        assert!(matches!(
+            server.handle(Command::CURVE(CurveCommand::HELLO(hello))),
+            Ok(CurveStep::Send(_))
+        ));
+    }
+
+    #[test]
+    fn wrong_server_key() {
+        let other = CurveKeyPair::generate(&mut OsRng);
+        let Handshake {
+            mut client,
+            mut server,
+        } = setup(Some(*other.public()), true);
+
+        assert_eq!(
+            server.handle(transfer(client.hello())).err(),
+            Some(CurveError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn client_key_rejected() {
+        let Handshake {
+            mut client,
+            mut server,
+        } = setup(None, false);
+
+        let welcome = send(server.handle(transfer(client.hello())));
+        let initiate = send(client.handle(welcome));
+        let error = match server.handle(initiate) {
+            Ok(CurveStep::Close(error)) => transfer(error),
+            other => panic!("unexpected step {:?}", other),
+        };
+        assert_eq!(
+            client.handle(error).err(),
+            Some(CurveError::Rejected(Bytes::from_static(INVALID_CLIENT_KEY)))
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Endpoints sockets bind and connect to, e.g. `tcp://127.0.0.1:5555`.
+//!
+//! The supported forms are those of libzmq:
+//! * `tcp://host:port`, where a `*` host stands for all interfaces and a `0` or `*` port for an
+//!   ephemeral one, chosen when binding. IPv6 addresses are written in brackets.
+//! * `tcp://source;host:port`, connecting from the address `source`, optionally with a port.
+//! * `ipc://path`, or `ipc://@name` for the abstract namespace on Linux.
+//! * `inproc://name`
+
+use core::str::FromStr;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EndpointError {
+    /// There's no `transport://` prefix.
+    MissingTransport,
+    /// The transport is none of `tcp`, `ipc` and `inproc`.
+    UnknownTransport(String),
+    /// The endpoint is valid, but can't be used with this transport.
+    TransportMismatch,
+    /// The host, path or name is missing or malformed.
+    InvalidAddress,
+    /// The port is missing or no number up to 65535.
+    InvalidPort,
+}
+
+impl core::fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            EndpointError::MissingTransport => write!(f, "Endpoint without transport"),
+            EndpointError::UnknownTransport(name) => write!(f, "Unknown transport {}", name),
+            EndpointError::TransportMismatch => write!(f, "Endpoint of another transport"),
+            EndpointError::InvalidAddress => write!(f, "Invalid address"),
+            EndpointError::InvalidPort => write!(f, "Invalid port"),
+        }
+    }
+}
+
+impl std::error::Error for EndpointError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Host {
+    /// `*`, i.e. all interfaces.
+    Any,
+    Ip(IpAddr),
+    /// A name to be resolved, e.g. `localhost`.
+    Name(String),
+}
+
+impl Host {
+    fn parse(host: &str) -> Result<Self, EndpointError> {
+        if host == "*" {
+            return Ok(Host::Any);
+        }
+        if let Some(ip) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            return Ipv6Addr::from_str(ip)
+                .map(|ip| Host::Ip(ip.into()))
+                .map_err(|_| EndpointError::InvalidAddress);
+        }
+        if let Ok(ip) = IpAddr::from_str(host) {
+            return Ok(Host::Ip(ip));
+        }
+        let valid = !host.is_empty()
+            && host
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+        if valid {
+            Ok(Host::Name(host.into()))
+        } else {
+            Err(EndpointError::InvalidAddress)
+        }
+    }
+}
+
+impl core::fmt::Display for Host {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            Host::Any => write!(f, "*"),
+            Host::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            Host::Ip(ip) => write!(f, "{}", ip),
+            Host::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Host and port of a `tcp://` endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpAddress {
+    pub host: Host,
+    /// 0 stands for an ephemeral port.
+    pub port: u16,
+}
+
+impl TcpAddress {
+    fn parse(address: &str) -> Result<Self, EndpointError> {
+        let (host, port) = address.rsplit_once(':').ok_or(EndpointError::InvalidPort)?;
+        let port = match port {
+            "*" => 0,
+            port => port.parse().map_err(|_| EndpointError::InvalidPort)?,
+        };
+        Ok(TcpAddress {
+            host: Host::parse(host)?,
+            port,
+        })
+    }
+
+    /// The address in a form the standard library resolves, e.g. `0.0.0.0:5555` for `*:5555`.
+    pub fn to_socket_addr_string(&self) -> String {
+        match &self.host {
+            Host::Any => format!("0.0.0.0:{}", self.port),
+            host => format!("{}:{}", host, self.port),
+        }
+    }
+}
+
+impl core::fmt::Display for TcpAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpcAddress {
+    /// A socket file.
+    Path(PathBuf),
+    /// A name in the abstract namespace of Linux, written as `@name`.
+    Abstract(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp {
+        /// The local address to connect from.
+        source: Option<TcpAddress>,
+        address: TcpAddress,
+    },
+    Ipc(IpcAddress),
+    Inproc(String),
+}
+
+impl Endpoint {
+    /// The name of the transport, e.g. `tcp`.
+    pub fn transport(&self) -> &'static str {
+        match self {
+            Endpoint::Tcp { .. } => "tcp",
+            Endpoint::Ipc(_) => "ipc",
+            Endpoint::Inproc(_) => "inproc",
+        }
+    }
+
+    fn parse_tcp(address: &str) -> Result<Self, EndpointError> {
+        let (source, address) = match address.split_once(';') {
+            Some((source, address)) => {
+                // the port of the source is optional
+                let source = match TcpAddress::parse(source) {
+                    Ok(source) => source,
+                    Err(_) => TcpAddress {
+                        host: Host::parse(source)?,
+                        port: 0,
+                    },
+                };
+                if source.host == Host::Any {
+                    return Err(EndpointError::InvalidAddress);
+                }
+                (Some(source), address)
+            }
+            None => (None, address),
+        };
+        Ok(Endpoint::Tcp {
+            source,
+            address: TcpAddress::parse(address)?,
+        })
+    }
+
+    fn parse_ipc(address: &str) -> Result<Self, EndpointError> {
+        match address.strip_prefix('@') {
+            _ if address.is_empty() => Err(EndpointError::InvalidAddress),
+            Some("") => Err(EndpointError::InvalidAddress),
+            Some(name) => Ok(Endpoint::Ipc(IpcAddress::Abstract(name.into()))),
+            None => Ok(Endpoint::Ipc(IpcAddress::Path(address.into()))),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(endpoint: &str) -> Result<Self, Self::Err> {
+        let (transport, address) = endpoint
+            .split_once("://")
+            .ok_or(EndpointError::MissingTransport)?;
+        match transport {
+            "tcp" => Endpoint::parse_tcp(address),
+            "ipc" => Endpoint::parse_ipc(address),
+            "inproc" if address.is_empty() => Err(EndpointError::InvalidAddress),
+            "inproc" => Ok(Endpoint::Inproc(address.into())),
+            _ => Err(EndpointError::UnknownTransport(transport.into())),
+        }
+    }
+}
+
+impl core::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        write!(f, "{}://", self.transport())?;
+        match self {
+            Endpoint::Tcp {
+                source: Some(source),
+                address,
+            } if source.port == 0 => write!(f, "{};{}", source.host, address),
+            Endpoint::Tcp {
+                source: Some(source),
+                address,
+            } => write!(f, "{};{}", source, address),
+            Endpoint::Tcp {
+                source: None,
+                address,
+            } => write!(f, "{}", address),
+            Endpoint::Ipc(IpcAddress::Path(path)) => write!(f, "{}", path.display()),
+            Endpoint::Ipc(IpcAddress::Abstract(name)) => write!(f, "@{}", name),
+            Endpoint::Inproc(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn parse(endpoint: &str) -> Result<Endpoint, EndpointError> {
+        endpoint.parse()
+    }
+
+    fn tcp(host: Host, port: u16) -> Endpoint {
+        Endpoint::Tcp {
+            source: None,
+            address: TcpAddress { host, port },
+        }
+    }
+
+    #[test]
+    fn tcp_endpoints() {
+        let localhost = Host::Ip(Ipv4Addr::LOCALHOST.into());
+        assert_eq!(
+            parse("tcp://127.0.0.1:5555"),
+            Ok(tcp(localhost.clone(), 5555))
+        );
+        assert_eq!(parse("tcp://*:5555"), Ok(tcp(Host::Any, 5555)));
+        assert_eq!(parse("tcp://*:0"), Ok(tcp(Host::Any, 0)));
+        assert_eq!(parse("tcp://*:*"), Ok(tcp(Host::Any, 0)));
+        assert_eq!(
+            parse("tcp://[::1]:5555"),
+            Ok(tcp(Host::Ip(Ipv6Addr::LOCALHOST.into()), 5555))
+        );
+        assert_eq!(
+            parse("tcp://localhost:5555"),
+            Ok(tcp(Host::Name("localhost".into()), 5555))
+        );
+        assert_eq!(
+            parse("tcp://127.0.0.1;example.com:5555"),
+            Ok(Endpoint::Tcp {
+                source: Some(TcpAddress {
+                    host: localhost.clone(),
+                    port: 0
+                }),
+                address: TcpAddress {
+                    host: Host::Name("example.com".into()),
+                    port: 5555
+                },
+            })
+        );
+        assert_eq!(
+            parse("tcp://127.0.0.1:4444;example.com:5555"),
+            Ok(Endpoint::Tcp {
+                source: Some(TcpAddress {
+                    host: localhost,
+                    port: 4444
+                }),
+                address: TcpAddress {
+                    host: Host::Name("example.com".into()),
+                    port: 5555
+                },
+            })
+        );
+
+        assert_eq!(parse("tcp://127.0.0.1"), Err(EndpointError::InvalidPort));
+        assert_eq!(
+            parse("tcp://127.0.0.1:65536"),
+            Err(EndpointError::InvalidPort)
+        );
+        assert_eq!(parse("tcp://:5555"), Err(EndpointError::InvalidAddress));
+        assert_eq!(parse("tcp://[::1:5555"), Err(EndpointError::InvalidAddress));
+        assert_eq!(
+            parse("tcp://*;127.0.0.1:5555"),
+            Err(EndpointError::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn other_endpoints() {
+        assert_eq!(
+            parse("ipc:///tmp/feed"),
+            Ok(Endpoint::Ipc(IpcAddress::Path("/tmp/feed".into())))
+        );
+        assert_eq!(
+            parse("ipc://@feed"),
+            Ok(Endpoint::Ipc(IpcAddress::Abstract("feed".into())))
+        );
+        assert_eq!(parse("inproc://feed"), Ok(Endpoint::Inproc("feed".into())));
+
+        assert_eq!(parse("ipc://"), Err(EndpointError::InvalidAddress));
+        assert_eq!(parse("ipc://@"), Err(EndpointError::InvalidAddress));
+        assert_eq!(parse("inproc://"), Err(EndpointError::InvalidAddress));
+        assert_eq!(
+            parse("udp://127.0.0.1:5555"),
+            Err(EndpointError::UnknownTransport("udp".into()))
+        );
+        assert_eq!(parse("feed"), Err(EndpointError::MissingTransport));
+    }
+
+    #[test]
+    fn display() {
+        for endpoint in &[
+            "tcp://127.0.0.1:5555",
+            "tcp://*:0",
+            "tcp://[::1]:5555",
+            "tcp://127.0.0.1;example.com:5555",
+            "tcp://127.0.0.1:4444;example.com:5555",
+            "ipc:///tmp/feed",
+            "ipc://@feed",
+            "inproc://feed",
+        ] {
+            assert_eq!(parse(endpoint).unwrap().to_string(), *endpoint);
+        }
+        assert_eq!(parse("tcp://*:*").unwrap().to_string(), "tcp://*:0");
+    }
+}
@@ -0,0 +1,304 @@
+//! Heartbeats using the PING and PONG commands of ZMTP 3.1, see https://rfc.zeromq.org/spec/37/
+//!
+//! Every `interval` a PING is sent. If nothing arrives from the peer within `timeout` after that,
+//! the connection is considered dead. The PING carries `ttl`, asking the peer to close the
+//! connection if it doesn't hear from us for that long; the TTL of the peer is honoured the same
+//! way. The options correspond to `ZMQ_HEARTBEAT_IVL`, `ZMQ_HEARTBEAT_TIMEOUT` and
+//! `ZMQ_HEARTBEAT_TTL` of libzmq.
+//!
+//! Time is taken from a `Clock`, so that tests can use a `ManualClock`.
+
+use bytes::Bytes;
+use core::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use zmqrs_parser::{Ping, Pong};
+
+/// The TTL of a PING is sent in tenths of a second.
+const TTL_UNIT_MS: u64 = 100;
+
+/// Source of the current time.
+pub trait Clock {
+    /// Time elapsed since an arbitrary, but fixed point in time.
+    fn now(&self) -> Duration;
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+/// The monotonic clock of the operating system.
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when told to; clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Heartbeat settings; all of them are disabled by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeartbeatOptions {
+    /// Time between two PINGs.
+    pub interval: Option<Duration>,
+
+    /// Time to wait for any data after a PING; `interval` if not set.
+    pub timeout: Option<Duration>,
+
+    /// Time the peer should wait for data from us before closing the connection.
+    pub ttl: Option<Duration>,
+}
+
+impl HeartbeatOptions {
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// The TTL field of the PINGs we send.
+    fn ttl_field(&self) -> u16 {
+        let ttl = self
+            .ttl
+            .map_or(0, |ttl| ttl.as_millis() / TTL_UNIT_MS as u128);
+        ttl.min(u16::MAX as u128) as u16
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeartbeatError {
+    /// No data arrived within the timeout after our PING.
+    Timeout,
+    /// No data arrived within the TTL the peer asked for.
+    TtlExpired,
+}
+
+impl core::fmt::Display for HeartbeatError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            HeartbeatError::Timeout => write!(f, "Heartbeat timed out"),
+            HeartbeatError::TtlExpired => write!(f, "Heartbeat TTL of the peer expired"),
+        }
+    }
+}
+
+/// Timers of the heartbeats of one connection.
+#[derive(Debug)]
+pub struct Heartbeat<C> {
+    options: HeartbeatOptions,
+    clock: C,
+    /// When to send the next PING; `None` until started or without interval.
+    next_ping: Option<Duration>,
+    /// Deadline for data after our last PING.
+    timeout: Option<Duration>,
+    /// Deadline for data as requested by the peer.
+    remote_ttl: Option<Duration>,
+}
+
+impl<C: Clock> Heartbeat<C> {
+    pub fn new(options: HeartbeatOptions, clock: C) -> Self {
+        Heartbeat {
+            options,
+            clock,
+            next_ping: None,
+            timeout: None,
+            remote_ttl: None,
+        }
+    }
+
+    pub fn options(&self) -> &HeartbeatOptions {
+        &self.options
+    }
+
+    /// Start sending PINGs, i.e. once the handshake is done.
+    pub fn start(&mut self) {
+        self.next_ping = self.options.interval.map(|ivl| self.clock.now() + ivl);
+    }
+
+    /// Note that data arrived from the peer, which proves it is alive.
+    pub fn received(&mut self) {
+        self.timeout = None;
+        self.remote_ttl = None;
+    }
+
+    /// The PONG answering `ping`; also honours the TTL the peer asked for.
+    pub fn handle_ping<T>(&mut self, ping: Ping<T>) -> Pong<T> {
+        if ping.ttl > 0 && self.remote_ttl.is_none() {
+            let ttl = Duration::from_millis(u64::from(ping.ttl) * TTL_UNIT_MS);
+            self.remote_ttl = Some(self.clock.now() + ttl);
+        }
+        Pong {
+            context: ping.context,
+        }
+    }
+
+    /// The next point in time `poll` has something to do, if any.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        [self.next_ping, self.timeout, self.remote_ttl]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    /// Time left until `next_deadline`, if any.
+    pub fn time_left(&self) -> Option<Duration> {
+        let deadline = self.next_deadline()?;
+        Some(deadline.saturating_sub(self.clock.now()))
+    }
+
+    /// Check the timers; returns the PING to send, if it is time for it.
+    pub fn poll(&mut self) -> Result<Option<Ping<Bytes>>, HeartbeatError> {
+        let now = self.clock.now();
+        if self.remote_ttl.is_some_and(|deadline| now >= deadline) {
+            return Err(HeartbeatError::TtlExpired);
+        }
+        if self.timeout.is_some_and(|deadline| now >= deadline) {
+            return Err(HeartbeatError::Timeout);
+        }
+
+        match (self.next_ping, self.options.interval) {
+            (Some(next_ping), Some(interval)) if now >= next_ping => {
+                self.next_ping = Some(now + interval);
+                if self.timeout.is_none() {
+                    self.timeout = Some(now + self.options.timeout.unwrap_or(interval));
+                }
+                Ok(Some(Ping {
+                    ttl: self.options.ttl_field(),
+                    context: Bytes::new(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn ping_and_timeout() {
+        let clock = ManualClock::new();
+        let options = HeartbeatOptions::default()
+            .with_interval(secs(1))
+            .with_timeout(secs(3))
+            .with_ttl(secs(10));
+        let mut heartbeat = Heartbeat::new(options, clock.clone());
+        assert_eq!(heartbeat.poll(), Ok(None));
+        assert_eq!(heartbeat.next_deadline(), None);
+
+        heartbeat.start();
+        assert_eq!(heartbeat.next_deadline(), Some(secs(1)));
+        assert_eq!(heartbeat.poll(), Ok(None));
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(heartbeat.time_left(), Some(Duration::from_millis(600)));
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(heartbeat.time_left(), Some(Duration::ZERO));
+
+        let ping = heartbeat.poll().unwrap().unwrap();
+        assert_eq!(ping.ttl, 100);
+        assert_eq!(heartbeat.next_deadline(), Some(secs(2)));
+
+        // the peer answered, so the timeout is off
+        clock.advance(Duration::from_millis(500));
+        heartbeat.received();
+        clock.advance(secs(3));
+        assert!(heartbeat.poll().unwrap().is_some());
+
+        // the peer went silent
+        for _ in 0..2 {
+            clock.advance(secs(1));
+            assert!(heartbeat.poll().unwrap().is_some());
+        }
+        clock.advance(secs(1));
+        assert_eq!(heartbeat.poll(), Err(HeartbeatError::Timeout));
+    }
+
+    #[test]
+    fn timeout_defaults_to_interval() {
+        let clock = ManualClock::new();
+        let options = HeartbeatOptions::default().with_interval(secs(2));
+        let mut heartbeat = Heartbeat::new(options, clock.clone());
+        heartbeat.start();
+
+        clock.advance(secs(2));
+        assert_eq!(heartbeat.poll().unwrap().map(|ping| ping.ttl), Some(0));
+        clock.advance(secs(2));
+        assert_eq!(heartbeat.poll(), Err(HeartbeatError::Timeout));
+    }
+
+    #[test]
+    fn answer_ping_and_honour_ttl() {
+        let clock = ManualClock::new();
+        let mut heartbeat = Heartbeat::new(HeartbeatOptions::default(), clock.clone());
+        heartbeat.start();
+
+        let pong = heartbeat.handle_ping(Ping {
+            ttl: 20,
+            context: &b"ctx"[..],
+        });
+        assert_eq!(pong.context, &b"ctx"[..]);
+        assert_eq!(heartbeat.next_deadline(), Some(secs(2)));
+
+        clock.advance(secs(1));
+        assert_eq!(heartbeat.poll(), Ok(None));
+        clock.advance(secs(1));
+        assert_eq!(heartbeat.poll(), Err(HeartbeatError::TtlExpired));
+
+        // any data resets the TTL
+        heartbeat.received();
+        assert_eq!(heartbeat.poll(), Ok(None));
+        assert_eq!(heartbeat.next_deadline(), None);
+    }
+}
@@ -0,0 +1,384 @@
+//! The `inproc://` transport between sockets of the same process.
+//!
+//! Frames are passed through channels as they are, without encoding them. Like with ZMTP, each
+//! side starts with a READY command carrying its metadata, so that socket types are checked the
+//! same way. As with libzmq 4.x, a peer may connect before the endpoint is bound; it is handed
+//! to the listener once that exists.
+
+use bytes::Bytes;
+use core::convert::TryFrom;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+use zmqrs_parser::{
+    Assembled, ByteSlice, Command, Frame, MetaData, Multipart, MultipartAssembler, SocketType,
+};
+
+use crate::connection::{Event, ProtocolError, INCOMPATIBLE_SOCKET_TYPE};
+use crate::endpoint::{Endpoint, EndpointError};
+use crate::transport::TransportError;
+
+/// The ends of the channels a peer talks through.
+struct Channels {
+    tx: UnboundedSender<Frame<Bytes, Bytes>>,
+    rx: UnboundedReceiver<Frame<Bytes, Bytes>>,
+}
+
+/// The two sides of a new connection.
+fn channels() -> (Channels, Channels) {
+    let (a_tx, a_rx) = unbounded();
+    let (b_tx, b_rx) = unbounded();
+    (
+        Channels { tx: a_tx, rx: b_rx },
+        Channels { tx: b_tx, rx: a_rx },
+    )
+}
+
+#[derive(Default)]
+struct Entry {
+    /// Where to hand new peers to, if the endpoint is bound.
+    listener: Option<UnboundedSender<Channels>>,
+    /// Peers that connected before the endpoint was bound.
+    pending: Vec<Channels>,
+}
+
+/// All inproc endpoints of the process.
+fn registry() -> &'static Mutex<HashMap<String, Entry>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Entry>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+fn lock_registry() -> std::sync::MutexGuard<'static, HashMap<String, Entry>> {
+    registry().lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn name(endpoint: &str) -> Result<String, TransportError> {
+    match endpoint.parse()? {
+        Endpoint::Inproc(name) => Ok(name),
+        _ => Err(EndpointError::TransportMismatch.into()),
+    }
+}
+
+/// Connect to `endpoint` as a socket of type `socket_type`.
+///
+/// Returns right away, even if the endpoint isn't bound yet. Messages sent meanwhile are queued;
+/// the handshake completes with the first `recv` or `next_event`.
+pub fn connect<L: Into<slog::Logger>>(
+    endpoint: &str,
+    socket_type: SocketType,
+    logger: L,
+) -> Result<InprocPipe, TransportError> {
+    let logger = logger.into();
+    let name = name(endpoint)?;
+    let (ours, mut theirs) = channels();
+
+    let mut registry = lock_registry();
+    let entry = registry.entry(name.clone()).or_default();
+    if let Some(listener) = &entry.listener {
+        match listener.unbounded_send(theirs) {
+            Ok(()) => {
+                debug!(logger, "connected"; "endpoint" => endpoint);
+                return Ok(InprocPipe::new(ours, socket_type, logger));
+            }
+            // the listener is gone
+            Err(e) => {
+                theirs = e.into_inner();
+                entry.listener = None;
+            }
+        }
+    }
+    debug!(logger, "connected before bind"; "endpoint" => endpoint);
+    entry.pending.push(theirs);
+    Ok(InprocPipe::new(ours, socket_type, logger))
+}
+
+/// Listen on `endpoint` for peers of a socket of type `socket_type`.
+///
+/// Fails with `io::ErrorKind::AddrInUse` if another listener is bound to it.
+pub fn bind<L: Into<slog::Logger>>(
+    endpoint: &str,
+    socket_type: SocketType,
+    logger: L,
+) -> Result<InprocListener, TransportError> {
+    let logger = logger.into();
+    let name = name(endpoint)?;
+
+    let mut registry = lock_registry();
+    let entry = registry.entry(name.clone()).or_default();
+    if entry.listener.as_ref().is_some_and(|tx| !tx.is_closed()) {
+        return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+    }
+    let (tx, rx) = unbounded();
+    for peer in entry.pending.drain(..) {
+        // can't fail, the receiver is right here
+        let _ = tx.unbounded_send(peer);
+    }
+    entry.listener = Some(tx);
+    debug!(logger, "bound"; "endpoint" => endpoint);
+
+    Ok(InprocListener {
+        name,
+        peers: rx,
+        socket_type,
+        logger,
+    })
+}
+
+/// Listener on an `inproc://` endpoint; the endpoint is unbound when it is dropped.
+pub struct InprocListener {
+    name: String,
+    peers: UnboundedReceiver<Channels>,
+    socket_type: SocketType,
+    logger: slog::Logger,
+}
+
+impl InprocListener {
+    /// The endpoint bound to, like `ZMQ_LAST_ENDPOINT` of libzmq.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::Inproc(self.name.clone())
+    }
+
+    /// Wait for the next peer and run the handshake with it.
+    pub async fn accept(&mut self) -> Result<InprocPipe, TransportError> {
+        let channels = self.peers.next().await.ok_or(TransportError::Closed)?;
+        debug!(self.logger, "accepted");
+
+        let mut pipe = InprocPipe::new(channels, self.socket_type, self.logger.clone());
+        pipe.handshake().await?;
+        Ok(pipe)
+    }
+}
+
+impl Drop for InprocListener {
+    fn drop(&mut self) {
+        self.peers.close();
+        let mut registry = lock_registry();
+        if let Some(entry) = registry.get_mut(&self.name) {
+            if entry.listener.as_ref().is_some_and(|tx| tx.is_closed()) {
+                entry.listener = None;
+            }
+            if entry.listener.is_none() && entry.pending.is_empty() {
+                registry.remove(&self.name);
+            }
+        }
+    }
+}
+
+/// One side of an `inproc://` connection.
+pub struct InprocPipe {
+    channels: Channels,
+    socket_type: SocketType,
+    peer: Option<MetaData<Bytes, Bytes>>,
+    assembler: MultipartAssembler<Bytes>,
+    logger: slog::Logger,
+}
+
+impl InprocPipe {
+    fn new(channels: Channels, socket_type: SocketType, logger: slog::Logger) -> Self {
+        let metadata = MetaData::new().with_socket_type(Bytes::from_static(socket_type.as_bytes()));
+        // the peer may be gone already, which `recv` reports
+        let _ = channels
+            .tx
+            .unbounded_send(Frame::Command(Command::READY(metadata)));
+        InprocPipe {
+            channels,
+            socket_type,
+            peer: None,
+            assembler: MultipartAssembler::new(),
+            logger,
+        }
+    }
+
+    /// The metadata of the peer, once the handshake is done.
+    pub fn peer_metadata(&self) -> Option<&MetaData<Bytes, Bytes>> {
+        self.peer.as_ref()
+    }
+
+    /// Wait for the metadata of the peer.
+    pub async fn handshake(&mut self) -> Result<(), TransportError> {
+        while self.peer.is_none() {
+            self.next_event().await?;
+        }
+        Ok(())
+    }
+
+    /// Send a message to the peer.
+    pub fn send(&mut self, msg: Multipart<Bytes>) -> Result<(), TransportError> {
+        for part in msg.into_frames() {
+            self.send_frame(Frame::Message(part))?;
+        }
+        Ok(())
+    }
+
+    /// Send a command other than those of the handshake, e.g. SUBSCRIBE.
+    pub fn send_command(&mut self, cmd: Command<Bytes, Bytes>) -> Result<(), TransportError> {
+        self.send_frame(Frame::Command(cmd))
+    }
+
+    /// Wait for the next message of the peer.
+    ///
+    /// Commands arriving meanwhile are dropped; use `next_event` to see them.
+    pub async fn recv(&mut self) -> Result<Multipart<Bytes>, TransportError> {
+        loop {
+            if let Event::Message(msg) = self.next_event().await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Wait for the next event of the connection.
+    pub async fn next_event(&mut self) -> Result<Event, TransportError> {
+        loop {
+            let frame = self
+                .channels
+                .rx
+                .next()
+                .await
+                .ok_or(TransportError::Closed)?;
+
+            if self.peer.is_none() {
+                return self.handle_handshake(frame).map(Event::Ready);
+            }
+            match self.assembler.push(frame).map_err(ProtocolError::from)? {
+                Some(Assembled::Message(msg)) => return Ok(Event::Message(msg)),
+                Some(Assembled::Command(cmd)) => return Ok(Event::Command(cmd)),
+                None => {}
+            }
+        }
+    }
+
+    fn send_frame(&mut self, frame: Frame<Bytes, Bytes>) -> Result<(), TransportError> {
+        self.channels
+            .tx
+            .unbounded_send(frame)
+            .map_err(|_| TransportError::Closed)
+    }
+
+    /// Check the READY command of the peer.
+    fn handle_handshake(
+        &mut self,
+        frame: Frame<Bytes, Bytes>,
+    ) -> Result<MetaData<Bytes, Bytes>, TransportError> {
+        let peer = match frame {
+            Frame::Command(Command::READY(peer)) => peer,
+            Frame::Command(Command::ERROR(reason)) => {
+                return Err(ProtocolError::Rejected(reason.0).into())
+            }
+            _ => return Err(ProtocolError::UnexpectedCommand.into()),
+        };
+
+        let compatible = peer
+            .socket_type()
+            .and_then(|name| SocketType::try_from(name.as_ref()).ok())
+            .is_some_and(|peer| self.socket_type.is_compatible_with(peer));
+        if !compatible {
+            let _ = self.send_frame(Frame::Command(Command::ERROR(ByteSlice(
+                Bytes::from_static(INCOMPATIBLE_SOCKET_TYPE),
+            ))));
+            self.channels.tx.close_channel();
+            warn!(self.logger, "incompatible socket type");
+            return Err(ProtocolError::IncompatibleSocketType.into());
+        }
+
+        debug!(self.logger, "handshake done");
+        self.peer = Some(peer.clone());
+        Ok(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn hello() -> Multipart<Bytes> {
+        Multipart::from(vec![Bytes::new(), Bytes::from_static(b"Hello")])
+    }
+
+    #[test]
+    fn bind_then_connect() {
+        task::block_on(async {
+            let mut listener =
+                bind("inproc://bind_then_connect", SocketType::REP, logger()).unwrap();
+            let mut client =
+                connect("inproc://bind_then_connect", SocketType::REQ, logger()).unwrap();
+            let mut server = listener.accept().await.unwrap();
+            assert_eq!(
+                server.peer_metadata().unwrap().socket_type(),
+                Some(&Bytes::from_static(b"REQ"))
+            );
+
+            // the data isn't copied
+            let request = hello();
+            let data = request.parts()[1].as_ptr();
+            client.send(request).unwrap();
+            let received = server.recv().await.unwrap();
+            assert_eq!(received, hello());
+            assert_eq!(received.parts()[1].as_ptr(), data);
+
+            server.send(hello()).unwrap();
+            assert_eq!(client.recv().await.unwrap(), hello());
+            assert!(client.peer_metadata().is_some());
+        });
+    }
+
+    #[test]
+    fn connect_before_bind() {
+        task::block_on(async {
+            let mut client =
+                connect("inproc://connect_before_bind", SocketType::DEALER, logger()).unwrap();
+            client.send(hello()).unwrap();
+
+            let mut listener =
+                bind("inproc://connect_before_bind", SocketType::ROUTER, logger()).unwrap();
+            let mut server = listener.accept().await.unwrap();
+            assert_eq!(server.recv().await.unwrap(), hello());
+        });
+    }
+
+    #[test]
+    fn incompatible_socket_types() {
+        task::block_on(async {
+            let mut listener = bind("inproc://incompatible", SocketType::PUB, logger()).unwrap();
+            let mut client = connect("inproc://incompatible", SocketType::REQ, logger()).unwrap();
+            assert!(matches!(
+                listener.accept().await,
+                Err(TransportError::Protocol(
+                    ProtocolError::IncompatibleSocketType
+                ))
+            ));
+            assert!(matches!(
+                client.recv().await,
+                Err(TransportError::Protocol(
+                    ProtocolError::IncompatibleSocketType
+                ))
+            ));
+        });
+    }
+
+    #[test]
+    fn bind_twice() {
+        let listener = bind("inproc://bind_twice", SocketType::REP, logger()).unwrap();
+        match bind("inproc://bind_twice", SocketType::REP, logger()) {
+            Err(TransportError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::AddrInUse),
+            _ => panic!("expected AddrInUse"),
+        }
+        assert_eq!(listener.endpoint().to_string(), "inproc://bind_twice");
+        drop(listener);
+        assert!(bind("inproc://bind_twice", SocketType::REP, logger()).is_ok());
+
+        assert!(matches!(
+            bind("inproc://", SocketType::REP, logger()),
+            Err(TransportError::InvalidEndpoint(
+                EndpointError::InvalidAddress
+            ))
+        ));
+    }
+}
@@ -0,0 +1,229 @@
+//! The `ipc://` transport over Unix domain sockets.
+//!
+//! `ipc:///tmp/feed` names a socket file. On Linux, `ipc://@feed` names a socket in the abstract
+//! namespace, which has no file and vanishes with the last socket using it.
+
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use zmqrs_parser::SocketType;
+
+use crate::connection::Role;
+use crate::endpoint::{Endpoint, EndpointError, IpcAddress};
+use crate::transport::{Transport, TransportError};
+
+fn address(endpoint: &str) -> Result<IpcAddress, TransportError> {
+    match endpoint.parse()? {
+        Endpoint::Ipc(address) => Ok(address),
+        _ => Err(EndpointError::TransportMismatch.into()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<std::os::unix::net::SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    std::os::unix::net::SocketAddr::from_abstract_name(name)
+}
+
+/// Remove the socket file at `path` if no one listens on it anymore, like after a crash.
+///
+/// Files that are no sockets or still in use are left alone, so that binding fails.
+async fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path).await {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Connect to `endpoint` and run the handshake as a socket of type `socket_type`.
+pub async fn connect<L: Into<slog::Logger>>(
+    endpoint: &str,
+    socket_type: SocketType,
+    logger: L,
+) -> Result<Transport<UnixStream>, TransportError> {
+    let logger = logger.into();
+    let stream = match address(endpoint)? {
+        IpcAddress::Path(path) => UnixStream::connect(path).await?,
+        #[cfg(target_os = "linux")]
+        IpcAddress::Abstract(name) => {
+            let addr = abstract_addr(&name)?;
+            let stream = async_std::task::spawn_blocking(move || {
+                std::os::unix::net::UnixStream::connect_addr(&addr)
+            })
+            .await?;
+            stream.set_nonblocking(true)?;
+            UnixStream::from(stream)
+        }
+        #[cfg(not(target_os = "linux"))]
+        IpcAddress::Abstract(_) => return Err(EndpointError::InvalidAddress.into()),
+    };
+    debug!(logger, "connected"; "endpoint" => endpoint);
+
+    Transport::establish(stream, Role::Client, socket_type, logger).await
+}
+
+/// Listen on `endpoint` for peers of a socket of type `socket_type`.
+///
+/// A stale socket file left behind by a previous listener is replaced.
+pub async fn bind<L: Into<slog::Logger>>(
+    endpoint: &str,
+    socket_type: SocketType,
+    logger: L,
+) -> Result<IpcListener, TransportError> {
+    let logger = logger.into();
+    let address = address(endpoint)?;
+    let listener = match &address {
+        IpcAddress::Path(path) => {
+            remove_stale_socket(path).await?;
+            UnixListener::bind(path).await?
+        }
+        #[cfg(target_os = "linux")]
+        IpcAddress::Abstract(name) => {
+            let listener = std::os::unix::net::UnixListener::bind_addr(&abstract_addr(name)?)?;
+            listener.set_nonblocking(true)?;
+            UnixListener::from(listener)
+        }
+        #[cfg(not(target_os = "linux"))]
+        IpcAddress::Abstract(_) => return Err(EndpointError::InvalidAddress.into()),
+    };
+    debug!(logger, "bound"; "endpoint" => endpoint);
+
+    Ok(IpcListener {
+        listener,
+        address,
+        socket_type,
+        logger,
+    })
+}
+
+/// Listener on an `ipc://` endpoint; the socket file is removed when it is dropped.
+pub struct IpcListener {
+    listener: UnixListener,
+    address: IpcAddress,
+    socket_type: SocketType,
+    logger: slog::Logger,
+}
+
+impl IpcListener {
+    /// The endpoint bound to, like `ZMQ_LAST_ENDPOINT` of libzmq.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::Ipc(self.address.clone())
+    }
+
+    /// Wait for the next peer and run the handshake with it.
+    pub async fn accept(&self) -> Result<Transport<UnixStream>, TransportError> {
+        let (stream, _) = self.listener.accept().await?;
+        debug!(self.logger, "accepted");
+
+        Transport::establish(stream, Role::Server, self.socket_type, self.logger.clone()).await
+    }
+}
+
+impl Drop for IpcListener {
+    fn drop(&mut self) {
+        if let IpcAddress::Path(path) = &self.address {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!(self.logger, "failed to remove socket file"; "error" => %e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use bytes::Bytes;
+    use std::path::PathBuf;
+    use zmqrs_parser::Multipart;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// A socket file name unique to this test run.
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zmqrs-{}-{}.sock", name, std::process::id()))
+    }
+
+    async fn request_reply(endpoint: &str) {
+        let listener = bind(endpoint, SocketType::REP, logger()).await.unwrap();
+        assert_eq!(listener.endpoint().to_string(), endpoint);
+        let server = task::spawn(async move {
+            let mut server = listener.accept().await.unwrap();
+            let request = server.recv().await.unwrap();
+            server.send(request).await.unwrap();
+        });
+
+        let mut client = connect(endpoint, SocketType::REQ, logger()).await.unwrap();
+        let request = Multipart::from(vec![Bytes::new(), Bytes::from_static(b"Hello")]);
+        client.send(request.clone()).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), request);
+        server.await;
+    }
+
+    #[test]
+    fn endpoint_addresses() {
+        assert_eq!(
+            address("ipc:///tmp/feed").unwrap(),
+            IpcAddress::Path("/tmp/feed".into())
+        );
+        assert!(matches!(
+            address("ipc://"),
+            Err(TransportError::InvalidEndpoint(
+                EndpointError::InvalidAddress
+            ))
+        ));
+        assert!(matches!(
+            address("tcp://127.0.0.1:5555"),
+            Err(TransportError::InvalidEndpoint(
+                EndpointError::TransportMismatch
+            ))
+        ));
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            address("ipc://@feed").unwrap(),
+            IpcAddress::Abstract("feed".into())
+        );
+    }
+
+    #[test]
+    fn socket_file() {
+        let path = socket_path("socket_file");
+        let endpoint = format!("ipc://{}", path.display());
+        task::block_on(request_reply(&endpoint));
+        // removed when the listener was dropped
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_socket_file() {
+        let path = socket_path("stale_socket_file");
+        let endpoint = format!("ipc://{}", path.display());
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        task::block_on(async {
+            request_reply(&endpoint).await;
+
+            // but a socket file in use is not taken over
+            let _listener = bind(&endpoint, SocketType::REP, logger()).await.unwrap();
+            assert!(matches!(
+                bind(&endpoint, SocketType::REP, logger()).await,
+                Err(TransportError::Io(_))
+            ));
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_namespace() {
+        let endpoint = format!("ipc://@zmqrs-abstract-{}", std::process::id());
+        task::block_on(request_reply(&endpoint));
+    }
+}
@@ -0,0 +1,91 @@
+//! A connection to one peer over any of the transports, as used by sockets.
+
+use async_std::net::TcpStream;
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
+use bytes::Bytes;
+
+use zmqrs_parser::{MetaData, Multipart, SocketType};
+
+use crate::endpoint::Endpoint;
+use crate::inproc::InprocPipe;
+use crate::transport::{Transport, TransportError};
+use crate::{inproc, tcp};
+
+pub enum Pipe {
+    Tcp(Transport<TcpStream>),
+    #[cfg(unix)]
+    Ipc(Transport<UnixStream>),
+    Inproc(InprocPipe),
+}
+
+impl Pipe {
+    /// Connect to `endpoint` with the transport it names.
+    ///
+    /// As with `inproc::connect`, an `inproc://` endpoint needn't be bound yet.
+    pub async fn connect(
+        endpoint: &str,
+        socket_type: SocketType,
+        logger: slog::Logger,
+    ) -> Result<Self, TransportError> {
+        match endpoint.parse()? {
+            Endpoint::Tcp { .. } => Ok(tcp::connect(endpoint, socket_type, logger).await?.into()),
+            #[cfg(unix)]
+            Endpoint::Ipc(_) => Ok(crate::ipc::connect(endpoint, socket_type, logger)
+                .await?
+                .into()),
+            #[cfg(not(unix))]
+            Endpoint::Ipc(_) => Err(crate::EndpointError::TransportMismatch.into()),
+            Endpoint::Inproc(_) => Ok(inproc::connect(endpoint, socket_type, logger)?.into()),
+        }
+    }
+
+    /// The metadata of the peer, once the handshake is done.
+    pub fn peer_metadata(&self) -> Option<&MetaData<Bytes, Bytes>> {
+        match self {
+            Pipe::Tcp(transport) => transport.peer_metadata(),
+            #[cfg(unix)]
+            Pipe::Ipc(transport) => transport.peer_metadata(),
+            Pipe::Inproc(pipe) => pipe.peer_metadata(),
+        }
+    }
+
+    /// Send a message to the peer.
+    pub async fn send(&mut self, msg: Multipart<Bytes>) -> Result<(), TransportError> {
+        match self {
+            Pipe::Tcp(transport) => transport.send(msg).await,
+            #[cfg(unix)]
+            Pipe::Ipc(transport) => transport.send(msg).await,
+            Pipe::Inproc(pipe) => pipe.send(msg),
+        }
+    }
+
+    /// Wait for the next message of the peer.
+    pub async fn recv(&mut self) -> Result<Multipart<Bytes>, TransportError> {
+        match self {
+            Pipe::Tcp(transport) => transport.recv().await,
+            #[cfg(unix)]
+            Pipe::Ipc(transport) => transport.recv().await,
+            Pipe::Inproc(pipe) => pipe.recv().await,
+        }
+    }
+}
+
+impl From<Transport<TcpStream>> for Pipe {
+    fn from(transport: Transport<TcpStream>) -> Self {
+        Pipe::Tcp(transport)
+    }
+}
+
+#[cfg(unix)]
+impl From<Transport<UnixStream>> for Pipe {
+    fn from(transport: Transport<UnixStream>) -> Self {
+        Pipe::Ipc(transport)
+    }
+}
+
+impl From<InprocPipe> for Pipe {
+    fn from(pipe: InprocPipe) -> Self {
+        Pipe::Inproc(pipe)
+    }
+}
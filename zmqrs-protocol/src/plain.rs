@@ -0,0 +1,310 @@
+//! Handshake of the PLAIN security mechanism, see https://rfc.zeromq.org/spec/24/
+//!
+//! The client sends HELLO with its credentials, the server answers WELCOME if they are accepted.
+//! Then the client sends INITIATE and the server READY, both carrying the connection metadata.
+//! The state machines only turn received commands into commands to send; reading and writing
+//! frames is up to the caller.
+
+use zmqrs_parser::{Command, Hello, MetaData};
+
+/// Error reason the server sends if the credentials are rejected.
+pub const INVALID_CREDENTIALS: &[u8] = b"Invalid username or password";
+
+/// Decides whether a client may connect with the given credentials.
+///
+/// Closures `FnMut(&[u8], &[u8]) -> bool` taking username and password implement this trait.
+pub trait PlainAuthenticator {
+    fn authenticate(&mut self, username: &[u8], password: &[u8]) -> bool;
+}
+
+impl<F: FnMut(&[u8], &[u8]) -> bool> PlainAuthenticator for F {
+    fn authenticate(&mut self, username: &[u8], password: &[u8]) -> bool {
+        self(username, password)
+    }
+}
+
+/// What to do after a command of the peer was handled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlainStep<S, T> {
+    /// Send the command and wait for the next command of the peer.
+    Send(Command<S, T>),
+    /// The handshake is complete; send `reply` if present. `peer` is the metadata of the peer.
+    Done {
+        reply: Option<Command<S, T>>,
+        peer: MetaData<S, T>,
+    },
+    /// The handshake failed; send the ERROR command and close the connection.
+    Close(Command<S, T>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlainError<T> {
+    /// The peer sent a command that is not allowed at this point of the handshake.
+    UnexpectedCommand { expected: &'static str },
+    /// The server rejected the handshake with the given reason.
+    Rejected(T),
+}
+
+impl<T: AsRef<[u8]>> core::fmt::Display for PlainError<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            PlainError::UnexpectedCommand { expected } => {
+                write!(f, "Unexpected command, expected {}", expected)
+            }
+            PlainError::Rejected(reason) => write!(
+                f,
+                "Handshake rejected: {}",
+                core::str::from_utf8(reason.as_ref()).unwrap_or("<invalid reason>")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClientState {
+    SendHello,
+    ExpectWelcome,
+    ExpectReady,
+    Done,
+}
+
+/// Client side of the PLAIN handshake.
+#[derive(Debug)]
+pub struct PlainClient<S, T> {
+    state: ClientState,
+    username: T,
+    password: T,
+    metadata: MetaData<S, T>,
+}
+
+impl<S: Clone, T: Clone> PlainClient<S, T> {
+    /// `metadata` is sent with the INITIATE command, e.g. the socket type.
+    pub fn new(username: T, password: T, metadata: MetaData<S, T>) -> Self {
+        PlainClient {
+            state: ClientState::SendHello,
+            username,
+            password,
+            metadata,
+        }
+    }
+
+    /// The HELLO command starting the handshake.
+    pub fn hello(&mut self) -> Command<S, T> {
+        self.state = ClientState::ExpectWelcome;
+        Command::HELLO(Hello {
+            username: self.username.clone(),
+            password: self.password.clone(),
+        })
+    }
+
+    /// Handle the next command of the server.
+    pub fn handle(&mut self, cmd: Command<S, T>) -> Result<PlainStep<S, T>, PlainError<T>> {
+        match (self.state, cmd) {
+            (ClientState::ExpectWelcome, Command::WELCOME) => {
+                self.state = ClientState::ExpectReady;
+                Ok(PlainStep::Send(Command::INITIATE(self.metadata.clone())))
+            }
+            (ClientState::ExpectReady, Command::READY(peer)) => {
+                self.state = ClientState::Done;
+                Ok(PlainStep::Done { reply: None, peer })
+            }
+            (ClientState::ExpectWelcome, Command::ERROR(reason))
+            | (ClientState::ExpectReady, Command::ERROR(reason)) => {
+                self.state = ClientState::Done;
+                Err(PlainError::Rejected(reason.0))
+            }
+            (ClientState::SendHello, _) => Err(PlainError::UnexpectedCommand { expected: "none" }),
+            (ClientState::ExpectWelcome, _) => Err(PlainError::UnexpectedCommand {
+                expected: "WELCOME",
+            }),
+            (ClientState::ExpectReady, _) => {
+                Err(PlainError::UnexpectedCommand { expected: "READY" })
+            }
+            (ClientState::Done, _) => Err(PlainError::UnexpectedCommand { expected: "none" }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerState {
+    ExpectHello,
+    ExpectInitiate,
+    Done,
+}
+
+/// Server side of the PLAIN handshake, checking the credentials with an authenticator.
+#[derive(Debug)]
+pub struct PlainServer<S, T, A> {
+    state: ServerState,
+    authenticator: A,
+    metadata: MetaData<S, T>,
+}
+
+impl<S, T, A> PlainServer<S, T, A>
+where
+    S: Clone,
+    T: Clone + AsRef<[u8]> + From<&'static [u8]>,
+    A: PlainAuthenticator,
+{
+    /// `metadata` is sent with the READY command, e.g. the socket type.
+    pub fn new(authenticator: A, metadata: MetaData<S, T>) -> Self {
+        PlainServer {
+            state: ServerState::ExpectHello,
+            authenticator,
+            metadata,
+        }
+    }
+
+    /// The authenticator, e.g. to look up what it learned about the client.
+    pub fn authenticator(&self) -> &A {
+        &self.authenticator
+    }
+
+    /// Handle the next command of the client.
+    pub fn handle(&mut self, cmd: Command<S, T>) -> Result<PlainStep<S, T>, PlainError<T>> {
+        match (self.state, cmd) {
+            (ServerState::ExpectHello, Command::HELLO(hello)) => {
+                if self
+                    .authenticator
+                    .authenticate(hello.username.as_ref(), hello.password.as_ref())
+                {
+                    self.state = ServerState::ExpectInitiate;
+                    Ok(PlainStep::Send(Command::WELCOME))
+                } else {
+                    self.state = ServerState::Done;
+                    Ok(PlainStep::Close(Command::ERROR(zmqrs_parser::ByteSlice(
+                        INVALID_CREDENTIALS.into(),
+                    ))))
+                }
+            }
+            (ServerState::ExpectInitiate, Command::INITIATE(peer)) => {
+                self.state = ServerState::Done;
+                Ok(PlainStep::Done {
+                    reply: Some(Command::READY(self.metadata.clone())),
+                    peer,
+                })
+            }
+            (ServerState::ExpectHello, _) => {
+                Err(PlainError::UnexpectedCommand { expected: "HELLO" })
+            }
+            (ServerState::ExpectInitiate, _) => Err(PlainError::UnexpectedCommand {
+                expected: "INITIATE",
+            }),
+            (ServerState::Done, _) => Err(PlainError::UnexpectedCommand { expected: "none" }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+    use zmqrs_parser::{frame_limited, Frame, Limits, SecurityMechanism};
+
+    type Cmd = Command<Bytes, Bytes>;
+
+    /// Encode and parse the command again, as if sent over the wire.
+    fn transfer(cmd: Cmd) -> Cmd {
+        let mut logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut buf = BytesMut::new();
+        Frame::Command(cmd).encode(&mut buf).unwrap();
+        let buf = buf.freeze();
+        let (rest, parsed) = frame_limited(
+            &buf,
+            &SecurityMechanism::PLAIN,
+            &Limits::unlimited(),
+            &mut logger,
+        )
+        .unwrap();
+        assert!(rest.is_empty());
+        match Frame::<Bytes, Bytes>::from((&buf, parsed)) {
+            Frame::Command(cmd) => cmd,
+            other => panic!("expected a command, got {:?}", other),
+        }
+    }
+
+    fn metadata(socket_type: &'static [u8]) -> MetaData<Bytes, Bytes> {
+        MetaData::new().with_socket_type(Bytes::from_static(socket_type))
+    }
+
+    fn client(password: &'static [u8]) -> PlainClient<Bytes, Bytes> {
+        PlainClient::new(
+            Bytes::from_static(b"admin"),
+            Bytes::from_static(password),
+            metadata(b"REQ"),
+        )
+    }
+
+    fn server() -> PlainServer<Bytes, Bytes, impl PlainAuthenticator> {
+        PlainServer::new(
+            |username: &[u8], password: &[u8]| username == b"admin" && password == b"secret",
+            metadata(b"REP"),
+        )
+    }
+
+    #[test]
+    fn handshake_accepted() {
+        let mut client = client(b"secret");
+        let mut server = server();
+
+        let welcome = match server.handle(transfer(client.hello())) {
+            Ok(PlainStep::Send(cmd)) => cmd,
+            other => panic!("unexpected step {:?}", other),
+        };
+        let initiate = match client.handle(transfer(welcome)) {
+            Ok(PlainStep::Send(cmd)) => cmd,
+            other => panic!("unexpected step {:?}", other),
+        };
+        let ready = match server.handle(transfer(initiate)) {
+            Ok(PlainStep::Done {
+                reply: Some(ready),
+                peer,
+            }) => {
+                assert_eq!(peer.socket_type(), Some(&Bytes::from_static(b"REQ")));
+                ready
+            }
+            other => panic!("unexpected step {:?}", other),
+        };
+        match client.handle(transfer(ready)) {
+            Ok(PlainStep::Done { reply: None, peer }) => {
+                assert_eq!(peer.socket_type(), Some(&Bytes::from_static(b"REP")))
+            }
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_rejected() {
+        let mut client = client(b"wrong");
+        let mut server = server();
+
+        let error = match server.handle(transfer(client.hello())) {
+            Ok(PlainStep::Close(cmd)) => cmd,
+            other => panic!("unexpected step {:?}", other),
+        };
+        assert_eq!(
+            client.handle(transfer(error)),
+            Err(PlainError::Rejected(Bytes::from_static(
+                INVALID_CREDENTIALS
+            )))
+        );
+    }
+
+    #[test]
+    fn unexpected_commands() {
+        let mut server = server();
+        assert_eq!(
+            server.handle(Command::INITIATE(metadata(b"REQ"))),
+            Err(PlainError::UnexpectedCommand { expected: "HELLO" })
+        );
+
+        let mut client = client(b"secret");
+        client.hello();
+        assert_eq!(
+            client.handle(Command::READY(metadata(b"REP"))),
+            Err(PlainError::UnexpectedCommand {
+                expected: "WELCOME"
+            })
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! The REQ socket, see https://rfc.zeromq.org/spec/28/
+//!
+//! Each request goes to the next peer in turn, preceded by an empty delimiter frame. A REQ
+//! socket strictly alternates between sending a request and receiving its reply; replies from
+//! other peers or without delimiter are dropped.
+//!
+//! Like with `ZMQ_REQ_RELAXED` of libzmq, a relaxed socket may send a new request instead of
+//! waiting for the reply, which is then dropped. With `ZMQ_REQ_CORRELATE`, each request is
+//! preceded by an id, so that replies to earlier requests are recognized.
+
+use bytes::Bytes;
+use zmqrs_parser::{Multipart, SocketType};
+
+use crate::pipe::Pipe;
+use crate::transport::TransportError;
+
+#[derive(Debug)]
+pub enum ReqError {
+    /// A request was sent before the reply to the last one arrived, or a reply was asked for
+    /// without a request; `EFSM` in libzmq.
+    InvalidState,
+    /// There is no peer to send the request to.
+    NoPeers,
+    /// The connection to the peer failed; it was removed.
+    Transport(TransportError),
+}
+
+impl core::fmt::Display for ReqError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            ReqError::InvalidState => write!(f, "Operation not possible in the current state"),
+            ReqError::NoPeers => write!(f, "No peers connected"),
+            ReqError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReqError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReqError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransportError> for ReqError {
+    fn from(e: TransportError) -> Self {
+        ReqError::Transport(e)
+    }
+}
+
+/// The request a reply is expected for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pending {
+    /// Index of the peer the request went to.
+    peer: usize,
+    id: u32,
+}
+
+pub struct ReqSocket {
+    pipes: Vec<Pipe>,
+    /// Index of the peer to send the next request to.
+    next: usize,
+    pending: Option<Pending>,
+    request_id: u32,
+    relaxed: bool,
+    correlate: bool,
+    logger: slog::Logger,
+}
+
+impl ReqSocket {
+    pub fn new<L: Into<slog::Logger>>(logger: L) -> Self {
+        ReqSocket {
+            pipes: Vec::new(),
+            next: 0,
+            pending: None,
+            request_id: 0,
+            relaxed: false,
+            correlate: false,
+            logger: logger.into(),
+        }
+    }
+
+    /// Allow sending a new request before the reply arrived, like `ZMQ_REQ_RELAXED`.
+    pub fn with_relaxed(mut self, relaxed: bool) -> Self {
+        self.relaxed = relaxed;
+        self
+    }
+
+    /// Prefix requests with an id the reply has to carry, like `ZMQ_REQ_CORRELATE`.
+    pub fn with_correlate(mut self, correlate: bool) -> Self {
+        self.correlate = correlate;
+        self
+    }
+
+    /// The number of peers requests are distributed to.
+    pub fn peers(&self) -> usize {
+        self.pipes.len()
+    }
+
+    /// Connect to another peer.
+    pub async fn connect(&mut self, endpoint: &str) -> Result<(), ReqError> {
+        let pipe = Pipe::connect(endpoint, SocketType::REQ, self.logger.clone()).await?;
+        self.attach(pipe);
+        Ok(())
+    }
+
+    /// Add a peer connected as a REQ socket, e.g. one accepted by a listener.
+    pub fn attach<P: Into<Pipe>>(&mut self, pipe: P) {
+        self.pipes.push(pipe.into());
+    }
+
+    /// Send a request to the next peer; peers whose connection failed are removed and skipped.
+    pub async fn send(&mut self, msg: Multipart<Bytes>) -> Result<(), ReqError> {
+        if self.pending.is_some() && !self.relaxed {
+            return Err(ReqError::InvalidState);
+        }
+        if self.pipes.is_empty() {
+            return Err(ReqError::NoPeers);
+        }
+        // a reply to an earlier request is dropped from now on
+        self.pending = None;
+        self.request_id = self.request_id.wrapping_add(1);
+
+        let mut request = Multipart::new();
+        if self.correlate {
+            request.push(Bytes::copy_from_slice(&self.request_id.to_be_bytes()));
+        }
+        request.push(Bytes::new());
+        for part in msg {
+            request.push(part);
+        }
+
+        // failed peers are removed and the request goes to the next one
+        while !self.pipes.is_empty() {
+            let peer = self.next % self.pipes.len();
+            self.next = peer + 1;
+            match self.pipes[peer].send(request.clone()).await {
+                Ok(()) => {
+                    self.pending = Some(Pending {
+                        peer,
+                        id: self.request_id,
+                    });
+                    return Ok(());
+                }
+                Err(e) => {
+                    debug!(self.logger, "sending request failed"; "error" => %e);
+                    self.remove_pipe(peer);
+                }
+            }
+        }
+        Err(ReqError::NoPeers)
+    }
+
+    /// Wait for the reply to the last request.
+    pub async fn recv(&mut self) -> Result<Multipart<Bytes>, ReqError> {
+        let pending = self.pending.ok_or(ReqError::InvalidState)?;
+        loop {
+            let reply = match self.pipes[pending.peer].recv().await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    self.remove_pipe(pending.peer);
+                    return Err(e.into());
+                }
+            };
+            if let Some(body) = self.strip_envelope(reply, pending.id) {
+                self.pending = None;
+                return Ok(body);
+            }
+            debug!(self.logger, "dropped unexpected reply");
+        }
+    }
+
+    /// The body of `reply`, if it answers the request `id`.
+    fn strip_envelope(&self, reply: Multipart<Bytes>, id: u32) -> Option<Multipart<Bytes>> {
+        let mut parts = reply.into_iter();
+        if self.correlate && parts.next()?[..] != id.to_be_bytes() {
+            return None;
+        }
+        if !parts.next()?.is_empty() {
+            return None;
+        }
+        Some(parts.collect::<Vec<_>>().into())
+    }
+
+    fn remove_pipe(&mut self, peer: usize) {
+        warn!(self.logger, "removed failed peer"; "peer" => peer);
+        self.pipes.remove(peer);
+        if self.next > peer {
+            self.next -= 1;
+        }
+        self.pending = match self.pending {
+            // the reply is lost, so allow sending the request again
+            Some(pending) if pending.peer == peer => None,
+            Some(pending) if pending.peer > peer => Some(Pending {
+                peer: pending.peer - 1,
+                ..pending
+            }),
+            pending => pending,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inproc::{self, InprocListener, InprocPipe};
+    use crate::tcp;
+    use async_std::task;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn msg(body: &'static [u8]) -> Multipart<Bytes> {
+        Multipart::from(vec![Bytes::from_static(body)])
+    }
+
+    /// The other side of the next connection, standing in for a REP socket.
+    async fn rep_peer(listener: &mut InprocListener) -> InprocPipe {
+        listener.accept().await.unwrap()
+    }
+
+    /// Answer the next request with `body`, keeping the envelope.
+    async fn reply(peer: &mut InprocPipe, body: &'static [u8]) -> Multipart<Bytes> {
+        let request = peer.recv().await.unwrap();
+        let mut reply: Vec<_> = request.parts()[..request.len() - 1].to_vec();
+        reply.push(Bytes::from_static(body));
+        peer.send(reply.into()).unwrap();
+        request
+    }
+
+    #[test]
+    fn hello_world() {
+        task::block_on(async {
+            let listener = tcp::bind("tcp://127.0.0.1:0", SocketType::REP, logger())
+                .await
+                .unwrap();
+            let endpoint = listener.endpoint().unwrap().to_string();
+            let server = task::spawn(async move {
+                let mut server = listener.accept().await.unwrap();
+                for _ in 0..3 {
+                    let request = server.recv().await.unwrap();
+                    assert_eq!(request, Multipart::from(vec![Bytes::new(), "Hello".into()]));
+                    server
+                        .send(Multipart::from(vec![Bytes::new(), "World".into()]))
+                        .await
+                        .unwrap();
+                }
+            });
+
+            let mut req = ReqSocket::new(logger());
+            req.connect(&endpoint).await.unwrap();
+            for _ in 0..3 {
+                req.send(msg(b"Hello")).await.unwrap();
+                assert_eq!(req.recv().await.unwrap(), msg(b"World"));
+            }
+            server.await;
+        });
+    }
+
+    #[test]
+    fn strict_alternation() {
+        task::block_on(async {
+            let mut listener =
+                inproc::bind("inproc://req_strict", SocketType::REP, logger()).unwrap();
+            let mut req = ReqSocket::new(logger());
+            assert!(matches!(req.send(msg(b"A")).await, Err(ReqError::NoPeers)));
+            req.connect("inproc://req_strict").await.unwrap();
+            let mut peer = rep_peer(&mut listener).await;
+
+            assert!(matches!(req.recv().await, Err(ReqError::InvalidState)));
+            req.send(msg(b"A")).await.unwrap();
+            assert!(matches!(
+                req.send(msg(b"B")).await,
+                Err(ReqError::InvalidState)
+            ));
+
+            // a reply without delimiter is dropped
+            peer.recv().await.unwrap();
+            peer.send(msg(b"bogus")).unwrap();
+            peer.send(Multipart::from(vec![Bytes::new(), "a".into()]))
+                .unwrap();
+            assert_eq!(req.recv().await.unwrap(), msg(b"a"));
+            assert!(matches!(req.recv().await, Err(ReqError::InvalidState)));
+        });
+    }
+
+    #[test]
+    fn round_robin() {
+        task::block_on(async {
+            let mut listener =
+                inproc::bind("inproc://req_round_robin", SocketType::REP, logger()).unwrap();
+            let mut req = ReqSocket::new(logger());
+            req.connect("inproc://req_round_robin").await.unwrap();
+            req.connect("inproc://req_round_robin").await.unwrap();
+            assert_eq!(req.peers(), 2);
+            let mut first = rep_peer(&mut listener).await;
+            let mut second = rep_peer(&mut listener).await;
+
+            for _ in 0..2 {
+                req.send(msg(b"A")).await.unwrap();
+                reply(&mut first, b"first").await;
+                assert_eq!(req.recv().await.unwrap(), msg(b"first"));
+
+                req.send(msg(b"B")).await.unwrap();
+                reply(&mut second, b"second").await;
+                assert_eq!(req.recv().await.unwrap(), msg(b"second"));
+            }
+
+            // a peer going away is skipped from then on
+            drop(second);
+            req.send(msg(b"C")).await.unwrap();
+            reply(&mut first, b"first").await;
+            assert_eq!(req.recv().await.unwrap(), msg(b"first"));
+            req.send(msg(b"D")).await.unwrap();
+            assert_eq!(req.peers(), 1);
+            let request = reply(&mut first, b"first").await;
+            assert_eq!(request.parts()[1], Bytes::from_static(b"D"));
+            assert_eq!(req.recv().await.unwrap(), msg(b"first"));
+
+            // without any peers left, there's nobody to send to
+            drop(first);
+            assert!(matches!(req.send(msg(b"E")).await, Err(ReqError::NoPeers)));
+            assert_eq!(req.peers(), 0);
+        });
+    }
+
+    #[test]
+    fn relaxed_and_correlate() {
+        task::block_on(async {
+            let mut listener =
+                inproc::bind("inproc://req_relaxed", SocketType::REP, logger()).unwrap();
+            let mut req = ReqSocket::new(logger())
+                .with_relaxed(true)
+                .with_correlate(true);
+            req.connect("inproc://req_relaxed").await.unwrap();
+            let mut peer = rep_peer(&mut listener).await;
+
+            req.send(msg(b"A")).await.unwrap();
+            let first = peer.recv().await.unwrap();
+            assert_eq!(first.len(), 3);
+            assert_eq!(first.parts()[0].len(), 4);
+
+            // the first request gets no answer in time, so it is sent again
+            req.send(msg(b"A")).await.unwrap();
+            let second = peer.recv().await.unwrap();
+            assert_ne!(first.parts()[0], second.parts()[0]);
+
+            // the late reply to the first request is dropped
+            let late: Vec<_> = first.parts()[..2]
+                .iter()
+                .cloned()
+                .chain(Some("late".into()))
+                .collect();
+            peer.send(late.into()).unwrap();
+            let answer: Vec<_> = second.parts()[..2]
+                .iter()
+                .cloned()
+                .chain(Some("answer".into()))
+                .collect();
+            peer.send(answer.into()).unwrap();
+            assert_eq!(req.recv().await.unwrap(), msg(b"answer"));
+        });
+    }
+}
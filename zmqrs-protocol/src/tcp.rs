@@ -0,0 +1,369 @@
+//! The `tcp://` transport, see https://rfc.zeromq.org/spec/23/
+
+use async_std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use zmqrs_parser::SocketType;
+
+use crate::connection::Role;
+use crate::endpoint::{Endpoint, EndpointError, Host, TcpAddress};
+use crate::transport::{Transport, TransportError};
+
+/// The source and address of a `tcp://` endpoint.
+fn address(endpoint: &str) -> Result<(Option<TcpAddress>, TcpAddress), TransportError> {
+    match endpoint.parse()? {
+        Endpoint::Tcp { source, address } => Ok((source, address)),
+        _ => Err(EndpointError::TransportMismatch.into()),
+    }
+}
+
+async fn resolve(address: &TcpAddress) -> io::Result<Vec<SocketAddr>> {
+    Ok(address
+        .to_socket_addr_string()
+        .to_socket_addrs()
+        .await?
+        .collect())
+}
+
+/// Connect to `address` from the local address `source`.
+async fn connect_from(source: &TcpAddress, address: &TcpAddress) -> io::Result<TcpStream> {
+    let source = resolve(source).await?.into_iter().next();
+    let source = source.ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+    let addr = resolve(address)
+        .await?
+        .into_iter()
+        .find(|addr| addr.is_ipv4() == source.is_ipv4());
+    let addr = addr.ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+    let stream = async_std::task::spawn_blocking(move || {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.bind(&source.into())?;
+        socket.connect(&addr.into())?;
+        io::Result::Ok(std::net::TcpStream::from(socket))
+    })
+    .await?;
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from(stream))
+}
+
+/// Connect to `endpoint` and run the handshake as a socket of type `socket_type`.
+///
+/// With a source address, as in `tcp://192.168.1.2;example.com:5555`, the connection is made from
+/// that address.
+pub async fn connect<L: Into<slog::Logger>>(
+    endpoint: &str,
+    socket_type: SocketType,
+    logger: L,
+) -> Result<Transport<TcpStream>, TransportError> {
+    let logger = logger.into();
+    let (source, address) = address(endpoint)?;
+    if address.host == Host::Any {
+        return Err(EndpointError::InvalidAddress.into());
+    }
+    if address.port == 0 {
+        return Err(EndpointError::InvalidPort.into());
+    }
+    let stream = match source {
+        Some(source) => connect_from(&source, &address).await?,
+        None => TcpStream::connect(address.to_socket_addr_string()).await?,
+    };
+    stream.set_nodelay(true)?;
+    debug!(logger, "connected"; "endpoint" => endpoint);
+
+    Transport::establish(stream, Role::Client, socket_type, logger).await
+}
+
+/// Listen on `endpoint` for peers of a socket of type `socket_type`.
+///
+/// A `*` host listens on all interfaces and port 0 picks an ephemeral port, see `endpoint`.
+pub async fn bind<L: Into<slog::Logger>>(
+    endpoint: &str,
+    socket_type: SocketType,
+    logger: L,
+) -> Result<TcpListener, TransportError> {
+    let logger = logger.into();
+    let (source, address) = address(endpoint)?;
+    if source.is_some() {
+        return Err(EndpointError::InvalidAddress.into());
+    }
+    let listener = async_std::net::TcpListener::bind(address.to_socket_addr_string()).await?;
+    debug!(logger, "bound"; "endpoint" => endpoint);
+
+    Ok(TcpListener {
+        listener,
+        socket_type,
+        logger,
+    })
+}
+
+pub struct TcpListener {
+    listener: async_std::net::TcpListener,
+    socket_type: SocketType,
+    logger: slog::Logger,
+}
+
+impl TcpListener {
+    /// The address actually bound to, e.g. to find out the port chosen for port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, TransportError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// The endpoint actually bound to, like `ZMQ_LAST_ENDPOINT` of libzmq.
+    ///
+    /// Peers can connect to it, unless it was bound to all interfaces.
+    pub fn endpoint(&self) -> Result<Endpoint, TransportError> {
+        let addr = self.local_addr()?;
+        Ok(Endpoint::Tcp {
+            source: None,
+            address: TcpAddress {
+                host: Host::Ip(addr.ip()),
+                port: addr.port(),
+            },
+        })
+    }
+
+    /// Wait for the next peer and run the handshake with it.
+    pub async fn accept(&self) -> Result<Transport<TcpStream>, TransportError> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+        stream.set_nodelay(true)?;
+        let logger = self.logger.new(o!("peer" => peer_addr.to_string()));
+        debug!(logger, "accepted");
+
+        Transport::establish(stream, Role::Server, self.socket_type, logger).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Connection, Event, ProtocolError};
+    use crate::heartbeat::{HeartbeatError, HeartbeatOptions, ManualClock};
+    use async_std::task;
+    use bytes::Bytes;
+    use core::time::Duration;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use zmqrs_parser::Multipart;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn hello() -> Multipart<Bytes> {
+        Multipart::from(vec![Bytes::new(), Bytes::from_static(b"Hello")])
+    }
+
+    fn world() -> Multipart<Bytes> {
+        Multipart::from(vec![Bytes::new(), Bytes::from_static(b"World")])
+    }
+
+    #[test]
+    fn endpoint_addresses() {
+        let addr = |endpoint| address(endpoint).unwrap().1.to_socket_addr_string();
+        assert_eq!(addr("tcp://127.0.0.1:5555"), "127.0.0.1:5555");
+        assert_eq!(addr("tcp://*:5555"), "0.0.0.0:5555");
+        assert_eq!(addr("tcp://[::1]:5555"), "[::1]:5555");
+        assert!(matches!(
+            address("ipc:///tmp/socket"),
+            Err(TransportError::InvalidEndpoint(
+                EndpointError::TransportMismatch
+            ))
+        ));
+    }
+
+    #[test]
+    fn hello_world_capture() {
+        task::block_on(async {
+            let listener = bind("tcp://127.0.0.1:0", SocketType::REP, logger())
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // the client side as captured from the hello_world python example
+            let client = task::spawn(async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                let mut greeting = vec![0xff, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x7f, 0x03, 0x00];
+                greeting.extend_from_slice(b"NULL");
+                greeting.resize(64, 0);
+                stream.write_all(&greeting).await.unwrap();
+                stream
+                    .write_all(b"\x04\x26\x05READY\x0bSocket-Type\x00\x00\x00\x03REQ")
+                    .await
+                    .unwrap();
+                stream
+                    .write_all(b"\x08Identity\x00\x00\x00\x00\x01\x00\x00\x05Hello")
+                    .await
+                    .unwrap();
+
+                let mut received = vec![0u8; 64 + 27 + 9];
+                stream.read_exact(&mut received).await.unwrap();
+                received
+            });
+
+            let mut server = listener.accept().await.unwrap();
+            assert_eq!(
+                server.peer_metadata().unwrap().socket_type(),
+                Some(&Bytes::from_static(b"REQ"))
+            );
+            assert_eq!(server.recv().await.unwrap(), hello());
+            server.send(world()).await.unwrap();
+
+            let received = client.await;
+            assert_eq!(
+                &received[..11],
+                b"\xff\x00\x00\x00\x00\x00\x00\x00\x01\x7f\x03"
+            );
+            assert_eq!(
+                &received[64..],
+                &b"\x04\x19\x05READY\x0bSocket-Type\x00\x00\x00\x03REP\x01\x00\x00\x05World"[..]
+            );
+        });
+    }
+
+    #[test]
+    fn connect_and_bind() {
+        task::block_on(async {
+            let listener = bind("tcp://127.0.0.1:0", SocketType::REP, logger())
+                .await
+                .unwrap();
+            let endpoint = listener.endpoint().unwrap().to_string();
+            assert!(endpoint.starts_with("tcp://127.0.0.1:"));
+            assert!(!endpoint.ends_with(":0"));
+
+            let server = task::spawn(async move {
+                let mut server = listener.accept().await.unwrap();
+                let request = server.recv().await.unwrap();
+                server.send(world()).await.unwrap();
+                request
+            });
+
+            let mut client = connect(&endpoint, SocketType::REQ, logger()).await.unwrap();
+            client.send(hello()).await.unwrap();
+            assert_eq!(client.recv().await.unwrap(), world());
+            assert_eq!(server.await, hello());
+
+            // the server is gone now
+            assert!(matches!(
+                client.recv().await,
+                Err(TransportError::Closed) | Err(TransportError::Io(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn source_address() {
+        task::block_on(async {
+            let listener = bind("tcp://*:0", SocketType::REP, logger()).await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = task::spawn(async move {
+                let mut server = listener.accept().await.unwrap();
+                server.recv().await.unwrap()
+            });
+
+            let endpoint = format!("tcp://127.0.0.2;127.0.0.1:{}", port);
+            let mut client = connect(&endpoint, SocketType::REQ, logger()).await.unwrap();
+            client.send(hello()).await.unwrap();
+            assert_eq!(server.await, hello());
+            let local = client.into_inner().local_addr().unwrap();
+            assert_eq!(local.ip().to_string(), "127.0.0.2");
+
+            assert!(matches!(
+                connect("tcp://*:5555", SocketType::REQ, logger()).await,
+                Err(TransportError::InvalidEndpoint(
+                    EndpointError::InvalidAddress
+                ))
+            ));
+            assert!(matches!(
+                connect("tcp://127.0.0.1:0", SocketType::REQ, logger()).await,
+                Err(TransportError::InvalidEndpoint(EndpointError::InvalidPort))
+            ));
+        });
+    }
+
+    #[test]
+    fn heartbeats() {
+        task::block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // a ZMTP 3.1 ROUTER peer, written out by hand to see the heartbeats on the wire
+            let server = task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut greeting = vec![0xff, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x7f, 0x03, 0x01];
+                greeting.extend_from_slice(b"NULL");
+                greeting.resize(64, 0);
+                stream.write_all(&greeting).await.unwrap();
+                stream
+                    .write_all(b"\x04\x1c\x05READY\x0bSocket-Type\x00\x00\x00\x06ROUTER")
+                    .await
+                    .unwrap();
+                let mut handshake = vec![0u8; 64 + 30];
+                stream.read_exact(&mut handshake).await.unwrap();
+                stream
+            });
+
+            let clock = ManualClock::new();
+            let options = HeartbeatOptions::default()
+                .with_interval(Duration::from_secs(1))
+                .with_timeout(Duration::from_millis(100));
+            let connection = Connection::new(Role::Client, SocketType::DEALER, logger())
+                .with_heartbeat(options, clock.clone());
+            let mut client = Transport::new(TcpStream::connect(addr).await.unwrap(), connection);
+            client.handshake().await.unwrap();
+            let mut server = server.await;
+
+            // the PING is due while the client waits for data; the PONG keeps it alive
+            clock.advance(Duration::from_secs(1));
+            let answering = task::spawn(async move {
+                let mut ping = [0u8; 9];
+                server.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"\x04\x07\x04PING\x00\x00");
+                server
+                    .write_all(b"\x04\x05\x04PONG\x00\x02hi")
+                    .await
+                    .unwrap();
+                server
+            });
+            match client.next_event().await.unwrap() {
+                Event::Message(msg) => assert_eq!(msg.parts(), &[Bytes::from_static(b"hi")][..]),
+                other => panic!("unexpected event {:?}", other),
+            }
+            let mut server = answering.await;
+
+            // without an answer to the next PING the connection fails
+            clock.advance(Duration::from_secs(1));
+            let silent = task::spawn({
+                let clock = clock.clone();
+                async move {
+                    let mut ping = [0u8; 9];
+                    server.read_exact(&mut ping).await.unwrap();
+                    clock.advance(Duration::from_millis(100));
+                    server
+                }
+            });
+            assert!(matches!(
+                client.next_event().await,
+                Err(TransportError::Protocol(ProtocolError::Heartbeat(
+                    HeartbeatError::Timeout
+                )))
+            ));
+            silent.await;
+        });
+    }
+
+    #[test]
+    fn incompatible_peer() {
+        task::block_on(async {
+            let listener = bind("tcp://127.0.0.1:0", SocketType::PUB, logger())
+                .await
+                .unwrap();
+            let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+
+            let server = task::spawn(async move { listener.accept().await.err() });
+            let client = connect(&endpoint, SocketType::REQ, logger()).await;
+            assert!(matches!(client.err(), Some(TransportError::Protocol(_))));
+            assert!(server.await.is_some());
+        });
+    }
+}
@@ -0,0 +1,183 @@
+//! Driving a `Connection` over a byte stream, i.e. anything implementing the `AsyncRead` and
+//! `AsyncWrite` traits of the futures crate.
+
+use async_std::future;
+use bytes::Bytes;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use zmqrs_parser::{MetaData, Multipart, SocketType};
+
+use crate::connection::{Connection, Event, ProtocolError, Role};
+use crate::endpoint::EndpointError;
+
+/// Size of the buffer data is read into from the stream.
+const READ_BUFFER_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// Reading from or writing to the stream failed.
+    Io(std::io::Error),
+    /// The peer violated the protocol or rejected the connection.
+    Protocol(ProtocolError),
+    /// The endpoint is malformed or can't be used with this transport.
+    InvalidEndpoint(EndpointError),
+    /// The peer closed the connection.
+    Closed,
+}
+
+impl core::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            TransportError::Io(e) => write!(f, "IoError: {}", e),
+            TransportError::Protocol(e) => write!(f, "{}", e),
+            TransportError::InvalidEndpoint(e) => write!(f, "Invalid endpoint: {}", e),
+            TransportError::Closed => write!(f, "Connection closed by peer"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Io(e) => Some(e),
+            TransportError::InvalidEndpoint(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(e: std::io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<EndpointError> for TransportError {
+    fn from(e: EndpointError) -> Self {
+        TransportError::InvalidEndpoint(e)
+    }
+}
+
+impl From<ProtocolError> for TransportError {
+    fn from(e: ProtocolError) -> Self {
+        TransportError::Protocol(e)
+    }
+}
+
+/// A `Connection` talking to its peer over `stream`.
+pub struct Transport<S> {
+    stream: S,
+    connection: Connection,
+    peer: Option<MetaData<Bytes, Bytes>>,
+    buffer: Box<[u8]>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Transport<S> {
+    pub fn new(stream: S, connection: Connection) -> Self {
+        Transport {
+            stream,
+            connection,
+            peer: None,
+            buffer: vec![0u8; READ_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Run the handshake on a freshly connected or accepted `stream`.
+    pub(crate) async fn establish(
+        stream: S,
+        role: Role,
+        socket_type: SocketType,
+        logger: slog::Logger,
+    ) -> Result<Self, TransportError> {
+        let mut transport = Transport::new(stream, Connection::new(role, socket_type, logger));
+        transport.handshake().await?;
+        Ok(transport)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// The metadata of the peer, once the handshake is done.
+    pub fn peer_metadata(&self) -> Option<&MetaData<Bytes, Bytes>> {
+        self.peer.as_ref()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Exchange greetings and metadata with the peer.
+    pub async fn handshake(&mut self) -> Result<(), TransportError> {
+        while self.peer.is_none() {
+            self.next_event().await?;
+        }
+        Ok(())
+    }
+
+    /// Send a message to the peer.
+    pub async fn send(&mut self, msg: Multipart<Bytes>) -> Result<(), TransportError> {
+        self.connection.send_message(msg)?;
+        self.flush().await
+    }
+
+    /// Wait for the next message of the peer.
+    ///
+    /// Commands arriving meanwhile are dropped; use `next_event` to see them.
+    pub async fn recv(&mut self) -> Result<Multipart<Bytes>, TransportError> {
+        loop {
+            if let Event::Message(msg) = self.next_event().await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Wait for the next event of the connection.
+    ///
+    /// A failed connection is reported as `TransportError::Protocol`, after the pending output,
+    /// e.g. an ERROR command, was sent. Heartbeats are sent and checked while waiting.
+    pub async fn next_event(&mut self) -> Result<Event, TransportError> {
+        loop {
+            self.flush().await?;
+            match self.connection.poll_event() {
+                Some(Event::Failed(e)) => return Err(e.into()),
+                Some(Event::Ready(peer)) => {
+                    self.peer = Some(peer.clone());
+                    return Ok(Event::Ready(peer));
+                }
+                Some(event) => return Ok(event),
+                None => {}
+            }
+
+            // wait for data, but no longer than until heartbeats are due
+            let read = self.stream.read(&mut self.buffer);
+            let len = match self.connection.time_to_timeout() {
+                Some(left) => match future::timeout(left, read).await {
+                    Ok(len) => len?,
+                    Err(_) => {
+                        self.connection.handle_timeout();
+                        continue;
+                    }
+                },
+                None => read.await?,
+            };
+            if len == 0 {
+                return Err(TransportError::Closed);
+            }
+            self.connection.feed(&self.buffer[..len]);
+        }
+    }
+
+    /// Write all pending output of the connection.
+    async fn flush(&mut self) -> Result<(), TransportError> {
+        let mut written = false;
+        while let Some(data) = self.connection.poll_output() {
+            self.stream.write_all(&data).await?;
+            written = true;
+        }
+        if written {
+            self.stream.flush().await?;
+        }
+        Ok(())
+    }
+}
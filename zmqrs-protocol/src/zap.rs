@@ -0,0 +1,454 @@
+//! ZeroMQ Authentication Protocol, see https://rfc.zeromq.org/spec/27/
+//!
+//! The security handshakes ask a ZAP handler whether a peer may connect. libzmq sends the
+//! requests over a REQ socket connected to `inproc://zeromq.zap.01`; here the handler is a plain
+//! callback, and requests and replies can be converted to and from the messages of the wire
+//! format to talk to a handler on a socket.
+
+use alloc::vec::Vec;
+use bytes::Bytes;
+
+use zmqrs_parser::{properties, Limits, MetaData, Multipart, SecurityMechanism, CURVE_KEY_LEN};
+
+use crate::{CurveAuthenticator, PlainAuthenticator};
+
+/// Endpoint libzmq expects the ZAP handler to be bound to.
+pub const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+/// The only version of the protocol.
+pub const ZAP_VERSION: &[u8] = b"1.0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZapError {
+    /// The message has fewer frames than required.
+    MissingFrames,
+    UnsupportedVersion,
+    UnknownMechanism,
+    UnknownStatusCode,
+    MalformedMetadata,
+}
+
+impl core::fmt::Display for ZapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            ZapError::MissingFrames => write!(f, "ZAP message is incomplete"),
+            ZapError::UnsupportedVersion => write!(f, "Unsupported ZAP version"),
+            ZapError::UnknownMechanism => write!(f, "Unknown security mechanism"),
+            ZapError::UnknownStatusCode => write!(f, "Unknown ZAP status code"),
+            ZapError::MalformedMetadata => write!(f, "Malformed ZAP metadata"),
+        }
+    }
+}
+
+/// Data of the connecting peer the handler decides on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZapRequest {
+    /// Security domain of the server socket, may be empty.
+    pub domain: Bytes,
+    /// Address of the peer, e.g. its IP address.
+    pub address: Bytes,
+    /// Identity of the server socket.
+    pub identity: Bytes,
+    pub mechanism: SecurityMechanism,
+    /// None for NULL, username and password for PLAIN, the client's public key for CURVE.
+    pub credentials: Vec<Bytes>,
+}
+
+fn mechanism_from_name(name: &[u8]) -> Result<SecurityMechanism, ZapError> {
+    match name {
+        b"NULL" => Ok(SecurityMechanism::NULL),
+        b"PLAIN" => Ok(SecurityMechanism::PLAIN),
+        b"CURVE" => Ok(SecurityMechanism::CURVE),
+        _ => Err(ZapError::UnknownMechanism),
+    }
+}
+
+/// Take the frames following the version, which has to be `ZAP_VERSION`.
+fn frames_after_version(msg: &Multipart<Bytes>) -> Result<&[Bytes], ZapError> {
+    match msg.parts().split_first() {
+        Some((version, rest)) if version.as_ref() == ZAP_VERSION => Ok(rest),
+        Some(_) => Err(ZapError::UnsupportedVersion),
+        None => Err(ZapError::MissingFrames),
+    }
+}
+
+impl ZapRequest {
+    /// The request message, without the empty delimiter added by the REQ socket.
+    ///
+    /// zap-request = version request-id domain address identity mechanism credentials
+    pub fn to_message(&self, request_id: Bytes) -> Multipart<Bytes> {
+        let mut msg = Multipart::new()
+            .with_part(Bytes::from_static(ZAP_VERSION))
+            .with_part(request_id)
+            .with_part(self.domain.clone())
+            .with_part(self.address.clone())
+            .with_part(self.identity.clone())
+            .with_part(Bytes::from_static(self.mechanism.name()));
+        for credential in &self.credentials {
+            msg.push(credential.clone());
+        }
+        msg
+    }
+
+    /// Parse a request message; returns the request id along with the request.
+    pub fn from_message(msg: &Multipart<Bytes>) -> Result<(Bytes, Self), ZapError> {
+        let frames = frames_after_version(msg)?;
+        match frames {
+            [request_id, domain, address, identity, mechanism, credentials @ ..] => Ok((
+                request_id.clone(),
+                ZapRequest {
+                    domain: domain.clone(),
+                    address: address.clone(),
+                    identity: identity.clone(),
+                    mechanism: mechanism_from_name(mechanism)?,
+                    credentials: credentials.to_vec(),
+                },
+            )),
+            _ => Err(ZapError::MissingFrames),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZapStatus {
+    /// 200, the peer may connect.
+    Success,
+    /// 300, the handler could not decide, e.g. a backend was not available.
+    TemporaryError,
+    /// 400, the peer is rejected.
+    AuthenticationFailure,
+    /// 500, the request was malformed or the handler failed.
+    InternalError,
+}
+
+impl ZapStatus {
+    pub fn code(&self) -> &'static [u8] {
+        match self {
+            ZapStatus::Success => b"200",
+            ZapStatus::TemporaryError => b"300",
+            ZapStatus::AuthenticationFailure => b"400",
+            ZapStatus::InternalError => b"500",
+        }
+    }
+
+    pub fn from_code(code: &[u8]) -> Result<Self, ZapError> {
+        match code {
+            b"200" => Ok(ZapStatus::Success),
+            b"300" => Ok(ZapStatus::TemporaryError),
+            b"400" => Ok(ZapStatus::AuthenticationFailure),
+            b"500" => Ok(ZapStatus::InternalError),
+            _ => Err(ZapError::UnknownStatusCode),
+        }
+    }
+}
+
+/// Decision of the handler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZapReply {
+    pub status: ZapStatus,
+    pub status_text: Bytes,
+    /// The user the peer was authenticated as; empty unless the status is `Success`.
+    pub user_id: Bytes,
+    /// Properties added to the metadata of the connection.
+    pub metadata: MetaData<Bytes, Bytes>,
+}
+
+impl ZapReply {
+    /// Accept the peer as `user_id`.
+    pub fn success(user_id: Bytes) -> Self {
+        ZapReply {
+            status: ZapStatus::Success,
+            status_text: Bytes::from_static(b"OK"),
+            user_id,
+            metadata: MetaData::new(),
+        }
+    }
+
+    /// Reject the peer with the given reason.
+    pub fn failure(status: ZapStatus, status_text: Bytes) -> Self {
+        ZapReply {
+            status,
+            status_text,
+            user_id: Bytes::new(),
+            metadata: MetaData::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: MetaData<Bytes, Bytes>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == ZapStatus::Success
+    }
+
+    /// The reply message, without the empty delimiter added by the REP socket.
+    ///
+    /// Fails with `MalformedMetadata` if the metadata can't be encoded.
+    ///
+    /// zap-reply = version request-id status-code status-text user-id metadata
+    pub fn to_message(&self, request_id: Bytes) -> Result<Multipart<Bytes>, ZapError> {
+        let mut metadata = Vec::with_capacity(self.metadata.encoded_len());
+        self.metadata
+            .encode(&mut metadata)
+            .map_err(|_| ZapError::MalformedMetadata)?;
+        Ok(Multipart::new()
+            .with_part(Bytes::from_static(ZAP_VERSION))
+            .with_part(request_id)
+            .with_part(Bytes::from_static(self.status.code()))
+            .with_part(self.status_text.clone())
+            .with_part(self.user_id.clone())
+            .with_part(metadata.into()))
+    }
+
+    /// Parse a reply message; returns the request id along with the reply.
+    pub fn from_message(msg: &Multipart<Bytes>) -> Result<(Bytes, Self), ZapError> {
+        let frames = frames_after_version(msg)?;
+        match frames {
+            [request_id, status_code, status_text, user_id, metadata] => {
+                let mut logger = slog::Logger::root(slog::Discard, slog::o!());
+                let (_, md) = properties(metadata, &Limits::unlimited(), &mut logger)
+                    .map_err(|_| ZapError::MalformedMetadata)?;
+                Ok((
+                    request_id.clone(),
+                    ZapReply {
+                        status: ZapStatus::from_code(status_code)?,
+                        status_text: status_text.clone(),
+                        user_id: user_id.clone(),
+                        metadata: (metadata, md).into(),
+                    },
+                ))
+            }
+            _ => Err(ZapError::MissingFrames),
+        }
+    }
+}
+
+/// Decides on ZAP requests.
+///
+/// Closures `FnMut(&ZapRequest) -> ZapReply` implement this trait.
+pub trait ZapHandler {
+    fn handle(&mut self, request: &ZapRequest) -> ZapReply;
+}
+
+impl<F: FnMut(&ZapRequest) -> ZapReply> ZapHandler for F {
+    fn handle(&mut self, request: &ZapRequest) -> ZapReply {
+        self(request)
+    }
+}
+
+/// Authenticator of the PLAIN and CURVE servers asking a ZAP handler.
+///
+/// The reply of the handler is kept, so that user id and metadata can be looked up once the
+/// handshake is done.
+#[derive(Debug)]
+pub struct ZapAuthenticator<H> {
+    handler: H,
+    domain: Bytes,
+    address: Bytes,
+    identity: Bytes,
+    reply: Option<ZapReply>,
+}
+
+impl<H: ZapHandler> ZapAuthenticator<H> {
+    /// `address` is the address of the connecting peer.
+    pub fn new(handler: H, address: Bytes) -> Self {
+        ZapAuthenticator {
+            handler,
+            domain: Bytes::new(),
+            address,
+            identity: Bytes::new(),
+            reply: None,
+        }
+    }
+
+    pub fn with_domain(mut self, domain: Bytes) -> Self {
+        self.domain = domain;
+        self
+    }
+
+    /// Identity of the server socket.
+    pub fn with_identity(mut self, identity: Bytes) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// The last reply of the handler.
+    pub fn reply(&self) -> Option<&ZapReply> {
+        self.reply.as_ref()
+    }
+
+    /// The user id the peer was authenticated as.
+    pub fn user_id(&self) -> Option<&Bytes> {
+        self.reply
+            .as_ref()
+            .filter(|reply| reply.is_success())
+            .map(|reply| &reply.user_id)
+    }
+
+    /// Ask the handler; only `ZapStatus::Success` accepts the peer.
+    pub fn authenticate(&mut self, mechanism: SecurityMechanism, credentials: Vec<Bytes>) -> bool {
+        let request = ZapRequest {
+            domain: self.domain.clone(),
+            address: self.address.clone(),
+            identity: self.identity.clone(),
+            mechanism,
+            credentials,
+        };
+        let reply = self.handler.handle(&request);
+        let accepted = reply.is_success();
+        self.reply = Some(reply);
+        accepted
+    }
+}
+
+impl<H: ZapHandler> PlainAuthenticator for ZapAuthenticator<H> {
+    fn authenticate(&mut self, username: &[u8], password: &[u8]) -> bool {
+        let credentials = vec![
+            Bytes::copy_from_slice(username),
+            Bytes::copy_from_slice(password),
+        ];
+        ZapAuthenticator::authenticate(self, SecurityMechanism::PLAIN, credentials)
+    }
+}
+
+impl<H: ZapHandler> CurveAuthenticator for ZapAuthenticator<H> {
+    fn authenticate(&mut self, client_key: &[u8; CURVE_KEY_LEN]) -> bool {
+        let credentials = vec![Bytes::copy_from_slice(client_key)];
+        ZapAuthenticator::authenticate(self, SecurityMechanism::CURVE, credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zmqrs_parser::Command;
+
+    use crate::{PlainServer, PlainStep};
+
+    /// Handler accepting "admin" with password "secret", working on the wire format like a
+    /// handler behind `ZAP_ENDPOINT` would.
+    fn wire_handler(request: &ZapRequest) -> ZapReply {
+        let msg = request.to_message(Bytes::from_static(b"1"));
+        let (request_id, request) = ZapRequest::from_message(&msg).unwrap();
+        assert_eq!(request_id, Bytes::from_static(b"1"));
+
+        let reply = match (&request.mechanism, &request.credentials[..]) {
+            (SecurityMechanism::PLAIN, [username, password])
+                if username.as_ref() == b"admin" && password.as_ref() == b"secret" =>
+            {
+                ZapReply::success(username.clone()).with_metadata(MetaData::new().with_property(
+                    Bytes::from_static(b"X-Role"),
+                    Bytes::from_static(b"operator"),
+                ))
+            }
+            _ => ZapReply::failure(
+                ZapStatus::AuthenticationFailure,
+                Bytes::from_static(b"Access denied"),
+            ),
+        };
+
+        let (reply_id, reply) =
+            ZapReply::from_message(&reply.to_message(request_id).unwrap()).unwrap();
+        assert_eq!(reply_id, Bytes::from_static(b"1"));
+        reply
+    }
+
+    fn hello(username: &'static [u8], password: &'static [u8]) -> Command<Bytes, Bytes> {
+        Command::HELLO(zmqrs_parser::Hello {
+            username: Bytes::from_static(username),
+            password: Bytes::from_static(password),
+        })
+    }
+
+    #[test]
+    fn plain_peer_accepted() {
+        let zap = ZapAuthenticator::new(wire_handler, Bytes::from_static(b"127.0.0.1"))
+            .with_domain(Bytes::from_static(b"global"));
+        let mut server = PlainServer::new(zap, MetaData::new());
+
+        assert_eq!(
+            server.handle(hello(b"admin", b"secret")),
+            Ok(PlainStep::Send(Command::WELCOME))
+        );
+        let zap = server.authenticator();
+        assert_eq!(zap.user_id(), Some(&Bytes::from_static(b"admin")));
+        assert_eq!(
+            zap.reply().unwrap().metadata.get("x-role"),
+            Some(&Bytes::from_static(b"operator"))
+        );
+    }
+
+    #[test]
+    fn plain_peer_rejected() {
+        let zap = ZapAuthenticator::new(wire_handler, Bytes::from_static(b"127.0.0.1"));
+        let mut server = PlainServer::new(zap, MetaData::new());
+
+        match server.handle(hello(b"admin", b"guess")) {
+            Ok(PlainStep::Close(Command::ERROR(_))) => {}
+            other => panic!("unexpected step {:?}", other),
+        }
+        let zap = server.authenticator();
+        assert_eq!(zap.user_id(), None);
+        assert_eq!(
+            zap.reply().map(|reply| reply.status),
+            Some(ZapStatus::AuthenticationFailure)
+        );
+    }
+
+    #[test]
+    fn curve_request() {
+        let mut requests = Vec::new();
+        let mut zap = ZapAuthenticator::new(
+            |request: &ZapRequest| {
+                requests.push(request.clone());
+                ZapReply::failure(ZapStatus::TemporaryError, Bytes::from_static(b"busy"))
+            },
+            Bytes::from_static(b"10.0.0.1"),
+        );
+
+        assert!(!CurveAuthenticator::authenticate(
+            &mut zap,
+            &[7; CURVE_KEY_LEN]
+        ));
+        drop(zap);
+        assert_eq!(
+            requests,
+            vec![ZapRequest {
+                domain: Bytes::new(),
+                address: Bytes::from_static(b"10.0.0.1"),
+                identity: Bytes::new(),
+                mechanism: SecurityMechanism::CURVE,
+                credentials: vec![Bytes::copy_from_slice(&[7; CURVE_KEY_LEN])],
+            }]
+        );
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let request = Multipart::from(vec![
+            Bytes::from_static(ZAP_VERSION),
+            Bytes::from_static(b"1"),
+        ]);
+        assert_eq!(
+            ZapRequest::from_message(&request),
+            Err(ZapError::MissingFrames)
+        );
+
+        let mut reply = ZapReply::success(Bytes::new())
+            .to_message(Bytes::from_static(b"1"))
+            .unwrap()
+            .into_parts();
+        reply[2] = Bytes::from_static(b"201");
+        assert_eq!(
+            ZapReply::from_message(&Multipart::from(reply.clone())),
+            Err(ZapError::UnknownStatusCode)
+        );
+        reply[0] = Bytes::from_static(b"2.0");
+        assert_eq!(
+            ZapReply::from_message(&Multipart::from(reply)),
+            Err(ZapError::UnsupportedVersion)
+        );
+    }
+}